@@ -0,0 +1,41 @@
+// Measures how long a `get` that hits takes, to compare builds with and
+// without the `stats` feature. Run it with
+// `cargo run --release --example hit_latency` and
+// `cargo run --release --no-default-features --example hit_latency`.
+
+use std::time::Instant;
+
+use async_trait::async_trait;
+
+use thru::Store;
+
+const KEYS: u64 = 1000;
+const HITS: u64 = 5_000_000;
+const ROUNDS: u64 = 5;
+
+struct NopStore;
+
+#[async_trait]
+impl Store<u64, u64> for NopStore {
+    async fn fetch(&self, key: &u64) -> anyhow::Result<u64> {
+        Ok(*key)
+    }
+
+    async fn update(&self, _key: u64, _value: u64) {}
+}
+
+#[tokio::main]
+async fn main() {
+    let cache = thru::Cache::builder(NopStore).no_pruner().build().await;
+    for k in 0..KEYS {
+        cache.get(k).await.unwrap();
+    }
+    for round in 0..ROUNDS {
+        let start = Instant::now();
+        for i in 0..HITS {
+            cache.get(i % KEYS).await.unwrap();
+        }
+        let latency = start.elapsed() / HITS as u32;
+        println!("Round {}: {:?} per hit", round, latency);
+    }
+}
@@ -0,0 +1,48 @@
+// Measures how many cache misses per second `get` can serve, with a store
+// that returns right away, so that the cache's own overhead dominates. Run it
+// with `cargo run --release --example miss_throughput`.
+
+use std::time::Instant;
+
+use async_trait::async_trait;
+
+use thru::Store;
+
+const MISSES: u64 = 200_000;
+const TASKS: u64 = 8;
+const ROUNDS: u64 = 5;
+
+struct NopStore;
+
+#[async_trait]
+impl Store<u64, u64> for NopStore {
+    async fn fetch(&self, key: &u64) -> anyhow::Result<u64> {
+        Ok(*key)
+    }
+
+    async fn update(&self, _key: u64, _value: u64) {}
+}
+
+#[tokio::main]
+async fn main() {
+    for round in 0..ROUNDS {
+        let cache = thru::Cache::builder(NopStore).no_pruner().build().await;
+        let start = Instant::now();
+        let mut tasks = tokio::task::JoinSet::new();
+        for task in 0..TASKS {
+            let cache = cache.clone();
+            tasks.spawn(async move {
+                // Every key is new, so every get misses.
+                let first = (round * TASKS + task) * MISSES;
+                for k in first..first + MISSES / TASKS {
+                    cache.get(k).await.unwrap();
+                }
+            });
+        }
+        while let Some(task) = tasks.join_next().await {
+            task.unwrap();
+        }
+        let misses_per_sec = MISSES as f64 / start.elapsed().as_secs_f64();
+        println!("Round {}: {:.0} misses/s", round, misses_per_sec);
+    }
+}
@@ -0,0 +1,39 @@
+// Measures how long a `get` that misses takes when it's the only waiter on
+// its fetch, with a store that returns right away, so that the cost of
+// coalescing fetches shows. Run it with
+// `cargo run --release --example single_waiter_latency`.
+
+use std::time::Instant;
+
+use async_trait::async_trait;
+
+use thru::Store;
+
+const MISSES: u64 = 200_000;
+const ROUNDS: u64 = 5;
+
+struct NopStore;
+
+#[async_trait]
+impl Store<u64, u64> for NopStore {
+    async fn fetch(&self, key: &u64) -> anyhow::Result<u64> {
+        Ok(*key)
+    }
+
+    async fn update(&self, _key: u64, _value: u64) {}
+}
+
+#[tokio::main]
+async fn main() {
+    for round in 0..ROUNDS {
+        let cache = thru::Cache::builder(NopStore).no_pruner().build().await;
+        let start = Instant::now();
+        // Every key is new, so every get misses, and nothing else waits on
+        // its fetch.
+        for k in round * MISSES..(round + 1) * MISSES {
+            cache.get(k).await.unwrap();
+        }
+        let latency = start.elapsed() / MISSES as u32;
+        println!("Round {}: {:?} per miss", round, latency);
+    }
+}
@@ -0,0 +1,65 @@
+use std::sync::Mutex;
+
+use tokio::time::{Duration, Instant};
+
+// Stops fetching from a store that seems to be down. After `threshold`
+// fetches in a row fail, the circuit opens, and fetches are refused except
+// for one probe every `reset_timeout`. The circuit closes again as soon as a
+// fetch succeeds.
+pub(crate) struct CircuitBreaker {
+    threshold: usize,
+    reset_timeout: Duration,
+    state: Mutex<BreakerState>,
+}
+
+struct BreakerState {
+    // The number of fetches in a row that failed.
+    failures: usize,
+    // When the next probe can go through, if the circuit is open.
+    next_probe: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub(crate) fn new(threshold: usize, reset_timeout: Duration) -> Self {
+        Self {
+            threshold,
+            reset_timeout,
+            state: Mutex::new(BreakerState {
+                failures: 0,
+                next_probe: None,
+            }),
+        }
+    }
+
+    pub(crate) fn is_open(&self) -> bool {
+        self.state.lock().unwrap().next_probe.is_some()
+    }
+
+    // Whether a fetch can go to the store now. While the circuit is open,
+    // this lets one fetch through as a probe each `reset_timeout`. If the
+    // probe is abandoned, the next one goes `reset_timeout` later.
+    pub(crate) fn allow(&self, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match state.next_probe {
+            None => true,
+            Some(next_probe) if now >= next_probe => {
+                state.next_probe = Some(now + self.reset_timeout);
+                true
+            }
+            Some(_) => false,
+        }
+    }
+
+    pub(crate) fn record(&self, succeeded: bool, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        if succeeded {
+            state.failures = 0;
+            state.next_probe = None;
+            return;
+        }
+        state.failures += 1;
+        if state.next_probe.is_none() && state.failures >= self.threshold {
+            state.next_probe = Some(now + self.reset_timeout);
+        }
+    }
+}
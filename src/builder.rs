@@ -0,0 +1,686 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::future::Future;
+use std::hash::Hash;
+use std::pin::Pin;
+use std::sync::atomic::{self, AtomicUsize};
+use std::sync::Arc;
+
+use tokio::runtime::Handle;
+use tokio::task::JoinHandle;
+use tokio::time::Duration;
+
+use crate::clock::{Clock, SystemClock};
+use crate::error::{BackgroundError, BackgroundPhase};
+use crate::events::{Event, EventKind, EventLog};
+use crate::evict::EvictOverflow;
+use crate::executor::FetchExecutor;
+use crate::policy::EvictionPolicy;
+use crate::runtime::CacheRuntime;
+use crate::{AdmissionContext, Cache, InsertDuringFetch, OnFetchTimeout, Store, ValueSource};
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+pub(crate) type KeyOrder<K> = dyn Fn(&K, &K) -> Ordering + Send + Sync;
+
+pub(crate) type Canonicalize<K> = dyn Fn(&K) -> K + Send + Sync;
+
+pub(crate) type Weigher<K, V> = dyn Fn(&K, &V) -> usize + Send + Sync;
+
+pub(crate) type TtlFn<K, V> = dyn Fn(&K, &V) -> Duration + Send + Sync;
+
+pub(crate) type NegativeTtl<V> = dyn Fn(&V) -> Option<Duration> + Send + Sync;
+
+pub(crate) type Validate<K, V> = dyn Fn(&K, &V) -> bool + Send + Sync;
+
+pub(crate) type ShouldAdmit<K, V> = dyn Fn(&K, &V, AdmissionContext<K>) -> bool + Send + Sync;
+
+pub(crate) type OnReplace<K, V> = dyn Fn(&K, Arc<V>) + Send + Sync;
+
+pub(crate) type OnBackgroundError = dyn Fn(BackgroundError) + Send + Sync;
+
+// Compares a value with the one last written back for its key, and copies it
+// to compare later values with, for `skip_unchanged_writebacks`.
+pub(crate) type SkipUnchanged<V> = (fn(&V, &V) -> bool, fn(&V) -> V);
+
+pub(crate) type OnFetchComplete<K> = dyn Fn(&K, Duration, bool) + Send + Sync;
+
+pub(crate) type BeforeFetch<K> = dyn Fn(&K) -> BoxFuture<'static, ()> + Send + Sync;
+
+pub(crate) struct Config<K, V> {
+    pub(crate) name: String,
+    pub(crate) access_ttl: Duration,
+    pub(crate) renew_while_held: bool,
+    pub(crate) max_lifetime: Option<Duration>,
+    pub(crate) ttl_fn: Option<Arc<TtlFn<K, V>>>,
+    pub(crate) soft_ttl: Option<Duration>,
+    pub(crate) hard_ttl: Option<Duration>,
+    pub(crate) negative_ttl: Option<Arc<NegativeTtl<V>>>,
+    pub(crate) prune_interval: Duration,
+    pub(crate) pruner: bool,
+    pub(crate) eviction_order: Option<Arc<KeyOrder<K>>>,
+    pub(crate) eviction_policy: Option<Arc<dyn EvictionPolicy<K>>>,
+    pub(crate) evict_channel_capacity: Option<usize>,
+    pub(crate) evict_overflow: EvictOverflow,
+    pub(crate) before_fetch: Option<Arc<BeforeFetch<K>>>,
+    pub(crate) on_fetch_complete: Option<Arc<OnFetchComplete<K>>>,
+    pub(crate) max_capacity: Option<usize>,
+    pub(crate) canonicalize: Option<Arc<Canonicalize<K>>>,
+    pub(crate) clock: Arc<dyn Clock>,
+    pub(crate) fetch_executor: FetchExecutor,
+    pub(crate) runtime: Option<CacheRuntime>,
+    pub(crate) background_handle: Option<Handle>,
+    pub(crate) fetch_deadline: Option<Duration>,
+    pub(crate) max_concurrent_fetches: Option<usize>,
+    pub(crate) max_fetching_keys: Option<usize>,
+    pub(crate) max_tasks: Option<usize>,
+    pub(crate) weigher: Option<Arc<Weigher<K, V>>>,
+    pub(crate) max_value_weight: Option<usize>,
+    pub(crate) max_weight: Option<usize>,
+    pub(crate) validate: Option<Arc<Validate<K, V>>>,
+    pub(crate) should_admit: Option<Arc<ShouldAdmit<K, V>>>,
+    pub(crate) on_replace: Option<Arc<OnReplace<K, V>>>,
+    pub(crate) insert_during_fetch: InsertDuringFetch,
+    pub(crate) on_fetch_timeout: OnFetchTimeout,
+    pub(crate) on_background_error: Option<Arc<OnBackgroundError>>,
+    pub(crate) update_retry: Option<(usize, Duration)>,
+    pub(crate) circuit_breaker: Option<(usize, Duration)>,
+    pub(crate) skip_clean_writebacks: bool,
+    pub(crate) skip_unchanged_writebacks: Option<SkipUnchanged<V>>,
+    pub(crate) fetch_debounce: Option<Duration>,
+    pub(crate) events: EventLog<K>,
+    // The weight of the cached values, which their nodes keep up to date.
+    pub(crate) total_weight: Arc<AtomicUsize>,
+}
+
+impl<K, V> Config<K, V> {
+    pub(crate) fn weigh(&self, k: &K, v: &V) -> usize {
+        self.weigher.as_ref().map_or(1, |weigher| weigher(k, v))
+    }
+
+    pub(crate) fn total_weight(&self) -> usize {
+        self.total_weight.load(atomic::Ordering::Relaxed)
+    }
+
+    // The access TTL of a value that's being cached. `store_ttl` is the TTL
+    // the store returned with it, if any.
+    pub(crate) fn ttl(&self, k: &K, v: &V, store_ttl: Option<Duration>) -> Option<Duration> {
+        store_ttl.or_else(|| self.ttl_fn.as_ref().map(|ttl_fn| ttl_fn(k, v)))
+    }
+
+    // Whether an evicted value from `source` is written back to the store.
+    pub(crate) fn needs_writeback(&self, source: ValueSource) -> bool {
+        !self.skip_clean_writebacks || source == ValueSource::Inserted
+    }
+
+    // How long `v` can be served for, however recently it was accessed. This
+    // is the shorter of the hard TTL and, for an error, the negative TTL.
+    pub(crate) fn hard_ttl(&self, v: &V) -> Option<Duration> {
+        let negative_ttl = self
+            .negative_ttl
+            .as_ref()
+            .and_then(|negative_ttl| negative_ttl(v));
+        match (self.hard_ttl, negative_ttl) {
+            (Some(hard_ttl), Some(negative_ttl)) => Some(hard_ttl.min(negative_ttl)),
+            (hard_ttl, negative_ttl) => hard_ttl.or(negative_ttl),
+        }
+    }
+
+    // Tell the eviction policy, if there is one, about changes to the values.
+    // Inserts are also added to the event log.
+    pub(crate) fn record_insert(&self, k: &K)
+    where
+        K: Copy,
+    {
+        if let Some(eviction_policy) = &self.eviction_policy {
+            eviction_policy.on_insert(k);
+        }
+        self.record_event(k, EventKind::Insert);
+    }
+
+    pub(crate) fn record_access(&self, k: &K) {
+        if let Some(eviction_policy) = &self.eviction_policy {
+            eviction_policy.on_access(k);
+        }
+    }
+
+    pub(crate) fn record_remove(&self, k: &K) {
+        if let Some(eviction_policy) = &self.eviction_policy {
+            eviction_policy.on_remove(k);
+        }
+    }
+
+    // Adds to the event log, if it's enabled.
+    pub(crate) fn record_event(&self, k: &K, kind: EventKind)
+    where
+        K: Copy,
+    {
+        if self.events.is_enabled() {
+            let at = self.clock.now();
+            self.events.record(*k, Event { kind, at });
+        }
+    }
+
+    pub(crate) fn is_valid(&self, k: &K, v: &V) -> bool {
+        self.validate.as_ref().is_none_or(|validate| validate(k, v))
+    }
+
+    // How long the error of a fetch that timed out is cached for, as
+    // `Cache::complete_fetch` takes it.
+    pub(crate) fn cache_timeout_for(&self) -> Option<Option<Duration>> {
+        match self.on_fetch_timeout {
+            OnFetchTimeout::Retry => None,
+            OnFetchTimeout::NegativeCache(window) => Some(Some(window)),
+        }
+    }
+
+    pub(crate) fn report_fetch(&self, k: &K, latency: Duration, succeeded: bool) {
+        if let Some(on_fetch_complete) = &self.on_fetch_complete {
+            on_fetch_complete(k, latency, succeeded);
+        }
+    }
+
+    pub(crate) fn report_background_error(&self, phase: BackgroundPhase, message: String) {
+        if let Some(on_background_error) = &self.on_background_error {
+            on_background_error(BackgroundError { phase, message });
+        }
+    }
+
+    // Spawns one of the cache's background tasks, on the background runtime
+    // if there is one.
+    pub(crate) fn spawn_background<F>(&self, task: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        match &self.background_handle {
+            Some(handle) => handle.spawn(task),
+            None => tokio::spawn(task),
+        }
+    }
+
+    pub(crate) fn is_oversize(&self, k: &K, v: &V) -> bool {
+        self.max_value_weight
+            .is_some_and(|max_value_weight| self.weigh(k, v) > max_value_weight)
+    }
+}
+
+pub struct CacheBuilder<K, V> {
+    store: Arc<dyn Store<K, V> + Send + Sync>,
+    config: Config<K, V>,
+}
+
+impl<K, V> CacheBuilder<K, V>
+where
+    K: Hash + fmt::Display + Copy + Eq + Send + Sync + 'static,
+    V: Send + Sync + 'static,
+{
+    pub(crate) fn new(store: impl Store<K, V> + Send + Sync + 'static) -> Self {
+        Self {
+            store: Arc::new(store),
+            config: Config {
+                name: String::from("cache"),
+                access_ttl: Duration::from_secs(60),
+                renew_while_held: false,
+                max_lifetime: None,
+                ttl_fn: None,
+                soft_ttl: None,
+                hard_ttl: None,
+                negative_ttl: None,
+                prune_interval: Duration::from_secs(10),
+                pruner: true,
+                eviction_order: None,
+                eviction_policy: None,
+                evict_channel_capacity: None,
+                evict_overflow: EvictOverflow::Block,
+                before_fetch: None,
+                on_fetch_complete: None,
+                max_capacity: None,
+                canonicalize: None,
+                clock: Arc::new(SystemClock),
+                fetch_executor: FetchExecutor::Spawn,
+                runtime: None,
+                background_handle: None,
+                fetch_deadline: None,
+                max_concurrent_fetches: None,
+                max_fetching_keys: None,
+                max_tasks: None,
+                weigher: None,
+                max_value_weight: None,
+                max_weight: None,
+                validate: None,
+                should_admit: None,
+                on_replace: None,
+                insert_during_fetch: InsertDuringFetch::InsertWins,
+                on_fetch_timeout: OnFetchTimeout::Retry,
+                on_background_error: None,
+                update_retry: None,
+                circuit_breaker: None,
+                skip_clean_writebacks: false,
+                skip_unchanged_writebacks: None,
+                fetch_debounce: None,
+                events: EventLog::new(1024),
+                total_weight: Arc::default(),
+            },
+        }
+    }
+
+    // Tells the cache apart from others in its tracing spans, its `Debug`
+    // output, and its web page. By default, it's "cache".
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.config.name = name.into();
+        self
+    }
+
+    // How long an entry can go without being accessed before the pruner
+    // evicts it.
+    pub fn access_ttl(mut self, access_ttl: Duration) -> Self {
+        self.config.access_ttl = access_ttl;
+        self
+    }
+
+    // Counts a value as accessed on every pruner sweep while a caller still
+    // holds a reference to it, e.g. for session-like values, so that its
+    // access TTL starts over when the last reference is dropped instead of
+    // running out right away. By default, a held value keeps the access time
+    // it had when it was last read.
+    pub fn renew_while_held(mut self) -> Self {
+        self.config.renew_while_held = true;
+        self
+    }
+
+    // How long a value can be cached, however recently it was accessed. The
+    // pruner evicts values once either this or their access TTL has passed.
+    // By default, values can be cached for as long as they keep being
+    // accessed.
+    pub fn max_lifetime(mut self, max_lifetime: Duration) -> Self {
+        self.config.max_lifetime = Some(max_lifetime);
+        self
+    }
+
+    // How long a value is fresh for after it's fetched or inserted. After
+    // this, `get` still returns it, but also refreshes it from the store in
+    // the background. The stale value is replaced without being written
+    // back, so this is for values that aren't modified in the cache.
+    pub fn soft_ttl(mut self, soft_ttl: Duration) -> Self {
+        self.config.soft_ttl = Some(soft_ttl);
+        self
+    }
+
+    // How long a value can be served for after it's fetched or inserted.
+    // After this, `get` drops it and waits for a fresh one, e.g. when a
+    // refresh after the soft TTL didn't happen in time. A fetched value is
+    // dropped without being written back, but one from `insert`,
+    // `compare_and_swap`, or `Entry::and_modify` is written back first, and
+    // is served until nothing else references it.
+    pub fn hard_ttl(mut self, hard_ttl: Duration) -> Self {
+        self.config.hard_ttl = Some(hard_ttl);
+        self
+    }
+
+    // Gives each value its own access TTL based on its contents, e.g. to
+    // cache small values for longer than big ones. It's called whenever a
+    // value is fetched or inserted, and a TTL the store returns with
+    // `fetch_with_ttl` takes precedence over it.
+    pub fn ttl_fn(mut self, ttl_fn: impl Fn(&K, &V) -> Duration + Send + Sync + 'static) -> Self {
+        self.config.ttl_fn = Some(Arc::new(ttl_fn));
+        self
+    }
+
+    // How long the pruner sleeps between sweeps.
+    pub fn prune_interval(mut self, prune_interval: Duration) -> Self {
+        self.config.prune_interval = prune_interval;
+        self
+    }
+
+    // The number of values the cache holds before the pruner starts evicting
+    // the least recently used ones. This is enforced by each sweep, so the
+    // cache can grow past it in between.
+    pub fn max_capacity(mut self, max_capacity: usize) -> Self {
+        self.config.max_capacity = Some(max_capacity);
+        self
+    }
+
+    // Builds the cache without a background pruner, e.g. for short-lived
+    // caches. Values are then only evicted explicitly, so the access TTL and
+    // `max_capacity` have no effect.
+    pub fn no_pruner(mut self) -> Self {
+        self.config.pruner = false;
+        self
+    }
+
+    // Decides which values to evict to stay under `max_capacity` and
+    // `max_weight`, and for `evict_one` and `evict_fraction`, instead of
+    // evicting the least recently used ones.
+    pub fn eviction_policy(mut self, eviction_policy: impl EvictionPolicy<K> + 'static) -> Self {
+        self.config.eviction_policy = Some(Arc::new(eviction_policy));
+        self
+    }
+
+    // The order in which entries evicted by the same pruner sweep are written
+    // back to the store. By default, the order is unspecified.
+    pub fn eviction_order(
+        mut self,
+        order: impl Fn(&K, &K) -> Ordering + Send + Sync + 'static,
+    ) -> Self {
+        self.config.eviction_order = Some(Arc::new(order));
+        self
+    }
+
+    // Bounds the number of batches of evicted values waiting to be written
+    // back. Each pruner sweep sends its victims as one batch, and other
+    // evictions send one value at a time. By default, the channel to the
+    // evictor is unbounded.
+    pub fn evict_channel_capacity(mut self, capacity: usize) -> Self {
+        self.config.evict_channel_capacity = Some(capacity);
+        self
+    }
+
+    // Drops evicted values that came from the store and haven't been changed
+    // since, instead of writing them back. Values from `insert`,
+    // `compare_and_swap`, and `Entry::and_modify` are still written back. The
+    // dropped values are still passed to `Store::finalize`. By default, every
+    // evicted value is written back.
+    pub fn skip_clean_writebacks(mut self) -> Self {
+        self.config.skip_clean_writebacks = true;
+        self
+    }
+
+    // Skips writing back an evicted value that's equal to the one last written
+    // back for its key, e.g. a value that was fetched again unchanged. This
+    // assumes that nothing else writes to the store, and it keeps a copy of
+    // the last value written back for every key, until the key's value is
+    // removed without being written back, e.g. by `remove`.
+    pub fn skip_unchanged_writebacks(mut self) -> Self
+    where
+        V: PartialEq + Clone,
+    {
+        self.config.skip_unchanged_writebacks = Some((V::eq, V::clone));
+        self
+    }
+
+    // What to do with evicted values when the bounded evict channel is full.
+    // With `EvictOverflow::Drop`, a batch that doesn't fit is dropped whole.
+    pub fn evict_overflow(mut self, overflow: EvictOverflow) -> Self {
+        self.config.evict_overflow = overflow;
+        self
+    }
+
+    // A hook that's awaited before each fetch from the store, e.g. to rate
+    // limit fetches. Gets that wait on a fetch that's already in progress
+    // don't call it, and a batched fetch calls it once per key. It runs as
+    // part of the fetch, after `max_concurrent_fetches` lets the fetch
+    // through, so the fetch deadline covers it, and a panic in it fails the
+    // fetch like a panicking store does.
+    pub fn before_fetch(
+        mut self,
+        before_fetch: impl Fn(&K) -> BoxFuture<'static, ()> + Send + Sync + 'static,
+    ) -> Self {
+        self.config.before_fetch = Some(Arc::new(before_fetch));
+        self
+    }
+
+    // Called when each fetch from the store completes, with the key as it
+    // was passed to the store, how long the fetch took, and whether it
+    // succeeded, e.g. to report per-key latencies. Fetches that time out or
+    // panic count as failures, and fetches that are abandoned aren't
+    // reported. A batched fetch reports each of its keys with the latency of
+    // the whole batch.
+    pub fn on_fetch_complete(
+        mut self,
+        on_fetch_complete: impl Fn(&K, Duration, bool) + Send + Sync + 'static,
+    ) -> Self {
+        self.config.on_fetch_complete = Some(Arc::new(on_fetch_complete));
+        self
+    }
+
+    // Maps keys to the key they're cached under, so that keys that differ
+    // only in fields that don't affect the value share an entry and a fetch.
+    // The store is still passed the original key when fetching, but values
+    // are written back under the canonical key.
+    pub fn canonicalize(mut self, canonicalize: impl Fn(&K) -> K + Send + Sync + 'static) -> Self {
+        self.config.canonicalize = Some(Arc::new(canonicalize));
+        self
+    }
+
+    // The clock that access times and TTLs are measured with. Note that the
+    // pruner still sweeps every `prune_interval` of real time.
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.config.clock = Arc::new(clock);
+        self
+    }
+
+    // Where fetches from the store run. By default, they're spawned on the
+    // async runtime.
+    pub fn fetch_executor(mut self, fetch_executor: FetchExecutor) -> Self {
+        self.config.fetch_executor = fetch_executor;
+        self
+    }
+
+    // How much each value weighs, e.g. its size in bytes. By default, every
+    // value weighs 1.
+    pub fn weigher(mut self, weigher: impl Fn(&K, &V) -> usize + Send + Sync + 'static) -> Self {
+        self.config.weigher = Some(Arc::new(weigher));
+        self
+    }
+
+    // Fetched values that weigh more than this are returned to the callers
+    // waiting on the fetch, but aren't cached, so that a single huge value
+    // can't blow the memory budget. Inserted values aren't checked.
+    pub fn max_value_weight(mut self, max_value_weight: usize) -> Self {
+        self.config.max_value_weight = Some(max_value_weight);
+        self
+    }
+
+    // The most the cached values can weigh in total. Inserts and fetches that
+    // take the cache over it evict the least recently used values until it's
+    // back under. Values that are still referenced can't be evicted, so the
+    // cache can stay over until the next one, or the next pruner sweep. The
+    // cache keeps a running total of the weights, so checking it is cheap,
+    // and it only looks for values to evict once it's over.
+    pub fn max_weight(mut self, max_weight: usize) -> Self {
+        self.config.max_weight = Some(max_weight);
+        self
+    }
+
+    // Checks fetched values before they're cached, e.g. to reject empty or
+    // malformed results. Values it returns false for are returned to the
+    // callers waiting on the fetch, but aren't cached, so the next get
+    // fetches again.
+    pub fn validate(mut self, validate: impl Fn(&K, &V) -> bool + Send + Sync + 'static) -> Self {
+        self.config.validate = Some(Arc::new(validate));
+        self
+    }
+
+    // Decides whether a fetched value that would put the cache over
+    // `max_capacity` or `max_weight` is cached, evicting others to make room,
+    // e.g. to keep a big, rarely used value from pushing out hot ones. Values
+    // it returns false for are returned to the callers waiting on the fetch,
+    // but aren't cached. It isn't called for values that fit, or for inserts.
+    pub fn should_admit(
+        mut self,
+        should_admit: impl Fn(&K, &V, AdmissionContext<K>) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.config.should_admit = Some(Arc::new(should_admit));
+        self
+    }
+
+    // Called with the old value when `insert` replaces a cached value, e.g.
+    // to release resources the old value holds. The replaced value isn't
+    // written back. The hook runs after the cache is unlocked, so it can use
+    // the cache.
+    pub fn on_replace(mut self, on_replace: impl Fn(&K, Arc<V>) + Send + Sync + 'static) -> Self {
+        self.config.on_replace = Some(Arc::new(on_replace));
+        self
+    }
+
+    // What `insert` does when the key is being fetched. By default, the
+    // inserted value wins.
+    pub fn insert_during_fetch(mut self, policy: InsertDuringFetch) -> Self {
+        self.config.insert_during_fetch = policy;
+        self
+    }
+
+    // What happens to a key after its fetch times out, whether by
+    // `fetch_deadline` or a get's own timeout. By default, the next get
+    // fetches it again.
+    pub fn on_fetch_timeout(mut self, policy: OnFetchTimeout) -> Self {
+        self.config.on_fetch_timeout = policy;
+        self
+    }
+
+    // Called whenever the cache's background work fails, e.g. when the store
+    // panics in a fetch or a writeback, or a hook panics during a pruner
+    // sweep. Failed fetches are still returned to their waiters, and failed
+    // writebacks are still reported by `shutdown`. The hook shouldn't block,
+    // since it's called from the cache's tasks.
+    pub fn on_background_error(
+        mut self,
+        on_background_error: impl Fn(BackgroundError) + Send + Sync + 'static,
+    ) -> Self {
+        self.config.on_background_error = Some(Arc::new(on_background_error));
+        self
+    }
+
+    // Writes evicted values back with `Store::try_update`, one at a time, and
+    // puts a value whose writeback fails back in the cache so that it's
+    // written back again once it expires. The first retry happens `backoff`
+    // after the failure, and each one after that waits twice as long. After
+    // `max_attempts` failed writebacks the value is dropped, and the failure
+    // is reported like a panicking writeback. A value whose writeback fails
+    // during `Cache::shutdown` is dropped and reported the same way, since
+    // shutdown can't retry it. If the key has a new value by the time it's
+    // put back, the new value wins.
+    pub fn update_retries(mut self, max_attempts: usize, backoff: Duration) -> Self {
+        self.config.update_retry = Some((max_attempts, backoff));
+        self
+    }
+
+    // How long a fetch from the store can take. A fetch that takes longer is
+    // abandoned, and every caller waiting on it fails with
+    // `CacheError::Timeout`. By default, the failure isn't cached, so the
+    // next get fetches again, but see `on_fetch_timeout`. A fetch started by
+    // `Cache::get_with_timeout` uses that get's timeout instead. By default,
+    // fetches can take as long as they need.
+    // With `FetchExecutor::Blocking`, an abandoned fetch can't be stopped:
+    // it keeps running on its blocking thread until the store returns, and
+    // its result is then thrown away. A store that can hang should give up
+    // on its own.
+    pub fn fetch_deadline(mut self, fetch_deadline: Duration) -> Self {
+        self.config.fetch_deadline = Some(fetch_deadline);
+        self
+    }
+
+    // Stops fetching from the store after `failure_threshold` fetches in a
+    // row fail, e.g. so that a store that's down isn't hammered with retries.
+    // While the circuit is open, gets serve cached values even past their
+    // hard TTL, misses fail with `CacheError::CircuitOpen` without fetching,
+    // and soft TTL refreshes are skipped, except that one fetch goes through
+    // as a probe every `reset_timeout`. The first fetch that succeeds closes
+    // the circuit.
+    pub fn circuit_breaker(mut self, failure_threshold: usize, reset_timeout: Duration) -> Self {
+        self.config.circuit_breaker = Some((failure_threshold, reset_timeout));
+        self
+    }
+
+    // The most fetches from the store that can be in progress at once. Other
+    // fetches wait for one of them to finish, and then start in the order of
+    // their `Priority`. By default, any number of fetches can run at once.
+    pub fn max_concurrent_fetches(mut self, max_concurrent_fetches: usize) -> Self {
+        self.config.max_concurrent_fetches = Some(max_concurrent_fetches);
+        self
+    }
+
+    // The most keys that can be fetched at once, including fetches waiting
+    // for `max_concurrent_fetches`. A get that would fetch another key fails
+    // with `CacheError::TooBusy` instead, e.g. to bound the memory that
+    // waiters hold during a mass miss. Gets of a key that's already being
+    // fetched still wait on its fetch.
+    pub fn max_fetching_keys(mut self, max_fetching_keys: usize) -> Self {
+        self.config.max_fetching_keys = Some(max_fetching_keys);
+        self
+    }
+
+    // The most tasks that the cache runs at once for its fetches and
+    // refreshes, e.g. to bound the work that a burst of misses spawns. At the
+    // limit, a get that would fetch another key fails with
+    // `CacheError::TooBusy`, and values past the soft TTL aren't refreshed.
+    // Gets of a key that's already being fetched still wait on its fetch.
+    pub fn max_tasks(mut self, max_tasks: usize) -> Self {
+        self.config.max_tasks = Some(max_tasks);
+        self
+    }
+
+    // Collects the keys that gets miss on for up to `debounce` after the first
+    // miss, then fetches them all with a single `Store::fetch_many`, e.g. to
+    // turn a burst of misses into one request to the backend. Gets of a key
+    // that's already waiting to be fetched join its fetch as usual. Gets with
+    // a fetch override or a timeout of their own still fetch on their own. By
+    // default, every miss fetches right away.
+    pub fn fetch_debounce(mut self, debounce: Duration) -> Self {
+        self.config.fetch_debounce = Some(debounce);
+        self
+    }
+
+    // How many of the most recent events the event log that
+    // `Cache::recent_events` reads keeps, across all keys. Zero turns the log
+    // off. The log is only kept with the `events` feature, and it keeps 1024
+    // events by default.
+    pub fn event_log_capacity(mut self, capacity: usize) -> Self {
+        self.config.events = EventLog::new(capacity);
+        self
+    }
+
+    // Prunes the cache with the runtime's shared task instead of spawning a
+    // pruner for it. The runtime's prune interval is used instead of the
+    // cache's.
+    pub fn runtime(mut self, runtime: &CacheRuntime) -> Self {
+        self.config.runtime = Some(runtime.clone());
+        self
+    }
+
+    // Runs the evictor and the pruner on the runtime behind `handle`, e.g. a
+    // small runtime of their own, so that slow writebacks don't take worker
+    // time away from gets. Fetches still run on the caller's runtime, and a
+    // shared pruner from `runtime` runs wherever its `CacheRuntime` was
+    // created. By default, everything runs on the runtime that builds the
+    // cache.
+    pub fn background_handle(mut self, handle: Handle) -> Self {
+        self.config.background_handle = Some(handle);
+        self
+    }
+
+    pub async fn build(self) -> Cache<K, V> {
+        Cache::with_config(self.store, self.config)
+    }
+}
+
+impl<K, V> CacheBuilder<K, V>
+where
+    K: Hash + fmt::Display + Copy + Ord + Send + Sync + 'static,
+    V: Send + Sync + 'static,
+{
+    // Writes back entries evicted by the same pruner sweep in key order.
+    pub fn eviction_order_by_key(self) -> Self {
+        self.eviction_order(K::cmp)
+    }
+}
+
+impl<K, T, E> CacheBuilder<K, Result<T, E>>
+where
+    K: Hash + fmt::Display + Copy + Eq + Send + Sync + 'static,
+    T: Send + Sync + 'static,
+    E: Send + Sync + 'static,
+{
+    // For stores that return their failures as values. An `Err` is served for
+    // at most `negative_ttl` after it's fetched or inserted, however recently
+    // it was accessed, and then `get` fetches again, as with `hard_ttl`. With
+    // `Duration::ZERO`, every get of an `Err` fetches again. `Ok` values are
+    // cached as usual.
+    pub fn negative_ttl(mut self, negative_ttl: Duration) -> Self {
+        self.config.negative_ttl = Some(Arc::new(move |v: &Result<T, E>| {
+            v.is_err().then_some(negative_ttl)
+        }));
+        self
+    }
+}
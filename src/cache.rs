@@ -9,21 +9,169 @@
 // 8. Store total time in cache (and display in web UI)
 // 9. Config (enabling web ui, access ttl)
 
+use std::borrow::Borrow;
 use std::collections::{hash_map, HashMap};
-use std::error;
 use std::fmt;
+use std::future::{poll_fn, Future};
+use std::hash::Hash;
 use std::io;
 use std::mem;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
 
 use async_trait::async_trait;
-use tokio::sync::{broadcast, mpsc, Mutex};
+use futures_core::Stream;
+use tokio::sync::{broadcast, watch, Mutex, Notify};
 use tokio::time::{sleep, Duration, Instant};
 
+use crate::breaker::CircuitBreaker;
+use crate::builder::{BoxFuture, CacheBuilder, Config, SkipUnchanged};
+use crate::error::{BackgroundPhase, CacheError};
+use crate::events::{Event, EventKind};
+use crate::evict::{self, Backlog, EvictProgress, EvictReceiver, EvictSender, Evicted};
+use crate::gate::{FetchGate, Priority};
+use crate::runtime::{Pruner, Sweep};
+use crate::stats::{CacheStats, Counters};
+use crate::tasks::TaskTracker;
+
 #[async_trait]
 pub trait Store<K, V> {
     async fn fetch(&self, key: &K) -> anyhow::Result<V>;
     async fn update(&self, key: K, value: V);
+
+    // Like `fetch`, but can also return how long the value can go without
+    // being accessed before it's evicted, e.g. from an HTTP Cache-Control
+    // header. This overrides the cache's access TTL for the value. The
+    // cache always fetches single keys with this, and by default it calls
+    // `fetch`.
+    async fn fetch_with_ttl(&self, key: &K) -> anyhow::Result<(V, Option<Duration>)>
+    where
+        K: Sync,
+        V: Send,
+    {
+        Ok((self.fetch(key).await?, None))
+    }
+
+    // Fetches several keys at once, returning a result for each key in the
+    // same order. By default, each key is fetched in turn.
+    async fn fetch_many(&self, keys: &[K]) -> Vec<anyhow::Result<V>>
+    where
+        K: Sync,
+        V: Send,
+    {
+        let mut results = Vec::with_capacity(keys.len());
+        for key in keys {
+            results.push(self.fetch(key).await);
+        }
+        results
+    }
+
+    // Writes back several values at once. By default, each value is updated
+    // in turn.
+    async fn update_many(&self, updates: Vec<(K, V)>)
+    where
+        K: Send + 'static,
+        V: Send + 'static,
+    {
+        for (key, value) in updates {
+            self.update(key, value).await;
+        }
+    }
+
+    // Like `update`, but can fail, handing the value back so that the write
+    // can be retried. The cache only calls this if it was built with
+    // `update_retries`, and by default it calls `update`.
+    async fn try_update(&self, key: K, value: V) -> Result<(), (V, anyhow::Error)>
+    where
+        K: Send + 'static,
+        V: Send + 'static,
+    {
+        self.update(key, value).await;
+        Ok(())
+    }
+
+    // Cleans up an evicted value, e.g. to flush it. Only values that reach the
+    // evictor are finalized, including ones that `skip_clean_writebacks`
+    // doesn't write back. Values that `get` drops for being past their hard
+    // TTL, that `remove`, `get_and_remove`, or `take_all` take out, and that
+    // `insert` replaces aren't. The evictor awaits this *before* it writes
+    // the value back, even if writebacks are disabled, so it mustn't close
+    // anything the writeback needs. A value that `update_retries` puts back
+    // in the cache is only finalized the first time it's evicted. By default,
+    // it does nothing.
+    async fn finalize(&self, _key: &K, _value: &V)
+    where
+        K: Sync,
+        V: Sync,
+    {
+    }
+}
+
+// Something that returns values by key, e.g. a `Cache`. Code that only reads
+// from a cache can depend on this instead so that it can be mocked in tests.
+#[async_trait]
+pub trait AsyncGet<K, V> {
+    async fn get(&self, k: K) -> Result<Arc<V>, CacheError>;
+}
+
+// What `insert` does when the key is being fetched.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InsertDuringFetch {
+    // Drop the inserted value, and cache the fetched one when the fetch
+    // completes.
+    FetchWins,
+    // Cache the inserted value and drop the fetched one. The fetch's waiters
+    // are disconnected, and get the inserted value when they retry.
+    #[default]
+    InsertWins,
+    // Like `InsertWins`, but the fetch's waiters are sent the inserted value
+    // right away.
+    InsertAndNotifyWaiters,
+}
+
+// What happens to a key after its fetch times out.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OnFetchTimeout {
+    // Nothing is cached, so the next get fetches again right away.
+    #[default]
+    Retry,
+    // The timeout error is cached for this long, so that gets in the
+    // meantime fail without adding to the load on a slow store.
+    NegativeCache(Duration),
+}
+
+// How far `Cache::warm_up_with` has got.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WarmUpProgress {
+    // The number of keys that have a value.
+    pub fetched: usize,
+    // The number of keys whose fetch failed.
+    pub failed: usize,
+    // The number of keys being warmed up.
+    pub total: usize,
+}
+
+// What the cache looks like to the `should_admit` hook, when a fetched value
+// would put it over `max_capacity` or `max_weight`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AdmissionContext<K> {
+    // The number of values in the cache, not counting the fetched one.
+    pub len: usize,
+    // The total weight of those values, which is zero without `max_weight`.
+    pub weight: usize,
+    // The value that would be evicted first to make room, or `None` if none
+    // can be evicted right now.
+    pub victim: Option<K>,
+}
+
+// Where a cached value came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValueSource {
+    // Fetched or refreshed from the store.
+    Fetched,
+    // Written to the cache, e.g. with `insert`, so the store may not have it
+    // until it's written back.
+    Inserted,
 }
 
 #[derive(Debug)]
@@ -31,15 +179,29 @@ struct RealCacheNode<V> {
     value: Arc<V>,
     first_access_ts: Instant,
     last_access_ts: Instant,
+    // When the value was fetched, refreshed, or inserted, for the soft and
+    // hard TTLs.
+    fetched_at: Instant,
+    // The access TTL the store returned for the value, if any.
+    ttl: Option<Duration>,
+    // Whether a refresh of the value is in progress, after it went past the
+    // soft TTL.
+    refreshing: bool,
+    source: ValueSource,
+    weight: NodeWeight,
 }
 
 impl<V> RealCacheNode<V> {
-    fn new(value: Arc<V>) -> Self {
-        let now = Instant::now();
+    fn new(value: Arc<V>, now: Instant, weight: NodeWeight) -> Self {
         Self {
             value,
             first_access_ts: now,
             last_access_ts: now,
+            fetched_at: now,
+            ttl: None,
+            refreshing: false,
+            source: ValueSource::Fetched,
+            weight,
         }
     }
 
@@ -53,11 +215,71 @@ impl<V> RealCacheNode<V> {
         }
     }
 
-    fn bump_access_time(&mut self) {
-        self.last_access_ts = Instant::now();
+    // Like `try_unwrap`, but the value is `Evicted::Clean` if it doesn't need
+    // to be written back.
+    fn try_unwrap_evicted<K>(self, config: &Config<K, V>) -> Result<Evicted<V>, Self> {
+        let needs_writeback = config.needs_writeback(self.source);
+        self.try_unwrap().map(|v| match needs_writeback {
+            true => Evicted::Dirty(v),
+            false => Evicted::Clean(v),
+        })
+    }
+
+    fn bump_access_time<K>(&mut self, k: &K, config: &Config<K, V>, now: Instant) {
+        self.last_access_ts = now;
+        config.record_access(k);
+    }
+
+    // Updates the value's weight after the value was replaced or modified.
+    fn reweigh<K>(&mut self, k: &K, config: &Config<K, V>) {
+        self.weight.set(config.weigh(k, &self.value));
+    }
+
+    // Whether the value was fetched, refreshed, or inserted `ttl` or longer
+    // ago.
+    fn is_older_than(&self, ttl: Option<Duration>, now: Instant) -> bool {
+        ttl.is_some_and(|ttl| now.saturating_duration_since(self.fetched_at) >= ttl)
+    }
+}
+
+// Counts a value's weight towards the cache's total for as long as its node
+// exists, so that the total doesn't have to be added up with the cache
+// locked, e.g. to check it against `max_weight` on every insert.
+#[derive(Debug)]
+struct NodeWeight {
+    total: Arc<AtomicUsize>,
+    weight: usize,
+}
+
+impl NodeWeight {
+    fn new<K, V>(config: &Config<K, V>, k: &K, v: &V) -> Self {
+        let weight = config.weigh(k, v);
+        config.total_weight.fetch_add(weight, Ordering::Relaxed);
+        Self {
+            total: config.total_weight.clone(),
+            weight,
+        }
+    }
+
+    fn set(&mut self, weight: usize) {
+        // Adding first keeps the total from underflowing in between.
+        self.total.fetch_add(weight, Ordering::Relaxed);
+        self.total.fetch_sub(self.weight, Ordering::Relaxed);
+        self.weight = weight;
+    }
+}
+
+impl Drop for NodeWeight {
+    fn drop(&mut self) {
+        self.total.fetch_sub(self.weight, Ordering::Relaxed);
     }
 }
 
+// `Dummy` is a placeholder used to move a node out of the map while trying to
+// unwrap its value for eviction. Every eviction does this while holding the
+// map's lock and either removes the entry or puts the real node back before
+// releasing it, so anything else holding the lock (e.g. `get`) never sees a
+// `Dummy` node.
 #[derive(Debug)]
 enum CacheNode<V> {
     Real(RealCacheNode<V>),
@@ -65,59 +287,468 @@ enum CacheNode<V> {
 }
 
 impl<V> CacheNode<V> {
-    fn new(value: Arc<V>) -> Self {
-        Self::Real(RealCacheNode::new(value))
+    fn new(
+        value: Arc<V>,
+        now: Instant,
+        ttl: Option<Duration>,
+        source: ValueSource,
+        weight: NodeWeight,
+    ) -> Self {
+        let mut real_node = RealCacheNode::new(value, now, weight);
+        real_node.ttl = ttl;
+        real_node.source = source;
+        Self::Real(real_node)
     }
 
     fn unwrap(&self) -> &RealCacheNode<V> {
         match self {
             Self::Real(real) => real,
-            Self::Dummy => unreachable!(),
+            Self::Dummy => unreachable!("Dummy node observed outside of an eviction"),
         }
     }
 
     fn unwrap_mut(&mut self) -> &mut RealCacheNode<V> {
         match self {
             Self::Real(real) => real,
-            Self::Dummy => unreachable!(),
+            Self::Dummy => unreachable!("Dummy node observed outside of an eviction"),
+        }
+    }
+}
+
+// A value that could be evicted, and what's needed to decide whether it
+// should be.
+struct Candidate<K> {
+    key: K,
+    first_access_ts: Instant,
+    last_access_ts: Instant,
+    ttl: Option<Duration>,
+}
+
+impl<K> Candidate<K> {
+    // Whether the value has gone unaccessed for longer than its access TTL,
+    // or been cached for longer than `max_lifetime`, however recently it was
+    // accessed.
+    fn is_expired<V>(&self, config: &Config<K, V>, now: Instant) -> bool {
+        let idle = now.duration_since(self.last_access_ts) >= self.ttl.unwrap_or(config.access_ttl);
+        let too_old = config
+            .max_lifetime
+            .is_some_and(|max_lifetime| now.duration_since(self.first_access_ts) >= max_lifetime);
+        idle || too_old
+    }
+}
+
+type FetchResult<V> = Result<Arc<V>, CacheError>;
+
+// Fetches a value in place of the store, for `get_or_fetch_with`.
+type FetchOverride<V> = Box<dyn FnOnce() -> BoxFuture<'static, anyhow::Result<V>> + Send>;
+
+// How a get fetches, if it has to.
+struct GetOptions<V> {
+    // If true, a fetch that the get starts or waits on isn't abandoned if the
+    // get stops waiting on it.
+    detach: bool,
+    // The priority of the fetch the get starts, if any.
+    priority: Priority,
+    // Fetches in place of the store, if the get starts the fetch.
+    fetch: Option<FetchOverride<V>>,
+    // Replaces the cache's `fetch_deadline` for the fetch the get starts.
+    fetch_deadline: Option<Duration>,
+}
+
+impl<V> Default for GetOptions<V> {
+    fn default() -> Self {
+        Self {
+            detach: false,
+            priority: Priority::Normal,
+            fetch: None,
+            fetch_deadline: None,
+        }
+    }
+}
+
+// A fetch in progress. Its waiters subscribe to `tx`, and notify
+// `unsubscribed` when they stop waiting, so that the fetch can be abandoned
+// once nobody is waiting on it. A detached fetch runs to completion anyway.
+#[derive(Debug)]
+struct InFlight<V> {
+    tx: broadcast::Sender<FetchResult<V>>,
+    unsubscribed: Arc<Notify>,
+    tombstone: Tombstone,
+    detached: bool,
+    _fetching: FetchingKey,
+    // When the fetch started, for `check_invariants`.
+    #[cfg(debug_assertions)]
+    started: Instant,
+}
+
+// Counts a key as being fetched for as long as its `InFlight` exists, for
+// `max_fetching_keys`.
+#[derive(Debug)]
+struct FetchingKey(Arc<AtomicUsize>);
+
+impl Drop for FetchingKey {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl<V> InFlight<V> {
+    fn new(fetching: &Arc<AtomicUsize>) -> (Self, Waiter<V>) {
+        fetching.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = broadcast::channel(1);
+        let unsubscribed = Arc::new(Notify::new());
+        let waiter = Waiter {
+            rx: Some(rx),
+            unsubscribed: unsubscribed.clone(),
+        };
+        let in_flight = Self {
+            tx,
+            unsubscribed,
+            tombstone: Tombstone::default(),
+            detached: false,
+            _fetching: FetchingKey(fetching.clone()),
+            #[cfg(debug_assertions)]
+            started: Instant::now(),
+        };
+        (in_flight, waiter)
+    }
+
+    fn subscribe(&self) -> Waiter<V> {
+        Waiter {
+            rx: Some(self.tx.subscribe()),
+            unsubscribed: self.unsubscribed.clone(),
         }
     }
 }
 
+// Set when a fetch's entry is removed by an invalidation like `remove`, so
+// that the fetch discards its result instead of caching it again. Without it,
+// a fetch that started before the invalidation would resurrect the value.
+type Tombstone = Arc<AtomicBool>;
+
+struct Waiter<V> {
+    // This is only `None` while the waiter is being dropped.
+    rx: Option<broadcast::Receiver<FetchResult<V>>>,
+    unsubscribed: Arc<Notify>,
+}
+
+impl<V> Waiter<V> {
+    // Fails if the fetch was abandoned without a result.
+    async fn recv(&mut self) -> Result<FetchResult<V>, broadcast::error::RecvError> {
+        self.rx.as_mut().unwrap().recv().await
+    }
+}
+
+impl<V> Drop for Waiter<V> {
+    fn drop(&mut self) {
+        // The receiver is dropped first so that the fetch sees the updated
+        // receiver count when it wakes up.
+        drop(self.rx.take());
+        self.unsubscribed.notify_one();
+    }
+}
+
 #[derive(Debug)]
 enum CacheEntry<V> {
-    Fetching(broadcast::Sender<Result<Arc<V>, Arc<anyhow::Error>>>),
-    FetchFailed(Arc<anyhow::Error>),
+    Fetching(InFlight<V>),
+    // A fetch's error, and when it stops being served, if ever.
+    FetchFailed(CacheError, Option<Instant>),
     Node(CacheNode<V>),
 }
 
-#[derive(Debug)]
-pub struct GetError {
-    pub fetch_error: Arc<anyhow::Error>,
+fn fetch_error(err: anyhow::Error) -> CacheError {
+    CacheError::Fetch(Arc::new(err))
+}
+
+// What a fetch or refresh got from the store: `None` if it passed its
+// deadline, and an error if it panicked.
+type Joined<V> = Option<Result<anyhow::Result<(V, Option<Duration>)>, tokio::task::JoinError>>;
+
+// What a spawned fetch, refresh or batch of fetches needs from the cache. The
+// cache builds it once and shares it with every fetch, so that a miss only
+// clones one `Arc` for it, and the store is only cloned once a fetch starts.
+struct FetchTask<K, V> {
+    store: SharedStore<K, V>,
+    stats: Arc<Counters>,
+    config: Arc<Config<K, V>>,
+    fetch_gate: Option<Arc<FetchGate>>,
+    breaker: Option<Arc<CircuitBreaker>>,
 }
 
-impl GetError {
-    fn new(fetch_error: Arc<anyhow::Error>) -> Self {
-        Self { fetch_error }
+impl<K, V> FetchTask<K, V>
+where
+    K: fmt::Display + Copy + Send + Sync + 'static,
+    V: Send + Sync + 'static,
+{
+    // The part that fetches and refreshes share. It runs `fetch` through
+    // `spawn_gated`, and reports how it went to `on_fetch_complete`, the event
+    // log, and the circuit breaker. Returns `None`, having aborted the fetch,
+    // if `abandoned` finishes first. The fetch is in the current span.
+    async fn run(
+        self: &Arc<Self>,
+        k: K,
+        fetch_key: K,
+        priority: Priority,
+        fetch_override: Option<BoxFuture<'static, anyhow::Result<V>>>,
+        deadline: Option<Duration>,
+        abandoned: impl Future<Output = ()>,
+    ) -> Option<Joined<V>> {
+        let fetch = move |store: DynStore<K, V>| async move {
+            match fetch_override {
+                Some(fetch) => fetch.await.map(|value| (value, None)),
+                None => store.fetch_with_ttl(&fetch_key).await,
+            }
+        };
+        let (joined, latency) = self
+            .spawn_gated(&[k], [fetch_key], priority, fetch, deadline, abandoned)
+            .await?;
+        let succeeded = matches!(joined, Some(Ok(Ok(_))));
+        self.config.report_fetch(&fetch_key, latency, succeeded);
+        self.config
+            .record_event(&k, EventKind::FetchDone { succeeded });
+        if let Some(breaker) = &self.breaker {
+            breaker.record(succeeded, self.config.clock.now());
+        }
+        Some(joined)
+    }
+
+    // Like `run`, for a batch of keys that's fetched with one call to
+    // `Store::fetch_many`. Returns a result for each key, in order. The
+    // circuit breaker counts the batch as a success if the store returned any
+    // value.
+    async fn run_many(
+        self: &Arc<Self>,
+        keys: &[K],
+        fetch_keys: Vec<K>,
+        deadline: Option<Duration>,
+    ) -> Vec<FetchResult<V>> {
+        let fetch = {
+            let fetch_keys = fetch_keys.clone();
+            move |store: DynStore<K, V>| async move { store.fetch_many(&fetch_keys).await }
+        };
+        let batch = self.spawn_gated(
+            keys,
+            fetch_keys.clone(),
+            Priority::Normal,
+            fetch,
+            deadline,
+            std::future::pending(),
+        );
+        let Some((joined, latency)) = batch.await else {
+            unreachable!("A batch of fetches isn't abandoned");
+        };
+        let results: Vec<FetchResult<V>> = match joined {
+            Some(Ok(results)) if results.len() == keys.len() => results
+                .into_iter()
+                .map(|result| result.map(Arc::new).map_err(fetch_error))
+                .collect(),
+            Some(Ok(results)) => {
+                let err = anyhow::anyhow!(
+                    "fetch_many returned {} results for {} keys",
+                    results.len(),
+                    keys.len()
+                );
+                vec![Err(fetch_error(err)); keys.len()]
+            }
+            Some(Err(err)) => {
+                let message = format!("fetch_many panicked: {}", panic_message(err));
+                self.config
+                    .report_background_error(BackgroundPhase::Fetch, message.clone());
+                vec![Err(fetch_error(anyhow::anyhow!(message))); keys.len()]
+            }
+            None => vec![Err(CacheError::Timeout); keys.len()],
+        };
+        for ((k, fetch_key), result) in keys.iter().zip(&fetch_keys).zip(&results) {
+            let succeeded = result.is_ok();
+            self.config.report_fetch(fetch_key, latency, succeeded);
+            self.config
+                .record_event(k, EventKind::FetchDone { succeeded });
+        }
+        if let Some(breaker) = &self.breaker {
+            breaker.record(results.iter().any(Result::is_ok), self.config.clock.now());
+        }
+        results
+    }
+
+    // Waits its turn with `max_concurrent_fetches`, then runs `before_fetch`
+    // for each of `fetch_keys` and `fetch` on the fetch executor. Returns how
+    // the fetch went and how long it took, or `None`, having aborted the
+    // fetch, if `abandoned` finishes first.
+    async fn spawn_gated<I, F>(
+        self: &Arc<Self>,
+        keys: &[K],
+        fetch_keys: I,
+        priority: Priority,
+        fetch: impl FnOnce(DynStore<K, V>) -> F + Send + 'static,
+        deadline: Option<Duration>,
+        abandoned: impl Future<Output = ()>,
+    ) -> Option<(Option<Result<F::Output, tokio::task::JoinError>>, Duration)>
+    where
+        I: IntoIterator<Item = K> + Send + 'static,
+        I::IntoIter: Send,
+        F: Future + Send,
+        F::Output: Send + 'static,
+    {
+        let permit = match &self.fetch_gate {
+            Some(fetch_gate) => Some(fetch_gate.acquire(priority).await),
+            None => None,
+        };
+
+        // The fetch, `before_fetch` included, runs in its own task so that
+        // a panicking store or hook can't leave the entry stuck in the
+        // `Fetching` state. Panics aren't cached, so the next get fetches
+        // again.
+        let fetch = {
+            let task = self.clone();
+            async move {
+                if let Some(before_fetch) = &task.config.before_fetch {
+                    for fetch_key in fetch_keys {
+                        before_fetch(&fetch_key).await;
+                    }
+                }
+                #[cfg(feature = "stats")]
+                let start = Instant::now();
+                let store = task.store.read().unwrap().clone();
+                let result = fetch(store).await;
+                #[cfg(feature = "stats")]
+                task.stats.record_fetch_latency(start.elapsed());
+                result
+            }
+        };
+        #[cfg(feature = "tracing")]
+        let fetch = tracing::Instrument::instrument(fetch, tracing::Span::current());
+        for k in keys {
+            self.config.record_event(k, EventKind::FetchStart);
+        }
+        let start = Instant::now();
+        let fetch = self.config.fetch_executor.spawn(fetch);
+        let abort_handle = fetch.abort_handle();
+        let joined = tokio::select! {
+            joined = join_fetch(fetch, deadline) => joined,
+            () = abandoned => {
+                abort_handle.abort();
+                return None;
+            }
+        };
+        drop(permit);
+        Some((joined, start.elapsed()))
     }
 }
 
-impl fmt::Display for GetError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Failed to fetch")
+// Waits for a spawned fetch. Returns `None` if the fetch deadline passes
+// first, in which case the fetch is aborted. The fetch is aborted too if the
+// task waiting for it is, so that it isn't left running on its own.
+async fn join_fetch<T>(
+    fetch: tokio::task::JoinHandle<T>,
+    deadline: Option<Duration>,
+) -> Option<Result<T, tokio::task::JoinError>> {
+    struct AbortOnDrop<T>(tokio::task::JoinHandle<T>);
+
+    impl<T> Drop for AbortOnDrop<T> {
+        fn drop(&mut self) {
+            self.0.abort();
+        }
+    }
+
+    let mut fetch = AbortOnDrop(fetch);
+    match deadline {
+        None => Some((&mut fetch.0).await),
+        Some(deadline) => tokio::time::timeout(deadline, &mut fetch.0).await.ok(),
     }
 }
 
-impl error::Error for GetError {}
+type Data<K, V> = Arc<Mutex<HashMap<K, CacheEntry<V>>>>;
 
-pub struct Cache<K, V> {
-    data: Arc<Mutex<HashMap<K, CacheEntry<V>>>>,
-    evict_tx: mpsc::UnboundedSender<(K, V)>,
-    evictor_join_handle: tokio::task::JoinHandle<()>,
-    pruner_join_handle: tokio::task::JoinHandle<()>,
+// The store is shared with the evictor, so swapping it out with `set_store`
+// is visible to subsequent updates as well as fetches.
+type SharedStore<K, V> = Arc<RwLock<DynStore<K, V>>>;
+
+type DynStore<K, V> = Arc<dyn Store<K, V> + Send + Sync>;
+
+type CloneFn<V> = dyn Fn(&V) -> V + Sync;
+
+// Whether the cache has no values, for `emptiness_watch`. It's updated by
+// whatever adds or removes values, with `publish_emptiness`.
+type Emptiness = Arc<watch::Sender<bool>>;
+
+// The first writeback that failed and hasn't been reported by `shutdown` yet.
+type UpdateFailure = Arc<std::sync::Mutex<Option<Arc<anyhow::Error>>>>;
+
+// The number of times in a row each value's writeback has failed, with
+// `update_retries`. It outlives the evictor, which `evict_all_sync` replaces.
+type UpdateAttempts<K> = Arc<std::sync::Mutex<HashMap<K, usize>>>;
+
+// The value the evictor last wrote back for each key, for
+// `skip_unchanged_writebacks`.
+type LastWritten<K, V> = Arc<std::sync::Mutex<HashMap<K, V>>>;
+
+// The sender to the cache's current evictor. `evict_all_sync` swaps in a new
+// one and waits for the old evictor to finish, which it only does once every
+// clone of the old sender is gone. So tasks that wait on the cache's lock look
+// the sender up after unlocking, instead of holding on to one.
+type EvictTx<K, V> = Arc<std::sync::Mutex<EvictSender<K, V>>>;
+
+// The cache's background tasks, which all of its clones share.
+// `evict_all_sync` replaces them, so they're behind a lock. The tasks are
+// stopped when the last clone of the cache is dropped.
+struct Background {
+    // This is only `None` once `into_store` has stopped the evictor.
+    evictor_join_handle: Option<tokio::task::JoinHandle<()>>,
+    // This is `None` if the cache was built without a pruner.
+    pruner: Option<Pruner>,
     web_join_handle: tokio::task::JoinHandle<io::Result<()>>,
-    store: Arc<dyn Store<K, V> + Send + Sync>,
-    access_ttl: Duration,
+    // The tasks started by `attach_invalidation_stream`.
+    invalidation_join_handles: Vec<tokio::task::JoinHandle<()>>,
+}
+
+impl Drop for Background {
+    fn drop(&mut self) {
+        // The pruner stops when it's dropped.
+        if let Some(evictor_join_handle) = &self.evictor_join_handle {
+            evictor_join_handle.abort();
+        }
+        // TODO: Use axum which supports graceful shutdown.
+        self.web_join_handle.abort();
+        for invalidation_join_handle in &self.invalidation_join_handles {
+            invalidation_join_handle.abort();
+        }
+    }
+}
+
+// Clones share everything: the cached values, the store, the stats, and the
+// background tasks.
+pub struct Cache<K, V> {
+    data: Data<K, V>,
+    background: Arc<std::sync::Mutex<Background>>,
+    evict_tx: EvictTx<K, V>,
+    store: SharedStore<K, V>,
+    config: Arc<Config<K, V>>,
+    stats: Arc<Counters>,
+    frozen: Arc<AtomicBool>,
+    emptiness: Emptiness,
+    // Notified on every insert, for `wait_for`.
+    inserted: Arc<Notify>,
+    shut_down: Arc<AtomicBool>,
+    update_failure: UpdateFailure,
+    update_attempts: UpdateAttempts<K>,
+    last_written: LastWritten<K, V>,
+    writeback_enabled: Arc<AtomicBool>,
+    // This is `None` if the number of concurrent fetches isn't limited.
+    fetch_gate: Option<Arc<FetchGate>>,
+    // This is `None` without `circuit_breaker`.
+    breaker: Option<Arc<CircuitBreaker>>,
+    fetch_task: Arc<FetchTask<K, V>>,
+    // The number of keys being fetched.
+    fetching: Arc<AtomicUsize>,
+    // The keys and fetch keys waiting for a debounced `fetch_many`, with
+    // `fetch_debounce`.
+    debounced: Arc<std::sync::Mutex<Vec<(K, K, Tombstone)>>>,
+    writeback_backlog: Backlog,
+    // The tasks for fetches, refreshes, and debounced batches.
+    tasks: Arc<TaskTracker>,
+    // The tasks putting back values whose writeback failed, with
+    // `update_retries`.
+    put_backs: Arc<TaskTracker>,
 }
 
 impl<K, V> Cache<K, V>
@@ -126,436 +757,5957 @@ where
     V: Send + Sync + 'static,
 {
     pub async fn new(store: impl Store<K, V> + Send + Sync + 'static) -> Self {
-        let store = Arc::new(store);
+        Self::builder(store).build().await
+    }
+
+    pub fn builder(store: impl Store<K, V> + Send + Sync + 'static) -> CacheBuilder<K, V> {
+        CacheBuilder::new(store)
+    }
+
+    pub(crate) fn with_config(
+        store: Arc<dyn Store<K, V> + Send + Sync>,
+        config: Config<K, V>,
+    ) -> Self {
+        let store: SharedStore<K, V> = Arc::new(RwLock::new(store));
+        let config = Arc::new(config);
+        let stats: Arc<Counters> = Arc::default();
+
+        let frozen: Arc<AtomicBool> = Arc::default();
 
         let data = Arc::new(Mutex::new(HashMap::new()));
+        let emptiness = Arc::new(watch::channel(true).0);
+
+        let writeback_backlog: Backlog = Arc::new(watch::channel(0).0);
+        let (evict_tx, evict_rx) = Self::evict_channel(&config, &stats, &writeback_backlog);
 
-        let (evict_tx, evict_rx) = mpsc::unbounded_channel();
+        let update_failure: UpdateFailure = Arc::default();
+        let update_attempts: UpdateAttempts<K> = Arc::default();
+        let last_written: LastWritten<K, V> = Arc::default();
+        let writeback_enabled = Arc::new(AtomicBool::new(true));
+        let shut_down: Arc<AtomicBool> = Arc::default();
+        let put_backs = TaskTracker::new();
+        let evictor_join_handle = Self::evictor_join_handle(
+            evict_rx,
+            store.clone(),
+            update_failure.clone(),
+            writeback_enabled.clone(),
+            Writeback {
+                data: data.clone(),
+                emptiness: emptiness.clone(),
+                update_attempts: update_attempts.clone(),
+                last_written: last_written.clone(),
+                update_failure: update_failure.clone(),
+                shut_down: shut_down.clone(),
+                put_backs: put_backs.clone(),
+            },
+            &config,
+        );
 
-        let evictor_join_handle = Self::evictor_join_handle(evict_rx, store.clone());
+        let evict_tx = Arc::new(std::sync::Mutex::new(evict_tx));
+        let pruner = Self::pruner(
+            data.clone(),
+            evict_tx.clone(),
+            config.clone(),
+            frozen.clone(),
+            emptiness.clone(),
+        );
 
-        let access_ttl = Duration::from_secs(60);
-        let pruner_join_handle =
-            Self::pruner_join_handle(data.clone(), evict_tx.clone(), access_ttl);
+        let web_join_handle = Self::web_join_handle(data.clone(), config.clone());
+        let fetch_gate = config.max_concurrent_fetches.map(FetchGate::new);
+        let breaker = config.circuit_breaker.map(|(threshold, reset_timeout)| {
+            Arc::new(CircuitBreaker::new(threshold, reset_timeout))
+        });
 
-        let web_join_handle = Self::web_join_handle(data.clone(), access_ttl);
+        let background = Background {
+            evictor_join_handle: Some(evictor_join_handle),
+            pruner,
+            web_join_handle,
+            invalidation_join_handles: Vec::new(),
+        };
+        let fetch_task = Arc::new(FetchTask {
+            store: store.clone(),
+            stats: stats.clone(),
+            config: config.clone(),
+            fetch_gate: fetch_gate.clone(),
+            breaker: breaker.clone(),
+        });
 
         Self {
             data,
+            background: Arc::new(std::sync::Mutex::new(background)),
             evict_tx,
-            evictor_join_handle,
-            pruner_join_handle,
             store,
-            access_ttl,
-            web_join_handle,
+            config,
+            stats,
+            frozen,
+            emptiness,
+            inserted: Arc::default(),
+            shut_down,
+            update_failure,
+            update_attempts,
+            last_written,
+            writeback_enabled,
+            fetch_gate,
+            breaker,
+            fetch_task,
+            fetching: Arc::default(),
+            debounced: Arc::default(),
+            writeback_backlog,
+            tasks: TaskTracker::new(),
+            put_backs,
         }
     }
 
-    pub async fn get(&self, k: K) -> Result<Arc<V>, GetError> {
-        let data = self.data.clone();
-        let mut lock = self.data.lock().await;
+    fn evict_tx(&self) -> EvictSender<K, V> {
+        Self::evict_sender(&self.evict_tx)
+    }
+
+    fn evict_sender(evict_tx: &EvictTx<K, V>) -> EvictSender<K, V> {
+        evict_tx.lock().unwrap().clone()
+    }
+
+    fn evict_channel(
+        config: &Config<K, V>,
+        stats: &Arc<Counters>,
+        backlog: &Backlog,
+    ) -> (EvictSender<K, V>, EvictReceiver<K, V>) {
+        evict::channel(
+            config.evict_channel_capacity,
+            config.evict_overflow,
+            stats.clone(),
+            backlog.clone(),
+        )
+    }
+
+    pub async fn get(&self, k: K) -> Result<Arc<V>, CacheError> {
+        self.get_impl(k, GetOptions::default()).await
+    }
+
+    // Like `get`, but on a miss the value is fetched with `fetch` instead of
+    // the store. Concurrent gets of the key still share a single fetch, so
+    // `fetch` isn't called if the key is already being fetched, and the
+    // first get's override wins over the others'.
+    pub async fn get_or_fetch_with<Fut>(
+        &self,
+        k: K,
+        fetch: impl FnOnce() -> Fut + Send + 'static,
+    ) -> Result<Arc<V>, CacheError>
+    where
+        Fut: Future<Output = anyhow::Result<V>> + Send + 'static,
+    {
+        let fetch: FetchOverride<V> = Box::new(move || Box::pin(fetch()));
+        let options = GetOptions {
+            fetch: Some(fetch),
+            ..GetOptions::default()
+        };
+        self.get_impl(k, options).await
+    }
+
+    // Like `get`, but if the number of concurrent fetches is limited and the
+    // get needs to fetch, its fetch starts ahead of waiting fetches with a
+    // lower priority. A get that joins a fetch that's already waiting doesn't
+    // change that fetch's priority.
+    pub async fn get_with_priority(&self, k: K, priority: Priority) -> Result<Arc<V>, CacheError> {
+        let options = GetOptions {
+            priority,
+            ..GetOptions::default()
+        };
+        self.get_impl(k, options).await
+    }
+
+    // Like `get`, but fails with `CacheError::Timeout` if the value isn't
+    // available within `timeout`, e.g. for keys that take longer to fetch
+    // than the rest. If the get starts a fetch, `timeout` is that fetch's
+    // deadline in place of the cache's `fetch_deadline`, whether it's longer
+    // or shorter, and the fetch fails every get waiting on it once it's up.
+    // If the get joins a fetch that's already in progress, `timeout` only
+    // bounds how long this get waits, and the fetch keeps its own deadline.
+    pub async fn get_with_timeout(&self, k: K, timeout: Duration) -> Result<Arc<V>, CacheError> {
+        let options = GetOptions {
+            detach: true,
+            fetch_deadline: Some(timeout),
+            ..GetOptions::default()
+        };
+        tokio::time::timeout(timeout, self.get_impl(k, options))
+            .await
+            .unwrap_or(Err(CacheError::Timeout))
+    }
+
+    async fn get_impl(&self, k: K, mut options: GetOptions<V>) -> Result<Arc<V>, CacheError> {
+        // Waiters are only disconnected when the fetch they're waiting on is
+        // abandoned because its entry was removed or replaced. In that case we
+        // retry once, which either finds the new entry or starts a new fetch,
+        // and give up if the retry's fetch is abandoned too.
+        match self.get_or_subscribe(k, &mut options).await {
+            Ok(result) => result,
+            Err(_) => self
+                .get_or_subscribe(k, &mut options)
+                .await
+                .unwrap_or(Err(CacheError::Cancelled)),
+        }
+    }
+
+    async fn get_or_subscribe(
+        &self,
+        fetch_key: K,
+        options: &mut GetOptions<V>,
+    ) -> Result<Result<Arc<V>, CacheError>, broadcast::error::RecvError> {
+        let k = self.canonical(&fetch_key);
+        let (mut lock, now) = loop {
+            let mut lock = self.data.lock().await;
+            // This is checked under the lock, since `shutdown` holds it while
+            // it drains the cache. A get that was waiting on the lock would
+            // otherwise start a fetch that outlives the shutdown.
+            if self.is_shut_down() {
+                return Ok(Err(CacheError::ShuttingDown));
+            }
+            let now = self.config.clock.now();
+
+            // A value past the hard TTL, or an error past the negative TTL, is
+            // dropped, so that the get below fetches a fresh one. While
+            // frozen, or while the circuit breaker is open, it's served
+            // anyway.
+            match lock.get(&k) {
+                Some(CacheEntry::Node(node)) => {
+                    let real_node = node.unwrap();
+                    let hard_ttl = self.config.hard_ttl(&real_node.value);
+                    if !self.is_frozen()
+                        && !self.is_circuit_open()
+                        && real_node.is_older_than(hard_ttl, now)
+                    {
+                        if real_node.source != ValueSource::Inserted {
+                            lock.remove(&k);
+                            self.config.record_remove(&k);
+                            self.config.record_event(&k, EventKind::Evict);
+                            Self::forget_written(&self.last_written, &k);
+                            Self::publish_emptiness(&self.emptiness, &lock);
+                        } else if let Some(v) = Self::try_take(&mut lock, &self.config, k) {
+                            // The store hasn't seen an inserted value, so it's
+                            // written back instead of dropped, and the get
+                            // starts over. One that's still referenced is
+                            // served until it can be.
+                            Self::publish_emptiness(&self.emptiness, &lock);
+                            drop(lock);
+                            self.evict_tx().send((k, v)).await;
+                            continue;
+                        }
+                    }
+                }
+                // So is an error that was only cached for a while, e.g. by
+                // `OnFetchTimeout::NegativeCache`.
+                Some(CacheEntry::FetchFailed(_, Some(expires_at))) if now >= *expires_at => {
+                    lock.remove(&k);
+                }
+                _ => {}
+            }
+            break (lock, now);
+        };
 
         match lock.get_mut(&k) {
+            None if self.is_frozen() => {
+                self.stats.record_miss();
+                Ok(Err(CacheError::Frozen))
+            }
+            None if self.is_too_busy() => {
+                self.stats.record_miss();
+                Ok(Err(CacheError::TooBusy))
+            }
+            None if !self.fetch_allowed() => {
+                self.stats.record_miss();
+                Ok(Err(CacheError::CircuitOpen))
+            }
             None => {
-                let (tx, mut rx) = broadcast::channel(1);
-                lock.insert(k, CacheEntry::Fetching(tx.clone()));
+                self.stats.record_miss();
+                let debounce = self
+                    .config
+                    .fetch_debounce
+                    .filter(|_| options.fetch.is_none() && options.fetch_deadline.is_none());
+                let (mut in_flight, mut waiter) = InFlight::new(&self.fetching);
+                // Batched fetches are never abandoned.
+                in_flight.detached = options.detach || debounce.is_some();
+                let unsubscribed = in_flight.unsubscribed.clone();
+                let tombstone = in_flight.tombstone.clone();
+                lock.insert(k, CacheEntry::Fetching(in_flight));
                 drop(lock);
 
-                let store_clone = self.store.clone();
-                tokio::spawn(async move {
-                    let fetch_result = store_clone.fetch(&k).await.map(Arc::new).map_err(Arc::new);
-
-                    let mut data = data.lock().await;
-                    let result = match data.entry(k) {
-                        hash_map::Entry::Occupied(mut e) => match e.get_mut() {
-                            // This could mean that the key was inserted while the
-                            // fetch was happening. In this case, we ignore the fetched
-                            // value and return the inserted value.
-                            CacheEntry::Node(ref mut node) => {
-                                let real_node = node.unwrap_mut();
-                                real_node.bump_access_time();
-                                Ok(real_node.value.clone())
-                            }
-                            CacheEntry::Fetching(_) | CacheEntry::FetchFailed(_) => {
-                                match fetch_result {
-                                    Ok(value) => {
-                                        e.insert(CacheEntry::Node(CacheNode::new(value.clone())));
-                                        Ok(value)
-                                    }
-                                    Err(err) => {
-                                        e.insert(CacheEntry::FetchFailed(err.clone()));
-                                        Err(err)
-                                    }
-                                }
-                            }
-                        },
-                        // This can happen if the value in the cache was deleted while
-                        // the fetch was happening.
-                        hash_map::Entry::Vacant(e) => match fetch_result {
-                            Ok(value) => {
-                                e.insert(CacheEntry::Node(CacheNode::new(value.clone())));
-                                Ok(value)
-                            }
-                            Err(err) => {
-                                e.insert(CacheEntry::FetchFailed(err.clone()));
-                                Err(err)
-                            }
-                        },
-                    };
-                    drop(data);
-
-                    let _ = tx.send(result);
-                });
+                if let Some(debounce) = debounce {
+                    self.debounce_fetch(k, fetch_key, tombstone, debounce);
+                } else {
+                    let fetch = options.fetch.take().map(|fetch| fetch());
+                    self.spawn_fetch(
+                        k,
+                        fetch_key,
+                        (unsubscribed, tombstone),
+                        options.priority,
+                        fetch,
+                        options.fetch_deadline,
+                    );
+                }
 
-                rx.recv().await.unwrap().map_err(GetError::new)
+                Ok(waiter.recv().await?)
             }
-            Some(CacheEntry::Fetching(tx)) => {
-                let mut rx = tx.subscribe();
+            Some(CacheEntry::Fetching(in_flight)) => {
+                self.stats.record_miss();
+                in_flight.detached |= options.detach;
+                let mut waiter = in_flight.subscribe();
                 drop(lock);
-                rx.recv().await.unwrap().map_err(GetError::new)
+                Ok(waiter.recv().await?)
             }
             Some(CacheEntry::Node(ref mut node)) => {
+                self.stats.record_hit();
+                self.config.record_event(&k, EventKind::Hit);
                 let real_node = node.unwrap_mut();
-                real_node.bump_access_time();
-                Ok(real_node.value.clone())
+                real_node.bump_access_time(&k, &self.config, now);
+                let past_soft_ttl = real_node.is_older_than(self.config.soft_ttl, now);
+                if past_soft_ttl
+                    && !real_node.refreshing
+                    && !self.is_frozen()
+                    && self.has_room_for_task()
+                    && self.fetch_allowed()
+                {
+                    real_node.refreshing = true;
+                    self.spawn_refresh(k, fetch_key);
+                }
+                Ok(Ok(real_node.value.clone()))
+            }
+            Some(CacheEntry::FetchFailed(e, _)) => {
+                self.stats.record_miss();
+                Ok(Err(e.clone()))
             }
-            Some(CacheEntry::FetchFailed(e)) => Err(GetError::new(e.clone())),
         }
     }
 
-    pub async fn insert(&self, k: K, v: Arc<V>) {
-        self.data
-            .lock()
-            .await
-            .insert(k, CacheEntry::Node(CacheNode::new(v)));
-    }
+    // Fetches a new value for `k`, which has a stale value in the cache, and
+    // replaces the stale value with it. Gets keep being served the stale
+    // value in the meantime. If the fetch fails, the stale value is kept, and
+    // the next get after the soft TTL tries again. The value keeps its place
+    // in `max_lifetime`, and the refresh doesn't count as a fetch in the
+    // stats, since nobody waited on it.
+    fn spawn_refresh(&self, k: K, fetch_key: K) {
+        let data = self.data.clone();
+        let task = self.fetch_task.clone();
+        let refresh = async move {
+            let config = &task.config;
+            // Refreshes aren't urgent, since there's a value to serve.
+            let refresh = task.run(
+                k,
+                fetch_key,
+                Priority::Low,
+                None,
+                config.fetch_deadline,
+                std::future::pending(),
+            );
+            let Some(joined) = refresh.await else {
+                unreachable!("Refreshes aren't abandoned");
+            };
 
-    pub async fn remove(&self, k: K) {
-        self.data.lock().await.remove(&k);
+            let mut data = data.lock().await;
+            // The value may have been removed or replaced in the meantime, in
+            // which case the refresh is dropped.
+            let Some(CacheEntry::Node(CacheNode::Real(real_node))) = data.get_mut(&k) else {
+                return;
+            };
+            real_node.refreshing = false;
+            let (value, ttl) = match joined {
+                Some(Ok(Ok((value, ttl)))) => (value, ttl),
+                Some(Err(err)) => {
+                    let message = format!("Refresh for key {} panicked: {}", k, panic_message(err));
+                    drop(data);
+                    config.report_background_error(BackgroundPhase::Fetch, message);
+                    return;
+                }
+                Some(Ok(Err(_))) | None => return,
+            };
+            if config.is_oversize(&k, &value) || !config.is_valid(&k, &value) {
+                return;
+            }
+            let now = config.clock.now();
+            let ttl = config.ttl(&k, &value, ttl);
+            let replaced = mem::replace(&mut real_node.value, Arc::new(value));
+            real_node.reweigh(&k, config);
+            config.record_insert(&k);
+            real_node.fetched_at = now;
+            real_node.source = ValueSource::Fetched;
+            real_node.ttl = ttl;
+            drop(data);
+            if let Some(on_replace) = &config.on_replace {
+                on_replace(&k, replaced);
+            }
+        };
+        #[cfg(feature = "tracing")]
+        let refresh = tracing::Instrument::instrument(
+            refresh,
+            tracing::info_span!("refresh", cache = %self.config.name, key = %fetch_key),
+        );
+        self.tasks.spawn(refresh);
     }
 
-    // Returns false if the key can't be evicted because the reference
-    // count of the Arc is not one.
-    async fn try_evict_without_lock(
+    // The `Fetching` entry owns the only sender for its waiters, and the fetch
+    // takes it back out when it installs the result. Anything else that
+    // replaces the entry drops the sender, which disconnects the waiters.
+    // `k` is the key of the entry, and `fetch_key` is the key as it was passed
+    // to the cache, which is what the store sees. If every waiter stops
+    // waiting before the fetch completes, the fetch is aborted and its entry
+    // removed.
+    fn spawn_fetch(
         &self,
         k: K,
-        lock: &mut tokio::sync::MutexGuard<'_, HashMap<K, CacheEntry<V>>>,
-    ) -> bool {
-        match lock.entry(k) {
-            hash_map::Entry::Vacant(_) => true,
-            hash_map::Entry::Occupied(mut e) => match e.get_mut() {
-                CacheEntry::Fetching(_) | CacheEntry::FetchFailed(_) => {
-                    e.remove();
-                    true
+        fetch_key: K,
+        (unsubscribed, tombstone): (Arc<Notify>, Tombstone),
+        priority: Priority,
+        fetch_override: Option<BoxFuture<'static, anyhow::Result<V>>>,
+        fetch_deadline: Option<Duration>,
+    ) {
+        let data = self.data.clone();
+        let emptiness = self.emptiness.clone();
+        let task = self.fetch_task.clone();
+        let evict_tx = self.evict_tx.clone();
+        let fetch = async move {
+            let (stats, config) = (&task.stats, &task.config);
+            let fetch = task.run(
+                k,
+                fetch_key,
+                priority,
+                fetch_override,
+                fetch_deadline.or(config.fetch_deadline),
+                Self::abandon_fetch(&data, &emptiness, k, &unsubscribed),
+            );
+            let Some(joined) = fetch.await else {
+                return;
+            };
+            let (fetch_result, ttl, cache_failure_for) = match joined {
+                Some(Ok(Ok((value, ttl)))) => (Ok(Arc::new(value)), ttl, Some(None)),
+                Some(Ok(Err(err))) => (Err(fetch_error(err)), None, Some(None)),
+                Some(Err(err)) => {
+                    let message = format!("Fetch for key {} panicked: {}", k, panic_message(err));
+                    config.report_background_error(BackgroundPhase::Fetch, message.clone());
+                    (Err(fetch_error(anyhow::anyhow!(message))), None, None)
                 }
-                CacheEntry::Node(node) => match mem::replace(node, CacheNode::Dummy) {
-                    CacheNode::Real(real_node) => match RealCacheNode::try_unwrap(real_node) {
-                        Ok(v) => {
-                            e.remove();
-                            self.evict_tx.send((k, v)).unwrap();
-                            true
-                        }
-                        Err(real_node) => {
-                            // If the unwrap wasn't successful, replace the dummy cache node
-                            // with the real cache node.
-                            *node = CacheNode::Real(real_node);
-                            false
-                        }
-                    },
-                    CacheNode::Dummy => false,
-                },
-            },
+                None => (Err(CacheError::Timeout), None, config.cache_timeout_for()),
+            };
+
+            let mut data = data.lock().await;
+            if tombstone.load(Ordering::Relaxed) {
+                stats.record_fetch(0);
+            } else {
+                Self::complete_fetch(
+                    &mut data,
+                    stats,
+                    config,
+                    k,
+                    fetch_result,
+                    ttl,
+                    cache_failure_for,
+                );
+            }
+            let overweight = Self::take_overweight(&mut data, config);
+            Self::publish_emptiness(&emptiness, &data);
+            drop(data);
+            if !overweight.is_empty() {
+                Self::evict_sender(&evict_tx).send_batch(overweight).await;
+            }
+        };
+        // Spawning loses the caller's span, so the fetch's span is created
+        // here, while the caller's span is still current, to make it a child
+        // of the caller's span.
+        #[cfg(feature = "tracing")]
+        let fetch = tracing::Instrument::instrument(
+            fetch,
+            tracing::info_span!("fetch", cache = %self.config.name, key = %fetch_key),
+        );
+        self.tasks.spawn(fetch);
+    }
+
+    // Waits for the fetch that owns `unsubscribed` to have no waiters left,
+    // then removes its entry.
+    async fn abandon_fetch(
+        data: &Mutex<HashMap<K, CacheEntry<V>>>,
+        emptiness: &watch::Sender<bool>,
+        k: K,
+        unsubscribed: &Arc<Notify>,
+    ) {
+        loop {
+            unsubscribed.notified().await;
+            // Another waiter may have subscribed since the notification, so
+            // the receiver count is checked under the lock.
+            let mut data = data.lock().await;
+            let abandoned = matches!(
+                data.get(&k),
+                Some(CacheEntry::Fetching(in_flight))
+                    if Arc::ptr_eq(&in_flight.unsubscribed, unsubscribed)
+                        && !in_flight.detached
+                        && in_flight.tx.receiver_count() == 0
+            );
+            if abandoned {
+                data.remove(&k);
+                Self::publish_emptiness(emptiness, &data);
+                return;
+            }
         }
     }
 
-    pub async fn try_evict(&self, k: K) -> bool {
-        let data = self.data.clone();
-        let mut lock = data.lock().await;
-        self.try_evict_without_lock(k, &mut lock).await
+    // Adds `k` to the next debounced `fetch_many`, which the first key added
+    // schedules for `debounce` later. `k` must already have a `Fetching`
+    // entry.
+    fn debounce_fetch(&self, k: K, fetch_key: K, tombstone: Tombstone, debounce: Duration) {
+        let mut debounced = self.debounced.lock().unwrap();
+        debounced.push((k, fetch_key, tombstone));
+        if debounced.len() > 1 {
+            return;
+        }
+        let cache = self.clone();
+        self.tasks.spawn(async move {
+            sleep(debounce).await;
+            let batch = mem::take(&mut *cache.debounced.lock().unwrap());
+            let (mut keys, mut fetch_keys, mut tombstones) = (vec![], vec![], vec![]);
+            for (k, fetch_key, tombstone) in batch {
+                keys.push(k);
+                fetch_keys.push(fetch_key);
+                tombstones.push(tombstone);
+            }
+            cache.spawn_fetch_many(keys, fetch_keys, tombstones);
+        });
     }
 
-    pub async fn evict_all_sync(&mut self) {
-        let data_clone = self.data.clone();
+    // Fetches all of `keys` with a single call to `Store::fetch_many`. Each key
+    // must already have a `Fetching` entry. Failures aren't cached, except for
+    // timeouts under `OnFetchTimeout::NegativeCache`.
+    fn spawn_fetch_many(&self, keys: Vec<K>, fetch_keys: Vec<K>, tombstones: Vec<Tombstone>) {
+        let data = self.data.clone();
+        let emptiness = self.emptiness.clone();
+        let task = self.fetch_task.clone();
+        let evict_tx = self.evict_tx.clone();
+        #[cfg(feature = "tracing")]
+        let span = tracing::info_span!(
+            "fetch_many",
+            cache = %self.config.name,
+            keys = fetch_keys.len()
+        );
+        let fetch = async move {
+            let (stats, config) = (&task.stats, &task.config);
+            let results = task
+                .run_many(&keys, fetch_keys, config.fetch_deadline)
+                .await;
 
-        // Make sure to hold the lock until the end of the function.
-        let mut data = self.data.lock().await;
-        loop {
-            let keys: Vec<_> = data.keys().copied().collect();
-            if keys.is_empty() {
-                break;
+            let mut data = data.lock().await;
+            for ((k, fetch_result), tombstone) in keys.into_iter().zip(results).zip(tombstones) {
+                if tombstone.load(Ordering::Relaxed) {
+                    stats.record_fetch(0);
+                    continue;
+                }
+                let cache_failure_for = match fetch_result {
+                    Err(CacheError::Timeout) => config.cache_timeout_for(),
+                    _ => None,
+                };
+                Self::complete_fetch(
+                    &mut data,
+                    stats,
+                    config,
+                    k,
+                    fetch_result,
+                    None,
+                    cache_failure_for,
+                );
             }
-
-            let mut all_done = true;
-            for key in keys {
-                all_done = all_done && self.try_evict_without_lock(key, &mut data).await;
+            let overweight = Self::take_overweight(&mut data, config);
+            Self::publish_emptiness(&emptiness, &data);
+            drop(data);
+            if !overweight.is_empty() {
+                Self::evict_sender(&evict_tx).send_batch(overweight).await;
             }
+        };
+        #[cfg(feature = "tracing")]
+        let fetch = tracing::Instrument::instrument(fetch, span);
+        self.tasks.spawn(fetch);
+    }
 
-            if all_done {
-                break;
+    // Installs the result of a fetch and sends it to the fetch's waiters. If
+    // the fetch failed, its error is cached for `cache_failure_for`, or
+    // forever if that's `Some(None)`. If it's `None`, or the value weighs
+    // more than `max_value_weight` or fails validation, the entry is removed
+    // so that the next get fetches again. `ttl` is the access TTL the store
+    // returned, if any.
+    fn complete_fetch(
+        data: &mut HashMap<K, CacheEntry<V>>,
+        stats: &Counters,
+        config: &Config<K, V>,
+        k: K,
+        fetch_result: FetchResult<V>,
+        ttl: Option<Duration>,
+        cache_failure_for: Option<Option<Duration>>,
+    ) {
+        let new_entry = match &fetch_result {
+            Ok(value) if config.is_oversize(&k, value) => {
+                stats.record_rejected_oversize();
+                None
             }
+            Ok(value) if !config.is_valid(&k, value) => None,
+            Ok(value) if !Self::admits(data, config, &k, value) => None,
+            Ok(value) => {
+                let ttl = config.ttl(&k, value, ttl);
+                let node = CacheNode::new(
+                    value.clone(),
+                    config.clock.now(),
+                    ttl,
+                    ValueSource::Fetched,
+                    NodeWeight::new(config, &k, &**value),
+                );
+                Some(CacheEntry::Node(node))
+            }
+            Err(err) => cache_failure_for.map(|duration| {
+                let expires_at = duration.map(|duration| config.clock.now() + duration);
+                CacheEntry::FetchFailed(err.clone(), expires_at)
+            }),
+        };
 
-            sleep(Duration::from_secs(1)).await;
+        if matches!(new_entry, Some(CacheEntry::Node(_)))
+            && !matches!(data.get(&k), Some(CacheEntry::Node(_)))
+        {
+            config.record_insert(&k);
         }
-
+        let tx = match data.entry(k) {
+            hash_map::Entry::Occupied(mut e) => match e.get_mut() {
+                // This could mean that the key was inserted while the
+                // fetch was happening. In this case, we ignore the fetched
+                // value. The insert disconnected any waiters, and their
+                // retry finds the inserted value.
+                CacheEntry::Node(_) => None,
+                CacheEntry::Fetching(_) | CacheEntry::FetchFailed(..) => {
+                    let old_entry = match new_entry {
+                        Some(new_entry) => e.insert(new_entry),
+                        None => e.remove(),
+                    };
+                    match old_entry {
+                        CacheEntry::Fetching(in_flight) => Some(in_flight.tx),
+                        _ => None,
+                    }
+                }
+            },
+            // This can happen if the entry was evicted while the fetch was
+            // happening. A fetch whose entry was removed is tombstoned
+            // instead, and doesn't get here.
+            hash_map::Entry::Vacant(e) => {
+                if let Some(new_entry) = new_entry {
+                    e.insert(new_entry);
+                }
+                None
+            }
+        };
+
+        stats.record_fetch(tx.as_ref().map_or(0, |tx| tx.receiver_count()));
+        if let Some(tx) = tx {
+            let _ = tx.send(fetch_result);
+        }
+    }
+
+    // Asks the `should_admit` hook whether to cache `v`, if caching it would
+    // put the cache over `max_capacity` or `max_weight`.
+    fn admits(data: &HashMap<K, CacheEntry<V>>, config: &Config<K, V>, k: &K, v: &V) -> bool {
+        let Some(should_admit) = &config.should_admit else {
+            return true;
+        };
+        let len = Self::count_values(data);
+        let weight = match config.max_weight {
+            Some(_) => config.total_weight(),
+            None => 0,
+        };
+        let over_capacity = config
+            .max_capacity
+            .is_some_and(|max_capacity| len >= max_capacity);
+        let over_weight = config
+            .max_weight
+            .is_some_and(|max_weight| weight + config.weigh(k, v) > max_weight);
+        if !over_capacity && !over_weight {
+            return true;
+        }
+        let victim = Self::by_policy(config, Self::evictable(data), |candidate| candidate.key, 1)
+            .first()
+            .map(|candidate| candidate.key);
+        should_admit(
+            k,
+            v,
+            AdmissionContext {
+                len,
+                weight,
+                victim,
+            },
+        )
+    }
+
+    // Gets several keys at once, fetching all of the missing ones with a
+    // single call to `Store::fetch_many`. Keys that are already being fetched
+    // wait on that fetch instead. A failure only affects its own key, and
+    // isn't cached, but an error that a get cached for a while, e.g. with
+    // `OnFetchTimeout::NegativeCache`, is served until it expires.
+    pub async fn get_many_results(&self, keys: &[K]) -> Vec<Result<Arc<V>, CacheError>> {
+        enum Pending<V> {
+            Done(Result<Arc<V>, CacheError>),
+            Waiting(Waiter<V>),
+        }
+
+        let mut lock = self.data.lock().await;
+        if self.is_shut_down() {
+            return keys.iter().map(|_| Err(CacheError::ShuttingDown)).collect();
+        }
+        let mut missing = vec![];
+        let mut missing_fetch_keys = vec![];
+        let mut missing_tombstones = vec![];
+        let mut fetch_allowed = None;
+        let now = self.config.clock.now();
+        let pending: Vec<_> = keys
+            .iter()
+            .map(|fetch_key| (self.canonical(fetch_key), fetch_key))
+            .map(|(k, fetch_key)| match lock.get_mut(&k) {
+                Some(CacheEntry::Node(node)) => {
+                    self.stats.record_hit();
+                    self.config.record_event(&k, EventKind::Hit);
+                    let real_node = node.unwrap_mut();
+                    real_node.bump_access_time(&k, &self.config, now);
+                    Pending::Done(Ok(real_node.value.clone()))
+                }
+                Some(CacheEntry::Fetching(in_flight)) => {
+                    self.stats.record_miss();
+                    Pending::Waiting(in_flight.subscribe())
+                }
+                Some(CacheEntry::FetchFailed(e, Some(expires_at))) if now < *expires_at => {
+                    self.stats.record_miss();
+                    Pending::Done(Err(e.clone()))
+                }
+                None | Some(CacheEntry::FetchFailed(..)) if self.is_frozen() => {
+                    self.stats.record_miss();
+                    Pending::Done(Err(CacheError::Frozen))
+                }
+                None | Some(CacheEntry::FetchFailed(..)) if self.is_too_busy() => {
+                    self.stats.record_miss();
+                    Pending::Done(Err(CacheError::TooBusy))
+                }
+                // The batch is a single fetch, so it's allowed or not as a
+                // whole.
+                None | Some(CacheEntry::FetchFailed(..))
+                    if !*fetch_allowed.get_or_insert_with(|| self.fetch_allowed()) =>
+                {
+                    self.stats.record_miss();
+                    Pending::Done(Err(CacheError::CircuitOpen))
+                }
+                None | Some(CacheEntry::FetchFailed(..)) => {
+                    self.stats.record_miss();
+                    let (mut in_flight, waiter) = InFlight::new(&self.fetching);
+                    // Batched fetches are never abandoned.
+                    in_flight.detached = true;
+                    missing_tombstones.push(in_flight.tombstone.clone());
+                    lock.insert(k, CacheEntry::Fetching(in_flight));
+                    missing.push(k);
+                    missing_fetch_keys.push(*fetch_key);
+                    Pending::Waiting(waiter)
+                }
+            })
+            .collect();
+        drop(lock);
+
+        if !missing.is_empty() {
+            self.spawn_fetch_many(missing, missing_fetch_keys, missing_tombstones);
+        }
+
+        let mut results = Vec::with_capacity(keys.len());
+        for (k, pending) in keys.iter().zip(pending) {
+            results.push(match pending {
+                Pending::Done(result) => result,
+                Pending::Waiting(mut waiter) => match waiter.recv().await {
+                    Ok(result) => result,
+                    // The fetch was abandoned, so fall back to a regular get.
+                    Err(_) => self.get(*k).await,
+                },
+            });
+        }
+        results
+    }
+
+    // Like `get_many_results`, but fails with the first error instead of
+    // returning a result per key.
+    pub async fn get_many(&self, keys: &[K]) -> Result<Vec<Arc<V>>, CacheError> {
+        self.get_many_results(keys).await.into_iter().collect()
+    }
+
+    // Returns the cached value for `k` only if it hasn't expired, without
+    // fetching, e.g. where an expired value is worse than none. A value past
+    // its TTL, `max_lifetime`, or hard TTL isn't returned even if the pruner
+    // hasn't evicted it yet, unlike with `get`.
+    pub async fn get_fresh_only(&self, k: K) -> Option<Arc<V>> {
+        let k = self.canonical(&k);
+        let mut data = self.data.lock().await;
+        let now = self.config.clock.now();
+        let fresh = match data.get_mut(&k) {
+            Some(CacheEntry::Node(node)) => {
+                let real_node = node.unwrap_mut();
+                let candidate = Candidate {
+                    key: k,
+                    first_access_ts: real_node.first_access_ts,
+                    last_access_ts: real_node.last_access_ts,
+                    ttl: real_node.ttl,
+                };
+                let hard_ttl = self.config.hard_ttl(&real_node.value);
+                (!candidate.is_expired(&self.config, now)
+                    && !real_node.is_older_than(hard_ttl, now))
+                .then_some(real_node)
+            }
+            _ => None,
+        };
+        let Some(real_node) = fresh else {
+            self.stats.record_miss();
+            return None;
+        };
+        self.stats.record_hit();
+        self.config.record_event(&k, EventKind::Hit);
+        real_node.bump_access_time(&k, &self.config, now);
+        Some(real_node.value.clone())
+    }
+
+    // Like `get`, but only constructs the full key on a miss. Hits are
+    // looked up by the borrowed form of the key, which isn't canonicalized.
+    pub async fn get_lazy_key<Q>(
+        &self,
+        lookup: &Q,
+        make_key: impl FnOnce() -> K,
+    ) -> Result<Arc<V>, CacheError>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut lock = self.data.lock().await;
+        // The policy is told about the key as it's stored, not `lookup`.
+        let k = lock.get_key_value(lookup).map(|(k, _)| *k);
+        if let Some((k, CacheEntry::Node(node))) = k.and_then(|k| Some((k, lock.get_mut::<K>(&k)?)))
+        {
+            self.stats.record_hit();
+            self.config.record_event(&k, EventKind::Hit);
+            let real_node = node.unwrap_mut();
+            real_node.bump_access_time(&k, &self.config, self.config.clock.now());
+            return Ok(real_node.value.clone());
+        }
+        drop(lock);
+
+        self.get(make_key()).await
+    }
+
+    // Like `get`, but for keys that take async work to derive, e.g. hashing
+    // a large object off the runtime. `make_key` is awaited once, before the
+    // cache is locked, and the key it returns is used for both the lookup and
+    // the fetch.
+    pub async fn get_async_key(
+        &self,
+        make_key: impl Future<Output = K>,
+    ) -> Result<Arc<V>, CacheError> {
+        let k = make_key.await;
+        self.get(k).await
+    }
+
+    // Gets each of `keys` ahead of time, e.g. to fill the cache before serving
+    // traffic, with at most `concurrency` gets in progress at once. The gets
+    // fetch at a low priority. `on_progress` is called after each get
+    // completes. If `cancel` completes first, the gets in progress are
+    // stopped and the rest aren't started. Returns the final progress.
+    pub async fn warm_up_with(
+        &self,
+        keys: impl IntoIterator<Item = K>,
+        concurrency: usize,
+        mut on_progress: impl FnMut(WarmUpProgress),
+        cancel: impl Future<Output = ()>,
+    ) -> WarmUpProgress {
+        let mut keys = keys.into_iter().collect::<Vec<_>>().into_iter();
+        let mut progress = WarmUpProgress {
+            fetched: 0,
+            failed: 0,
+            total: keys.len(),
+        };
+        let mut gets = tokio::task::JoinSet::new();
+        tokio::pin!(cancel);
+        loop {
+            while gets.len() < concurrency.max(1) {
+                let Some(k) = keys.next() else {
+                    break;
+                };
+                let cache = self.clone();
+                gets.spawn(async move { cache.get_with_priority(k, Priority::Low).await });
+            }
+            // Dropping `gets` on cancellation aborts the gets in progress.
+            let joined = tokio::select! {
+                joined = gets.join_next() => joined,
+                () = &mut cancel => return progress,
+            };
+            match joined {
+                Some(Ok(Ok(_))) => progress.fetched += 1,
+                Some(_) => progress.failed += 1,
+                None => return progress,
+            }
+            on_progress(progress);
+        }
+    }
+
+    // The name the cache was built with, e.g. to label its metrics.
+    pub fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    // Advances the cache's clock if it's a logical clock like `TickClock`.
+    pub fn advance_ticks(&self, ticks: u64) {
+        self.config.clock.advance_ticks(ticks);
+    }
+
+    // Reads the counters without locking the cache, so they may be slightly
+    // out of sync with each other and `entries` isn't set. Without the
+    // `stats` feature, the counters are all zero.
+    pub fn stats(&self) -> CacheStats {
+        self.stats.snapshot()
+    }
+
+    // The events in the event log for `k`, oldest first, e.g. to see what
+    // happened to a key in a flaky test. The log only keeps the most recent
+    // events across all keys, as many as `event_log_capacity`. Without the
+    // `events` feature, this is always empty.
+    pub fn recent_events(&self, k: &K) -> Vec<Event> {
+        self.config.events.recent(&self.canonical(k))
+    }
+
+    // Reads the counters while holding the cache's lock, so they're
+    // consistent with each other and with the number of entries.
+    pub async fn stats_consistent(&self) -> CacheStats {
+        let data = self.data.lock().await;
+        CacheStats {
+            entries: Some(Self::count_values(&data)),
+            ..self.stats.snapshot()
+        }
+    }
+
+    // The number of values in the cache. Keys that are being fetched or
+    // whose fetch failed aren't counted.
+    pub async fn len(&self) -> usize {
+        Self::count_values(&*self.data.lock().await)
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.len().await == 0
+    }
+
+    // The total weight of the cached values, as measured by the cache's
+    // weigher. Without a weigher, this is the number of values.
+    pub async fn weight(&self) -> usize {
+        self.config.total_weight()
+    }
+
+    // How many more values, or how much more weight with `max_weight`, the
+    // cache can take before it starts evicting, e.g. for admission control.
+    // With both limits, this is the smaller of the two. It's `None` if the
+    // cache has neither.
+    pub async fn headroom(&self) -> Option<u64> {
+        let data = self.data.lock().await;
+        let capacity = self
+            .config
+            .max_capacity
+            .map(|max_capacity| max_capacity.saturating_sub(Self::count_values(&data)));
+        let weight = self
+            .config
+            .max_weight
+            .map(|max_weight| max_weight.saturating_sub(self.config.total_weight()));
+        let headroom = match (capacity, weight) {
+            (Some(capacity), Some(weight)) => Some(capacity.min(weight)),
+            (capacity, weight) => capacity.or(weight),
+        };
+        headroom.map(|headroom| headroom as u64)
+    }
+
+    // The number of evicted values waiting to be written back, including the
+    // ones being written back now. A backlog that keeps growing means the
+    // store can't keep up with evictions.
+    pub fn writeback_backlog(&self) -> usize {
+        *self.writeback_backlog.subscribe().borrow()
+    }
+
+    // Returns once no fetch or refresh is in progress, nothing is waiting to
+    // be written back, and no failed writeback is being put back, e.g. to
+    // check the store in a test, or to hand the cache off in a known state.
+    // Work that starts while this waits, such as a writeback of a value
+    // evicted by a fetch, is waited for too. This can wait forever if gets or
+    // evictions keep coming.
+    pub async fn quiesce(&self) {
+        let mut fetches = self.tasks.subscribe();
+        let mut backlog = self.writeback_backlog.subscribe();
+        let mut put_backs = self.put_backs.subscribe();
+        loop {
+            if *fetches.borrow_and_update() == 0
+                && *backlog.borrow_and_update() == 0
+                && *put_backs.borrow_and_update() == 0
+            {
+                return;
+            }
+            // The senders live as long as the cache, so none of them fails.
+            tokio::select! {
+                _ = fetches.changed() => {}
+                _ = backlog.changed() => {}
+                _ = put_backs.changed() => {}
+            }
+        }
+    }
+
+    fn count_values(data: &HashMap<K, CacheEntry<V>>) -> usize {
+        data.values()
+            .filter(|entry| matches!(entry, CacheEntry::Node(_)))
+            .count()
+    }
+
+    // The key that `k` is cached under.
+    fn canonical(&self, k: &K) -> K {
+        match &self.config.canonicalize {
+            Some(canonicalize) => canonicalize(k),
+            None => *k,
+        }
+    }
+
+    fn current_store(&self) -> DynStore<K, V> {
+        self.store.read().unwrap().clone()
+    }
+
+    // Replaces the backing store. Fetches started and values evicted after
+    // this call go to the new store; the cache contents are kept.
+    pub fn set_store(&self, store: impl Store<K, V> + Send + Sync + 'static) {
+        *self.store.write().unwrap() = Arc::new(store);
+    }
+
+    // Freezes the cache for debugging. While frozen, only values that are
+    // already in the cache are served: misses fail with `CacheError::Frozen`
+    // instead of fetching, `insert`, `remove`, `rename`, and `try_evict` do
+    // nothing, and the pruner doesn't evict.
+    pub fn freeze(&self) {
+        self.frozen.store(true, Ordering::Relaxed);
+    }
+
+    pub fn unfreeze(&self) {
+        self.frozen.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        self.frozen.load(Ordering::Relaxed)
+    }
+
+    // Turns writing evicted values back to the store on or off, e.g. when the
+    // store is authoritative and the cache should never write to it. While
+    // it's off, evicted values are dropped instead, including ones that were
+    // evicted before it was turned off but haven't been written back yet.
+    // It's on by default.
+    pub fn set_writeback_enabled(&self, on: bool) {
+        self.writeback_enabled.store(on, Ordering::Relaxed);
+    }
+
+    pub fn is_writeback_enabled(&self) -> bool {
+        self.writeback_enabled.load(Ordering::Relaxed)
+    }
+
+    pub async fn insert(&self, k: K, v: Arc<V>) {
+        if self.is_frozen() || self.is_shut_down() {
+            return;
+        }
+        let k = self.canonical(&k);
+        let mut data = self.data.lock().await;
+        let Some((replaced, overweight)) = self.insert_locked(&mut data, k, v) else {
+            return;
+        };
+        drop(data);
+        if let (Some(on_replace), Some(replaced)) = (&self.config.on_replace, replaced) {
+            on_replace(&k, replaced);
+        }
+        if !overweight.is_empty() {
+            self.evict_tx().send_batch(overweight).await;
+        }
+    }
+
+    // Inserts `v` for `k` the way `insert` does, following
+    // `insert_during_fetch`, and takes the values that have to go for the
+    // cache to stay under `max_weight`. Returns `None` if a fetch in progress
+    // wins, and otherwise the value that was replaced, if any, and the values
+    // to send to the evictor once the cache is unlocked.
+    #[allow(clippy::type_complexity)]
+    fn insert_locked(
+        &self,
+        data: &mut HashMap<K, CacheEntry<V>>,
+        k: K,
+        v: Arc<V>,
+    ) -> Option<(Option<Arc<V>>, Vec<(K, Evicted<V>)>)> {
+        let policy = self.config.insert_during_fetch;
+        if policy == InsertDuringFetch::FetchWins
+            && matches!(data.get(&k), Some(CacheEntry::Fetching(_)))
+        {
+            return None;
+        }
+        let node = CacheNode::new(
+            v.clone(),
+            self.config.clock.now(),
+            self.config.ttl(&k, &v, None),
+            ValueSource::Inserted,
+            NodeWeight::new(&self.config, &k, &*v),
+        );
+        // A replaced fetch is dropped before unlocking, so that it stops
+        // counting toward `max_fetching_keys` with the entry. A replaced value
+        // is dropped before taking the overweight ones, so that its weight
+        // doesn't count.
+        let replaced = match data.insert(k, CacheEntry::Node(node)) {
+            Some(CacheEntry::Fetching(in_flight)) => {
+                if policy == InsertDuringFetch::InsertAndNotifyWaiters {
+                    let _ = in_flight.tx.send(Ok(v));
+                }
+                None
+            }
+            Some(CacheEntry::Node(node)) => Some(node.unwrap().value.clone()),
+            Some(CacheEntry::FetchFailed(..)) | None => None,
+        };
+        self.config.record_insert(&k);
+        let overweight = Self::take_overweight(data, &self.config);
+        Self::publish_emptiness(&self.emptiness, data);
+        self.inserted.notify_waiters();
+        Some((replaced, overweight))
+    }
+
+    // Sends `overweight` to the evictor without waiting, for the `Entry`
+    // methods, which can't await with the cache locked. A batch that has to
+    // wait for room in the channel is sent by a task of its own.
+    fn send_overweight_now(&self, overweight: Vec<(K, Evicted<V>)>) {
+        if overweight.is_empty() {
+            return;
+        }
+        let evict_tx = self.evict_tx();
+        if let Err(overweight) = evict_tx.try_send_batch(overweight) {
+            self.tasks
+                .spawn(async move { evict_tx.send_batch(overweight).await });
+        }
+    }
+
+    // Returns the cached value of each of `keys`, in order, computing the
+    // missing ones with `f` and inserting them in one pass with the cache
+    // locked, e.g. to derive many cheap values at once. A key that's being
+    // fetched waits on its fetch instead, and falls back to `f` if the fetch
+    // fails, in which case the computed value isn't cached. While the cache
+    // is frozen or shut down, computed values are returned but not cached.
+    pub async fn get_or_insert_many_with(&self, keys: Vec<K>, f: impl Fn(&K) -> V) -> Vec<Arc<V>> {
+        enum Pending<V> {
+            Done(Arc<V>),
+            Waiting(Waiter<V>),
+        }
+
+        let caching = !self.is_frozen() && !self.is_shut_down();
+        let mut data = self.data.lock().await;
+        let now = self.config.clock.now();
+        let mut inserted = false;
+        let pending: Vec<_> = keys
+            .iter()
+            .map(|fetch_key| {
+                let k = self.canonical(fetch_key);
+                match data.get_mut(&k) {
+                    Some(CacheEntry::Node(node)) => {
+                        self.stats.record_hit();
+                        self.config.record_event(&k, EventKind::Hit);
+                        let real_node = node.unwrap_mut();
+                        real_node.bump_access_time(&k, &self.config, now);
+                        return Pending::Done(real_node.value.clone());
+                    }
+                    Some(CacheEntry::Fetching(in_flight)) => {
+                        self.stats.record_miss();
+                        return Pending::Waiting(in_flight.subscribe());
+                    }
+                    None | Some(CacheEntry::FetchFailed(..)) => self.stats.record_miss(),
+                }
+                let v = Arc::new(f(fetch_key));
+                if caching {
+                    let ttl = self.config.ttl(&k, &v, None);
+                    let weight = NodeWeight::new(&self.config, &k, &*v);
+                    let node = CacheNode::new(v.clone(), now, ttl, ValueSource::Inserted, weight);
+                    data.insert(k, CacheEntry::Node(node));
+                    self.config.record_insert(&k);
+                    inserted = true;
+                }
+                Pending::Done(v)
+            })
+            .collect();
+        let overweight = Self::take_overweight(&mut data, &self.config);
+        Self::publish_emptiness(&self.emptiness, &data);
+        drop(data);
+        if inserted {
+            self.inserted.notify_waiters();
+        }
+        if !overweight.is_empty() {
+            self.evict_tx().send_batch(overweight).await;
+        }
+
+        let mut values = Vec::with_capacity(keys.len());
+        for (fetch_key, pending) in keys.iter().zip(pending) {
+            values.push(match pending {
+                Pending::Done(v) => v,
+                Pending::Waiting(mut waiter) => match waiter.recv().await {
+                    Ok(Ok(v)) => v,
+                    Ok(Err(_)) | Err(_) => Arc::new(f(fetch_key)),
+                },
+            });
+        }
+        values
+    }
+
+    // Replaces the cached value for `k` with `new` if it's equal to
+    // `expected`, e.g. to update a value optimistically without racing other
+    // updates. Returns false, and changes nothing, if the value is different,
+    // if `k` doesn't have a value, or if the cache is frozen or shut down.
+    pub async fn compare_and_swap(&self, k: K, expected: &V, new: Arc<V>) -> bool
+    where
+        V: PartialEq,
+    {
+        if self.is_frozen() || self.is_shut_down() {
+            return false;
+        }
+        let k = self.canonical(&k);
+        let mut data = self.data.lock().await;
+        let Some(CacheEntry::Node(node)) = data.get_mut(&k) else {
+            return false;
+        };
+        let real_node = node.unwrap_mut();
+        if *real_node.value != *expected {
+            return false;
+        }
+        real_node.bump_access_time(&k, &self.config, self.config.clock.now());
+        real_node.source = ValueSource::Inserted;
+        let old = mem::replace(&mut real_node.value, new);
+        real_node.reweigh(&k, &self.config);
+        self.config.record_insert(&k);
+        let overweight = Self::take_overweight(&mut data, &self.config);
+        Self::publish_emptiness(&self.emptiness, &data);
+        drop(data);
+        if let Some(on_replace) = &self.config.on_replace {
+            on_replace(&k, old);
+        }
+        if !overweight.is_empty() {
+            self.evict_tx().send_batch(overweight).await;
+        }
+        true
+    }
+
+    // Waits for `k` to have a value in the cache without fetching it, e.g. for
+    // a value that another task inserts. Returns `None` if it doesn't have one
+    // within `timeout`.
+    pub async fn wait_for(&self, k: K, timeout: Duration) -> Option<Arc<V>> {
+        let k = self.canonical(&k);
+        let wait = async {
+            loop {
+                // Every insert wakes every waiter, so we check the key again
+                // each time.
+                let inserted = self.inserted.notified();
+                if let Some(CacheEntry::Node(node)) = self.data.lock().await.get_mut(&k) {
+                    let real_node = node.unwrap_mut();
+                    real_node.bump_access_time(&k, &self.config, self.config.clock.now());
+                    return real_node.value.clone();
+                }
+                inserted.await;
+            }
+        };
+        tokio::time::timeout(timeout, wait).await.ok()
+    }
+
+    // Resets the access time of a cached value without returning it. Returns
+    // false if the key doesn't have a value in the cache.
+    pub async fn touch(&self, k: &K) -> bool {
+        let k = self.canonical(k);
+        match self.data.lock().await.get_mut(&k) {
+            Some(CacheEntry::Node(node)) => {
+                node.unwrap_mut()
+                    .bump_access_time(&k, &self.config, self.config.clock.now());
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Locks the cache and returns a guard for manipulating the value of `k`.
+    // The cache stays locked until the guard is dropped, so it shouldn't be
+    // held across unrelated awaits, and the cache can't be used again until
+    // it's dropped.
+    pub async fn entry(&self, k: K) -> Entry<'_, K, V> {
+        Entry {
+            key: self.canonical(&k),
+            data: self.data.lock().await,
+            cache: self,
+        }
+    }
+
+    // Like `touch`, but for several keys in one pass over the cache. Returns
+    // the number of keys that had a value.
+    pub async fn touch_many(&self, keys: &[K]) -> usize {
+        let now = self.config.clock.now();
+        let mut data = self.data.lock().await;
+        keys.iter()
+            .map(|k| self.canonical(k))
+            .filter(|k| match data.get_mut(k) {
+                Some(CacheEntry::Node(node)) => {
+                    node.unwrap_mut().bump_access_time(k, &self.config, now);
+                    true
+                }
+                _ => false,
+            })
+            .count()
+    }
+
+    // Calls `f` with a consistent view of the cache: nothing is inserted,
+    // fetched, or evicted while it runs, since the cache stays locked. `f`
+    // must not block, and can't await, since that would stall every other
+    // caller.
+    pub async fn read_transaction<R>(&self, f: impl FnOnce(&ReadView<'_, K, V>) -> R) -> R {
+        let data = self.data.lock().await;
+        f(&ReadView {
+            data: &data,
+            cache: self,
+        })
+    }
+
+    // Calls `f` with every cached value, `batch_size` at a time. The cache is
+    // only locked while each batch is collected, so other callers can use it
+    // in between, but then the scan isn't a snapshot: keys inserted during
+    // the scan may be missed, and keys removed during it are skipped. Like
+    // reads through `read_transaction`, these don't count as accesses.
+    pub async fn scan(&self, batch_size: usize, mut f: impl FnMut(&[(K, Arc<V>)])) {
+        assert!(batch_size > 0, "Batch size must be positive");
+        let keys: Vec<_> = self.data.lock().await.keys().copied().collect();
+        for keys in keys.chunks(batch_size) {
+            let batch: Vec<_> = {
+                let data = self.data.lock().await;
+                keys.iter()
+                    .filter_map(|k| match data.get(k) {
+                        Some(CacheEntry::Node(node)) => Some((*k, node.unwrap().value.clone())),
+                        _ => None,
+                    })
+                    .collect()
+            };
+            if !batch.is_empty() {
+                f(&batch);
+            }
+        }
+    }
+
+    // Returns whether a fetch is in progress for `k`.
+    pub async fn is_fetching(&self, k: &K) -> bool {
+        matches!(
+            self.data.lock().await.get(&self.canonical(k)),
+            Some(CacheEntry::Fetching(_))
+        )
+    }
+
+    // Moves the value for `from` to `to` without fetching, e.g. when a
+    // temporary id becomes permanent. Returns false, and changes nothing, if
+    // `from` doesn't have a value, `to` already has one or is being fetched,
+    // or the cache is frozen. The value keeps its access times.
+    pub async fn rename(&self, from: K, to: K) -> bool {
+        if self.is_frozen() {
+            return false;
+        }
+        let from = self.canonical(&from);
+        let to = self.canonical(&to);
+        let mut data = self.data.lock().await;
+        if from == to
+            || !matches!(data.get(&from), Some(CacheEntry::Node(_)))
+            || matches!(
+                data.get(&to),
+                Some(CacheEntry::Node(_) | CacheEntry::Fetching(_))
+            )
+        {
+            return false;
+        }
+        let entry = data.remove(&from).unwrap();
+        data.insert(to, entry);
+        self.config.record_remove(&from);
+        self.config.record_insert(&to);
+        true
+    }
+
+    // Where the cached value for `k` came from, if it has one.
+    pub async fn value_source(&self, k: &K) -> Option<ValueSource> {
+        match self.data.lock().await.get(&self.canonical(k)) {
+            Some(CacheEntry::Node(node)) => Some(node.unwrap().source),
+            _ => None,
+        }
+    }
+
+    // The keys that are being fetched, e.g. to find fetches that are stuck.
+    pub async fn fetching_keys(&self) -> Vec<K> {
+        self.data
+            .lock()
+            .await
+            .iter()
+            .filter(|(_, entry)| matches!(entry, CacheEntry::Fetching(_)))
+            .map(|(k, _)| *k)
+            .collect()
+    }
+
+    // Removes the value for `k` without writing it back. A fetch in progress
+    // for `k` is tombstoned, so its waiters fetch again and its result isn't
+    // cached. This does nothing while the cache is frozen.
+    pub async fn remove(&self, k: K) {
+        if self.is_frozen() {
+            return;
+        }
+        let mut data = self.data.lock().await;
+        let k = self.canonical(&k);
+        Self::invalidate(&mut data, &self.config, &self.last_written, &k);
+        Self::publish_emptiness(&self.emptiness, &data);
+    }
+
+    // Removes `k`'s entry, tombstoning the fetch if it's being fetched.
+    fn invalidate(
+        data: &mut HashMap<K, CacheEntry<V>>,
+        config: &Config<K, V>,
+        last_written: &LastWritten<K, V>,
+        k: &K,
+    ) -> Option<CacheEntry<V>> {
+        // The store may have changed, so a value equal to the last one written
+        // back still needs to be written.
+        Self::forget_written(last_written, k);
+        let entry = data.remove(k);
+        match &entry {
+            Some(CacheEntry::Fetching(in_flight)) => {
+                in_flight.tombstone.store(true, Ordering::Relaxed);
+            }
+            Some(CacheEntry::Node(_)) => config.record_remove(k),
+            Some(CacheEntry::FetchFailed(..)) | None => {}
+        }
+        if entry.is_some() {
+            config.record_event(k, EventKind::Invalidate);
+        }
+        entry
+    }
+
+    // Forgets the value last written back for `k`, once `k`'s value leaves
+    // the cache without being written back, so that `last_written` only
+    // grows with the keys that are written back.
+    fn forget_written(last_written: &LastWritten<K, V>, k: &K) {
+        last_written.lock().unwrap().remove(k);
+    }
+
+    // Removes each key that `stream` yields, as `remove` does, e.g. to keep the
+    // cache coherent with other processes by subscribing to a bus of changed
+    // keys. The stream is polled in a background task until it ends or the
+    // last clone of the cache is dropped.
+    pub fn attach_invalidation_stream(&self, stream: impl Stream<Item = K> + Send + 'static) {
+        // The task doesn't hold a clone of the cache, which would keep the
+        // cache's background tasks, this one included, from being stopped.
+        let data = self.data.clone();
+        let emptiness = self.emptiness.clone();
+        let config = self.config.clone();
+        let last_written = self.last_written.clone();
+        let invalidation_join_handle = self.config.spawn_background(async move {
+            let mut stream = std::pin::pin!(stream);
+            while let Some(k) = poll_fn(|cx| stream.as_mut().poll_next(cx)).await {
+                let k = match &config.canonicalize {
+                    Some(canonicalize) => canonicalize(&k),
+                    None => k,
+                };
+                let mut data = data.lock().await;
+                Self::invalidate(&mut data, &config, &last_written, &k);
+                Self::publish_emptiness(&emptiness, &data);
+            }
+        });
+        let mut background = self.background.lock().unwrap();
+        background
+            .invalidation_join_handles
+            .retain(|invalidation_join_handle| !invalidation_join_handle.is_finished());
+        background
+            .invalidation_join_handles
+            .push(invalidation_join_handle);
+    }
+
+    // Removes and returns the value for `k` in one step, so that no other
+    // caller can get it too. The value isn't written back, and a miss doesn't
+    // fetch. A fetch in progress for `k` is left alone.
+    pub async fn get_and_remove(&self, k: &K) -> Option<Arc<V>> {
+        let k = self.canonical(k);
+        let mut data = self.data.lock().await;
+        let value = match data.remove(&k)? {
+            CacheEntry::Node(CacheNode::Real(real_node)) => real_node.value,
+            CacheEntry::Node(CacheNode::Dummy) => {
+                unreachable!("Dummy node observed outside of an eviction")
+            }
+            entry @ (CacheEntry::Fetching(_) | CacheEntry::FetchFailed(..)) => {
+                data.insert(k, entry);
+                return None;
+            }
+        };
+        self.config.record_remove(&k);
+        Self::forget_written(&self.last_written, &k);
+        Self::publish_emptiness(&self.emptiness, &data);
+        Some(value)
+    }
+
+    // Removes and returns every value in one step, e.g. to flush the cache to
+    // another sink, so that nothing is inserted or evicted in between. The
+    // values aren't written back. Fetches in progress are left alone.
+    pub async fn take_all(&self) -> Vec<(K, Arc<V>)> {
+        let mut data = self.data.lock().await;
+        let mut values = vec![];
+        data.retain(|k, entry| match entry {
+            CacheEntry::Node(node) => {
+                values.push((*k, node.unwrap().value.clone()));
+                self.config.record_remove(k);
+                Self::forget_written(&self.last_written, k);
+                false
+            }
+            CacheEntry::Fetching(_) | CacheEntry::FetchFailed(..) => true,
+        });
+        Self::publish_emptiness(&self.emptiness, &data);
+        values
+    }
+
+    // Panics if the cache's state is inconsistent, e.g. between the steps of a
+    // stress test. It checks that no `Dummy` node is left in the cache, that
+    // no fetch that can be abandoned has gone without waiters for longer than
+    // it takes to abandon it, that the emptiness watch is up to date, and that
+    // the total weight and the count of fetching keys match the entries.
+    #[cfg(debug_assertions)]
+    pub async fn check_invariants(&self) {
+        const ABANDON_GRACE: Duration = Duration::from_secs(1);
+
+        let data = self.data.lock().await;
+        for (k, entry) in data.iter() {
+            match entry {
+                CacheEntry::Node(CacheNode::Dummy) => {
+                    panic!("Dummy node for key {} outside of an eviction", k)
+                }
+                CacheEntry::Fetching(in_flight) => {
+                    let abandoned = !in_flight.detached && in_flight.tx.receiver_count() == 0;
+                    assert!(
+                        !abandoned || in_flight.started.elapsed() < ABANDON_GRACE,
+                        "Fetch for key {} has had no waiters for {:?}",
+                        k,
+                        in_flight.started.elapsed()
+                    );
+                }
+                CacheEntry::Node(CacheNode::Real(_)) | CacheEntry::FetchFailed(..) => {}
+            }
+        }
+        assert_eq!(
+            Self::count_values(&data) == 0,
+            *self.emptiness.subscribe().borrow(),
+            "The emptiness watch is out of date"
+        );
+        let weight: usize = data
+            .iter()
+            .map(|(k, entry)| match entry {
+                CacheEntry::Node(node) => self.config.weigh(k, &node.unwrap().value),
+                _ => 0,
+            })
+            .sum();
+        assert_eq!(
+            weight,
+            self.config.total_weight(),
+            "The total weight is out of date"
+        );
+        let fetching = data
+            .values()
+            .filter(|entry| matches!(entry, CacheEntry::Fetching(_)))
+            .count();
+        assert_eq!(
+            fetching,
+            self.fetching.load(Ordering::Relaxed),
+            "The count of fetching keys is out of date"
+        );
+    }
+
+    // Returns a receiver of whether the cache has no values, e.g. to react
+    // to the cache draining. It changes whenever the cache goes from empty to
+    // non-empty or back.
+    pub fn emptiness_watch(&self) -> watch::Receiver<bool> {
+        self.emptiness.subscribe()
+    }
+
+    fn publish_emptiness(emptiness: &watch::Sender<bool>, data: &HashMap<K, CacheEntry<V>>) {
+        let is_empty = !data
+            .values()
+            .any(|entry| matches!(entry, CacheEntry::Node(_)));
+        emptiness.send_if_modified(|was_empty| mem::replace(was_empty, is_empty) != is_empty);
+    }
+
+    // Returns false if the key can't be evicted because the reference
+    // count of the Arc is not one. The evicted value is added to `evicted`,
+    // so that it can be sent to the evictor after unlocking.
+    fn try_evict_without_lock(
+        &self,
+        k: K,
+        lock: &mut HashMap<K, CacheEntry<V>>,
+        evicted: &mut Vec<(K, Evicted<V>)>,
+    ) -> bool {
+        match lock.entry(k) {
+            hash_map::Entry::Vacant(_) => true,
+            hash_map::Entry::Occupied(mut e) => match e.get_mut() {
+                CacheEntry::Fetching(_) | CacheEntry::FetchFailed(..) => {
+                    e.remove();
+                    true
+                }
+                CacheEntry::Node(node) => match mem::replace(node, CacheNode::Dummy) {
+                    CacheNode::Real(real_node) => {
+                        match real_node.try_unwrap_evicted(&self.config) {
+                            Ok(v) => {
+                                e.remove();
+                                self.config.record_remove(&k);
+                                self.config.record_event(&k, EventKind::Evict);
+                                evicted.push((k, v));
+                                true
+                            }
+                            Err(real_node) => {
+                                // If the unwrap wasn't successful, replace the dummy cache node
+                                // with the real cache node.
+                                *node = CacheNode::Real(real_node);
+                                false
+                            }
+                        }
+                    }
+                    CacheNode::Dummy => false,
+                },
+            },
+        }
+    }
+
+    pub async fn try_evict(&self, k: K) -> bool {
+        if self.is_frozen() {
+            return false;
+        }
+        let k = self.canonical(&k);
+        let data = self.data.clone();
+        let mut lock = data.lock().await;
+        let mut victims = vec![];
+        let evicted = self.try_evict_without_lock(k, &mut lock, &mut victims);
+        Self::publish_emptiness(&self.emptiness, &lock);
+        drop(lock);
+
+        // With `EvictOverflow::Block`, sending can wait for the evictor, which
+        // mustn't hold up gets.
+        if !victims.is_empty() {
+            self.evict_tx().send_batch(victims).await;
+        }
+        evicted
+    }
+
+    pub async fn evict_all_sync(&mut self) {
+        self.evict_all_sync_impl(None, &mut |_| ()).await
+    }
+
+    // Like `evict_all_sync`, but calls `on_progress` after each pass over the
+    // cache, e.g. to log how a long shutdown is going. Passes are retried
+    // until every value has been evicted.
+    pub async fn evict_all_sync_with_progress(
+        &mut self,
+        mut on_progress: impl FnMut(EvictProgress) + Send,
+    ) {
+        self.evict_all_sync_impl(None, &mut on_progress).await
+    }
+
+    // Like `evict_all_sync`, but only retries pinned entries `max_retries`
+    // times, backing off between attempts. Entries that are still referenced
+    // after that are written back as a clone of their current value.
+    pub async fn evict_all_sync_force(&mut self, max_retries: usize)
+    where
+        V: Clone,
+    {
+        self.evict_all_sync_impl(Some((max_retries, &V::clone)), &mut |_| ())
+            .await
+    }
+
+    // Writes back every value and shuts the cache down, after which gets fail
+    // with `CacheError::ShuttingDown` and inserts do nothing. Fetches and
+    // refreshes still in progress are aborted, and their waiters fail. Fails
+    // with `CacheError::UpdateFailed` if a writeback has failed since the
+    // cache was created or since the last shutdown.
+    pub async fn shutdown(&mut self) -> Result<(), CacheError> {
+        self.shut_down.store(true, Ordering::Relaxed);
+        self.evict_all_sync().await;
+        // Writebacks that failed during the evictions are given up on.
+        self.put_backs.wait_all().await;
+        self.tasks.abort_all().await;
+        match self.update_failure.lock().unwrap().take() {
+            Some(err) => Err(CacheError::UpdateFailed(err)),
+            None => Ok(()),
+        }
+    }
+
+    // Shuts the cache down in a task of its own once `signal` resolves, e.g.
+    // on a signal that `on_shutdown_signal` doesn't handle. The handle
+    // resolves to the result of `shutdown`.
+    pub fn shutdown_on(
+        mut self,
+        signal: impl Future<Output = ()> + Send + 'static,
+    ) -> tokio::task::JoinHandle<Result<(), CacheError>> {
+        tokio::spawn(async move {
+            signal.await;
+            self.shutdown().await
+        })
+    }
+
+    // Shuts the cache down, writing back every value, when the process gets
+    // SIGTERM, e.g. when its container is stopped. The handler is installed
+    // before this returns, so it fails if the handler can't be.
+    #[cfg(all(feature = "signal", unix))]
+    pub fn on_shutdown_signal(self) -> io::Result<tokio::task::JoinHandle<Result<(), CacheError>>> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut sigterm = signal(SignalKind::terminate())?;
+        Ok(self.shutdown_on(async move {
+            sigterm.recv().await;
+        }))
+    }
+
+    // Shuts the cache down and hands back its store, e.g. to close a
+    // connection the store holds. Returns `None` if something else still
+    // references the store, such as another clone of the cache or a fetch
+    // that's still in progress. The store is returned even if a writeback
+    // failed.
+    pub async fn into_store(mut self) -> Option<Arc<dyn Store<K, V> + Send + Sync>> {
+        let _ = self.shutdown().await;
+
+        // Stop the evictor and wait for it to drop its reference to the store.
+        let evictor_join_handle = {
+            let mut background = self.background.lock().unwrap();
+            background.pruner = None;
+            background.evictor_join_handle.take()
+        };
+        if let Some(evictor_join_handle) = evictor_join_handle {
+            evictor_join_handle.abort();
+            let _ = evictor_join_handle.await;
+        }
+
+        let store = self.current_store();
+        drop(self);
+        (Arc::strong_count(&store) == 1).then_some(store)
+    }
+
+    // Whether the circuit breaker has stopped fetches from the store, after
+    // too many failed in a row. This is always false without
+    // `circuit_breaker`.
+    pub fn is_circuit_open(&self) -> bool {
+        self.breaker
+            .as_ref()
+            .is_some_and(|breaker| breaker.is_open())
+    }
+
+    // Whether another key can't be fetched, with `max_fetching_keys` or
+    // `max_tasks`. Keys only start being fetched with the cache locked, so
+    // this should be checked with it locked.
+    fn is_too_busy(&self) -> bool {
+        self.config
+            .max_fetching_keys
+            .is_some_and(|max| self.fetching.load(Ordering::Relaxed) >= max)
+            || !self.has_room_for_task()
+    }
+
+    // Whether another fetch or refresh task can be spawned, with `max_tasks`.
+    fn has_room_for_task(&self) -> bool {
+        self.config
+            .max_tasks
+            .is_none_or(|max| self.tasks.len() < max)
+    }
+
+    // Whether the circuit breaker lets a fetch go to the store now. While the
+    // circuit is open, this lets a probe through every so often.
+    fn fetch_allowed(&self) -> bool {
+        self.breaker
+            .as_ref()
+            .is_none_or(|breaker| breaker.allow(self.config.clock.now()))
+    }
+
+    pub fn is_shut_down(&self) -> bool {
+        self.shut_down.load(Ordering::Relaxed)
+    }
+
+    async fn evict_all_sync_impl(
+        &mut self,
+        force: Option<(usize, &CloneFn<V>)>,
+        on_progress: &mut (dyn FnMut(EvictProgress) + Send),
+    ) {
+        // Make sure to hold the lock until the end of the function.
+        let mut data = self.data.lock().await;
+        let mut retries = 0;
+        let mut retry_delay = Duration::from_millis(100);
+        loop {
+            let keys: Vec<_> = data.keys().copied().collect();
+            if keys.is_empty() {
+                break;
+            }
+
+            let before = keys.len();
+            let mut all_done = true;
+            let mut victims = vec![];
+            for key in keys {
+                // Every key is tried, even after one that can't be evicted yet.
+                all_done &= self.try_evict_without_lock(key, &mut data, &mut victims);
+            }
+            // The cache stays locked until the evictor is done anyway, so
+            // there's no point in unlocking to send.
+            if !victims.is_empty() {
+                self.evict_tx().send_batch(victims).await;
+            }
+            on_progress(EvictProgress {
+                remaining: data.len(),
+                evicted: before - data.len(),
+            });
+
+            if all_done {
+                break;
+            }
+
+            match force {
+                None => sleep(Duration::from_secs(1)).await,
+                Some((max_retries, clone)) if retries >= max_retries => {
+                    on_progress(EvictProgress {
+                        remaining: 0,
+                        evicted: data.len(),
+                    });
+                    for (key, entry) in data.drain() {
+                        if let CacheEntry::Node(CacheNode::Real(real_node)) = entry {
+                            self.config.record_remove(&key);
+                            self.config.record_event(&key, EventKind::Evict);
+                            let needs_writeback = self.config.needs_writeback(real_node.source);
+                            let v = match RealCacheNode::try_unwrap(real_node) {
+                                Ok(v) => v,
+                                Err(real_node) => clone(&real_node.value),
+                            };
+                            let v = match needs_writeback {
+                                true => Evicted::Dirty(v),
+                                false => Evicted::Clean(v),
+                            };
+                            self.evict_tx().send((key, v)).await;
+                        }
+                    }
+                    break;
+                }
+                Some(_) => {
+                    retries += 1;
+                    sleep(retry_delay).await;
+                    retry_delay *= 2;
+                }
+            }
+        }
+        Self::publish_emptiness(&self.emptiness, &data);
+
         // At this point, the cache is empty and we need to wait for the evictor
         // to finish. To do this, we construct a new evictor_join_handle
-        // and .await on the old one. This requires constructing a new channel
-        // and a new pruner_join_handle.
+        // and .await on the old one. This requires constructing a new channel.
+        // The pruner looks the sender up for each sweep, so it's kept.
+
+        let (new_evict_tx, new_evict_rx) =
+            Self::evict_channel(&self.config, &self.stats, &self.writeback_backlog);
+
+        let new_evictor_join_handle = Self::evictor_join_handle(
+            new_evict_rx,
+            self.store.clone(),
+            self.update_failure.clone(),
+            self.writeback_enabled.clone(),
+            Writeback {
+                data: self.data.clone(),
+                emptiness: self.emptiness.clone(),
+                update_attempts: self.update_attempts.clone(),
+                last_written: self.last_written.clone(),
+                update_failure: self.update_failure.clone(),
+                shut_down: self.shut_down.clone(),
+                put_backs: self.put_backs.clone(),
+            },
+            &self.config,
+        );
+
+        let old_evictor_join_handle = {
+            drop(std::mem::replace(
+                &mut *self.evict_tx.lock().unwrap(),
+                new_evict_tx,
+            ));
+            self.background
+                .lock()
+                .unwrap()
+                .evictor_join_handle
+                .replace(new_evictor_join_handle)
+        };
+
+        // Wait for the old evictor to evict everything.
+        if let Some(old_evictor_join_handle) = old_evictor_join_handle {
+            old_evictor_join_handle.await.unwrap();
+        }
+    }
+
+    fn evictor_join_handle(
+        mut rx: EvictReceiver<K, V>,
+        store: SharedStore<K, V>,
+        update_failure: UpdateFailure,
+        writeback_enabled: Arc<AtomicBool>,
+        writeback: Writeback<K, V>,
+        config: &Arc<Config<K, V>>,
+    ) -> tokio::task::JoinHandle<()> {
+        let writeback = Arc::new(writeback);
+        let background_config = config.clone();
+        config.spawn_background(async move {
+            let config = background_config;
+            while let Some(batch) = rx.recv().await {
+                let count = batch.len();
+                // Look up the store for every batch so that a store swapped
+                // in with `set_store` receives all subsequent writebacks.
+                let store = store.read().unwrap().clone();
+
+                // The update runs in its own task so that a panicking store
+                // doesn't take the evictor down with it.
+                let retry = config.update_retry.map(|retry| (retry, config.clone()));
+                let skip_unchanged = config.skip_unchanged_writebacks;
+                let writeback = writeback.clone();
+                let writeback_enabled = writeback_enabled.clone();
+                let update = tokio::spawn(async move {
+                    for (k, v) in &batch {
+                        let retrying = retry.is_some()
+                            && writeback.update_attempts.lock().unwrap().contains_key(k);
+                        if !retrying {
+                            store.finalize(k, v.value()).await;
+                        }
+                    }
+                    if !writeback_enabled.load(Ordering::Relaxed) {
+                        return;
+                    }
+                    let mut batch: Vec<_> = batch
+                        .into_iter()
+                        .filter_map(|(k, v)| v.into_dirty().map(|v| (k, v)))
+                        .collect();
+                    if batch.is_empty() {
+                        return;
+                    }
+                    // The values are only recorded as written once their
+                    // update returns, so that one that panics or fails isn't
+                    // skipped next time.
+                    let mut written = HashMap::new();
+                    if let Some(skip_unchanged) = skip_unchanged {
+                        written = writeback.skip_unchanged(&mut batch, skip_unchanged);
+                        if batch.is_empty() {
+                            return;
+                        }
+                    }
+
+                    if let Some(((max_attempts, backoff), config)) = retry {
+                        for (k, v) in batch {
+                            let Err((v, err)) = store.try_update(k, v).await else {
+                                writeback.update_attempts.lock().unwrap().remove(&k);
+                                writeback.record_written(written.remove_entry(&k));
+                                continue;
+                            };
+                            let attempts = writeback.record_failure(k);
+                            // Nothing would evict a value put back after
+                            // shutdown, so shutdown gives up on it instead.
+                            if attempts < max_attempts && !writeback.is_shut_down() {
+                                let doubling = 2u32.saturating_pow(attempts as u32 - 1);
+                                let delay = backoff.saturating_mul(doubling);
+                                writeback.put_back(k, v, delay, &config);
+                                continue;
+                            }
+                            let message =
+                                format!("Update for key {} failed {} times: {}", k, attempts, err);
+                            writeback.give_up(k, message, &config);
+                        }
+                    } else if batch.len() == 1 {
+                        let (k, v) = batch.pop().unwrap();
+                        store.update(k, v).await;
+                        writeback.record_written(written);
+                    } else {
+                        store.update_many(batch).await;
+                        writeback.record_written(written);
+                    }
+                });
+                if let Err(err) = update.await {
+                    let message = format!("Update panicked: {}", panic_message(err));
+                    config.report_background_error(BackgroundPhase::Update, message.clone());
+                    let err = anyhow::anyhow!(message);
+                    update_failure.lock().unwrap().get_or_insert(Arc::new(err));
+                }
+                rx.finish(count);
+            }
+        })
+    }
+
+    // Starts the pruner, either in a task of its own or, if the cache was
+    // built with a `CacheRuntime`, by registering with the runtime.
+    fn pruner(
+        data: Data<K, V>,
+        evict_tx: EvictTx<K, V>,
+        config: Arc<Config<K, V>>,
+        frozen: Arc<AtomicBool>,
+        emptiness: Emptiness,
+    ) -> Option<Pruner> {
+        if !config.pruner {
+            return None;
+        }
+        let sweeper = Arc::new(Sweeper {
+            data,
+            evict_tx,
+            config: config.clone(),
+            frozen,
+            emptiness,
+        });
+        Some(match &config.runtime {
+            Some(runtime) => {
+                let sweeper: Arc<dyn Sweep> = sweeper;
+                runtime.register(&sweeper);
+                Pruner::Shared { _sweep: sweeper }
+            }
+            None => Pruner::Task(config.spawn_background(async move {
+                loop {
+                    sweeper.sweep_once().await;
+                    sleep(sweeper.config.prune_interval).await;
+                }
+            })),
+        })
+    }
+
+    // Returns the keys of the values that should be evicted. These are the
+    // values that haven't been accessed within their access TTL, followed by
+    // the least recently used values needed to get back under
+    // `max_capacity`, each least recently used first, or the ones the
+    // eviction policy picks. Values that are still referenced are skipped
+    // since they can't be evicted.
+    fn select_victims(
+        data: &HashMap<K, CacheEntry<V>>,
+        config: &Config<K, V>,
+        now: Instant,
+    ) -> Vec<K> {
+        let values = Self::count_values(data);
+        let (expired, live): (Vec<_>, Vec<_>) = Self::evictable(data)
+            .into_iter()
+            .partition(|candidate| candidate.is_expired(config, now));
+        let over_capacity = config.max_capacity.map_or(0, |max_capacity| {
+            (values - expired.len()).saturating_sub(max_capacity)
+        });
+
+        expired
+            .into_iter()
+            .chain(Self::by_policy(
+                config,
+                live,
+                |candidate| candidate.key,
+                over_capacity,
+            ))
+            .map(|candidate| candidate.key)
+            .collect()
+    }
+
+    // Removes and returns the least recently used values, or the ones the
+    // eviction policy picks, that need to be evicted to get back under
+    // `max_weight`.
+    fn take_overweight(
+        data: &mut HashMap<K, CacheEntry<V>>,
+        config: &Config<K, V>,
+    ) -> Vec<(K, Evicted<V>)> {
+        let Some(max_weight) = config.max_weight else {
+            return vec![];
+        };
+        if config.total_weight() <= max_weight {
+            return vec![];
+        }
+        let mut victims = vec![];
+        let candidates = Self::evictable(data);
+        let count = candidates.len();
+        for candidate in Self::by_policy(config, candidates, |candidate| candidate.key, count) {
+            // Taking a value drops its node, which takes its weight off the
+            // total.
+            if config.total_weight() <= max_weight {
+                break;
+            }
+            let k = candidate.key;
+            if let Some(v) = Self::try_take(data, config, k) {
+                victims.push((k, v));
+            }
+        }
+        victims
+    }
+
+    // Returns the values that nothing else references, least recently used
+    // first.
+    fn evictable(data: &HashMap<K, CacheEntry<V>>) -> Vec<Candidate<K>> {
+        let mut candidates: Vec<_> = data
+            .iter()
+            .filter_map(|(k, entry)| match entry {
+                CacheEntry::Node(node) if Arc::strong_count(&node.unwrap().value) == 1 => {
+                    let real_node = node.unwrap();
+                    Some(Candidate {
+                        key: *k,
+                        first_access_ts: real_node.first_access_ts,
+                        last_access_ts: real_node.last_access_ts,
+                        ttl: real_node.ttl,
+                    })
+                }
+                _ => None,
+            })
+            .collect();
+        candidates.sort_by_key(|candidate| candidate.last_access_ts);
+        candidates
+    }
+
+    // Returns the first `count` of `candidates`, which are least recently
+    // used first, or the ones the eviction policy picks, in the order it
+    // picks them.
+    fn by_policy<C>(
+        config: &Config<K, V>,
+        mut candidates: Vec<C>,
+        key: impl Fn(&C) -> K,
+        count: usize,
+    ) -> Vec<C> {
+        let Some(eviction_policy) = &config.eviction_policy else {
+            candidates.truncate(count);
+            return candidates;
+        };
+        let keys: Vec<_> = candidates.iter().map(&key).collect();
+        let mut candidates: HashMap<_, _> = candidates
+            .into_iter()
+            .map(|candidate| (key(&candidate), candidate))
+            .collect();
+        eviction_policy
+            .select_victims(&keys, count)
+            .into_iter()
+            .filter_map(|k| candidates.remove(&k))
+            .take(count)
+            .collect()
+    }
+
+    // Evicts the least recently used `fraction` of the values that can be
+    // evicted, or the ones the eviction policy picks, e.g. when an external
+    // monitor sees memory pressure. Returns the number of values evicted.
+    // This does nothing while the cache is frozen.
+    pub async fn evict_fraction(&self, fraction: f64) -> usize {
+        if self.is_frozen() {
+            return 0;
+        }
+        let mut data = self.data.lock().await;
+        let candidates = Self::evictable(&data);
+        let count = (candidates.len() as f64 * fraction.clamp(0.0, 1.0)).round() as usize;
+        let keys: Vec<_> =
+            Self::by_policy(&self.config, candidates, |candidate| candidate.key, count)
+                .into_iter()
+                .map(|candidate| candidate.key)
+                .collect();
+        let mut evicted = 0;
+        let mut victims = vec![];
+        for k in keys {
+            if let Some(v) = Self::try_take(&mut data, &self.config, k) {
+                evicted += 1;
+                victims.push((k, v));
+            }
+        }
+        Self::publish_emptiness(&self.emptiness, &data);
+        drop(data);
+
+        if let Some(order) = &self.config.eviction_order {
+            victims.sort_by(|(a, _), (b, _)| order(a, b));
+        }
+        if !victims.is_empty() {
+            self.evict_tx().send_batch(victims).await;
+        }
+        evicted
+    }
+
+    // Evicts the least recently used value that can be evicted, or the one the
+    // eviction policy picks, and returns its key, e.g. for an external
+    // controller that shrinks the cache one value at a time. Returns `None` if
+    // no value can be evicted or the cache is frozen.
+    pub async fn evict_one(&self) -> Option<K> {
+        if self.is_frozen() {
+            return None;
+        }
+        let mut data = self.data.lock().await;
+        let candidates = Self::evictable(&data);
+        let k = Self::by_policy(&self.config, candidates, |candidate| candidate.key, 1)
+            .first()?
+            .key;
+        let v = Self::try_take(&mut data, &self.config, k)?;
+        Self::publish_emptiness(&self.emptiness, &data);
+        drop(data);
+
+        self.evict_tx().send((k, v)).await;
+        Some(k)
+    }
+
+    // Evicts every value that hasn't been accessed for more than `age` right
+    // away, instead of waiting for the pruner. Returns the number of values
+    // evicted. Values that are still referenced are skipped, and this does
+    // nothing while the cache is frozen.
+    pub async fn evict_older_than(&self, age: Duration) -> usize {
+        if self.is_frozen() {
+            return 0;
+        }
+        let now = self.config.clock.now();
+        let mut data = self.data.lock().await;
+        let keys: Vec<_> = Self::evictable(&data)
+            .into_iter()
+            .take_while(|candidate| now.saturating_duration_since(candidate.last_access_ts) > age)
+            .map(|candidate| candidate.key)
+            .collect();
+        let mut evicted = 0;
+        let mut victims = vec![];
+        for k in keys {
+            if let Some(v) = Self::try_take(&mut data, &self.config, k) {
+                evicted += 1;
+                victims.push((k, v));
+            }
+        }
+        Self::publish_emptiness(&self.emptiness, &data);
+        drop(data);
+
+        if let Some(order) = &self.config.eviction_order {
+            victims.sort_by(|(a, _), (b, _)| order(a, b));
+        }
+        if !victims.is_empty() {
+            self.evict_tx().send_batch(victims).await;
+        }
+        evicted
+    }
+
+    // Removes the value for `k` if nothing else references it. Returns `None`
+    // if it wasn't removed.
+    fn try_take(
+        data: &mut HashMap<K, CacheEntry<V>>,
+        config: &Config<K, V>,
+        k: K,
+    ) -> Option<Evicted<V>> {
+        let hash_map::Entry::Occupied(mut e) = data.entry(k) else {
+            return None;
+        };
+        let CacheEntry::Node(node) = e.get_mut() else {
+            return None;
+        };
+        match mem::replace(node, CacheNode::Dummy) {
+            CacheNode::Real(real_node) => {
+                match RealCacheNode::try_unwrap_evicted(real_node, config) {
+                    Ok(v) => {
+                        e.remove();
+                        config.record_remove(&k);
+                        config.record_event(&k, EventKind::Evict);
+                        Some(v)
+                    }
+                    Err(real_node) => {
+                        *node = CacheNode::Real(real_node);
+                        None
+                    }
+                }
+            }
+            CacheNode::Dummy => None,
+        }
+    }
+
+    // Runs one pruner sweep now and returns once the evicted values have been
+    // sent to the evictor, e.g. to prune deterministically in benchmarks and
+    // tests. Combined with `no_pruner` and a `TickClock`, nothing is evicted
+    // except by explicit calls, and real time doesn't matter. The sweep is
+    // skipped while the cache is frozen.
+    pub async fn run_prune_once(&self) {
+        let sweeper = Sweeper {
+            data: self.data.clone(),
+            evict_tx: self.evict_tx.clone(),
+            config: self.config.clone(),
+            frozen: self.frozen.clone(),
+            emptiness: self.emptiness.clone(),
+        };
+        sweeper.sweep_once().await;
+    }
+
+    // Returns the keys the pruner would evict if it ran now, without
+    // evicting them.
+    pub async fn dry_run_eviction(&self) -> Vec<K> {
+        let data = self.data.lock().await;
+        Self::select_victims(&data, &self.config, self.config.clock.now())
+    }
+
+    fn web_join_handle(
+        data: Data<K, V>,
+        config: Arc<Config<K, V>>,
+    ) -> tokio::task::JoinHandle<io::Result<()>> {
+        tokio::spawn(async move {
+            let mut app = tide::with_state(data);
+            app.at("/").get(move |req: tide::Request<Data<K, V>>| {
+                let config = config.clone();
+                async move {
+                    let mut table = String::from("<table>");
+                    table.push_str(
+                        "
+                        <tr>
+                          <th>Key</th>
+                          <th>Time since last access</th>
+                          <th>Time in cache</th>
+                          <th>Strong count</th>
+                        </tr>",
+                    );
+                    let data = req.state().lock().await;
+                    let now = config.clock.now();
+                    for (k, entry) in &*data {
+                        table.push_str("<tr>");
+                        table += &*format!("<td>{}</td>", k);
+                        table += "<td>";
+                        table += &match entry {
+                            CacheEntry::Fetching(_) => String::from("Fetching..."),
+                            CacheEntry::Node(node) => match node {
+                                CacheNode::Real(real_node) => {
+                                    now.duration_since(real_node.last_access_ts)
+                                        .as_secs()
+                                        .to_string()
+                                        + " secs."
+                                }
+                                CacheNode::Dummy => String::from("<Dummy>"),
+                            },
+                            CacheEntry::FetchFailed(..) => String::from("<Fetch error>"),
+                        };
+                        table += "</td>";
+                        if let CacheEntry::Node(CacheNode::Real(real_node)) = entry {
+                            let time_in_cache =
+                                now.duration_since(real_node.first_access_ts).as_secs();
+                            table += &format!("<td>{time_in_cache} secs.</td>");
+                        } else {
+                            table += "<td></td>";
+                        }
+                        if let CacheEntry::Node(CacheNode::Real(real_node)) = entry {
+                            let strong_count = Arc::strong_count(&real_node.value);
+                            table += &format!("<td>{strong_count}</td>");
+                        } else {
+                            table += "<td></td>";
+                        }
+                        table.push_str("</tr>");
+                    }
+                    table.push_str("</table>");
+
+                    let response = format!(
+                        "
+                        <html>
+                          <head>
+                            <style>
+                              table {{
+                                width: 100%;
+                                border-collapse: collapse;
+                              }}
+                              td, th {{
+                                border: 1px solid black;
+                                text-align: left;
+                                padding: 8px;
+                              }}
+                            </style>
+                          </head>
+                          <body>
+                            <h1>{}</h1>
+                            <p>Access TTL: {} secs.</p>
+                            {table}
+                          </body>
+                        </html>
+                    ",
+                        config.name,
+                        config.access_ttl.as_secs()
+                    );
+
+                    Ok(tide::Response::builder(200)
+                        .body(response)
+                        .content_type(tide::http::mime::HTML)
+                        .build())
+                }
+            });
+            app.listen("127.0.0.1:8030").await
+        })
+    }
+}
+
+// A locked view of the cache, for `Cache::read_transaction`.
+pub struct ReadView<'a, K, V> {
+    data: &'a HashMap<K, CacheEntry<V>>,
+    cache: &'a Cache<K, V>,
+}
+
+impl<K, V> ReadView<'_, K, V>
+where
+    K: std::hash::Hash + fmt::Display + Copy + Eq + Send + Sync + 'static,
+    V: Send + Sync + 'static,
+{
+    // Returns the cached value for `k` without fetching it on a miss. Reads
+    // through the view don't count as accesses, so they don't reset the
+    // value's access TTL.
+    pub fn get_if_present(&self, k: &K) -> Option<Arc<V>> {
+        match self.data.get(&self.cache.canonical(k)) {
+            Some(CacheEntry::Node(node)) => Some(node.unwrap().value.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        Cache::count_values(self.data)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+pub struct Entry<'a, K, V> {
+    key: K,
+    data: tokio::sync::MutexGuard<'a, HashMap<K, CacheEntry<V>>>,
+    cache: &'a Cache<K, V>,
+}
+
+impl<'a, K, V> Entry<'a, K, V>
+where
+    K: std::hash::Hash + fmt::Display + Copy + Eq + Send + Sync + 'static,
+    V: Send + Sync + 'static,
+{
+    pub fn key(&self) -> K {
+        self.key
+    }
+
+    // Returns the cached value, inserting `v` if there isn't one. Like
+    // `insert`, inserting follows `insert_during_fetch` if the key is being
+    // fetched, and nothing is inserted while the cache is frozen or shut down.
+    // `v` is returned as is when it isn't inserted.
+    pub fn or_insert(self, v: Arc<V>) -> Arc<V> {
+        self.or_insert_with(|| v)
+    }
+
+    pub fn or_insert_with(mut self, f: impl FnOnce() -> Arc<V>) -> Arc<V> {
+        let now = self.cache.config.clock.now();
+        if let Some(CacheEntry::Node(node)) = self.data.get_mut(&self.key) {
+            let real_node = node.unwrap_mut();
+            real_node.bump_access_time(&self.key, &self.cache.config, now);
+            return real_node.value.clone();
+        }
+
+        let v = f();
+        if !self.cache.is_frozen() && !self.cache.is_shut_down() {
+            if let Some((_, overweight)) =
+                self.cache
+                    .insert_locked(&mut self.data, self.key, v.clone())
+            {
+                self.cache.send_overweight_now(overweight);
+            }
+        }
+        v
+    }
+
+    // Calls `f` with the cached value, if there is one. `f` can replace the
+    // value, or modify it in place with `Arc::get_mut` or `Arc::make_mut`.
+    pub fn and_modify(mut self, f: impl FnOnce(&mut Arc<V>)) -> Self {
+        if let Some(CacheEntry::Node(node)) = self.data.get_mut(&self.key) {
+            let real_node = node.unwrap_mut();
+            let now = self.cache.config.clock.now();
+            real_node.bump_access_time(&self.key, &self.cache.config, now);
+            real_node.source = ValueSource::Inserted;
+            f(&mut real_node.value);
+            real_node.reweigh(&self.key, &self.cache.config);
+            let overweight = Cache::take_overweight(&mut self.data, &self.cache.config);
+            Cache::publish_emptiness(&self.cache.emptiness, &self.data);
+            self.cache.send_overweight_now(overweight);
+        }
+        self
+    }
+
+    // Removes the entry without writing it back, returning its value if it
+    // had one.
+    pub fn remove(mut self) -> Option<Arc<V>> {
+        let entry = Cache::invalidate(
+            &mut self.data,
+            &self.cache.config,
+            &self.cache.last_written,
+            &self.key,
+        )?;
+        Cache::publish_emptiness(&self.cache.emptiness, &self.data);
+        match entry {
+            CacheEntry::Node(node) => match node {
+                CacheNode::Real(real_node) => Some(real_node.value),
+                CacheNode::Dummy => unreachable!("Dummy node observed outside of an eviction"),
+            },
+            CacheEntry::Fetching(_) | CacheEntry::FetchFailed(..) => None,
+        }
+    }
+}
+
+#[async_trait]
+impl<K, V> AsyncGet<K, V> for Cache<K, V>
+where
+    K: std::hash::Hash + fmt::Display + Copy + Eq + Send + Sync + 'static,
+    V: Send + Sync + 'static,
+{
+    async fn get(&self, k: K) -> Result<Arc<V>, CacheError> {
+        Cache::get(self, k).await
+    }
+}
+
+fn panic_message(err: tokio::task::JoinError) -> String {
+    match err.try_into_panic() {
+        Ok(payload) => panic_payload_message(payload),
+        Err(err) => err.to_string(),
+    }
+}
+
+fn panic_payload_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&'static str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => String::from("<non-string panic payload>"),
+        },
+    }
+}
+
+// What the evictor needs to put back values whose writeback failed, with
+// `update_retries`.
+struct Writeback<K, V> {
+    data: Data<K, V>,
+    emptiness: Emptiness,
+    update_attempts: UpdateAttempts<K>,
+    last_written: LastWritten<K, V>,
+    update_failure: UpdateFailure,
+    shut_down: Arc<AtomicBool>,
+    put_backs: Arc<TaskTracker>,
+}
+
+impl<K, V> Writeback<K, V>
+where
+    K: std::hash::Hash + fmt::Display + Copy + Eq + Send + Sync + 'static,
+    V: Send + Sync + 'static,
+{
+    // Returns the number of times in a row that `k`'s writeback has failed,
+    // including this time.
+    fn record_failure(&self, k: K) -> usize {
+        // The store might not have the last value that was written anymore.
+        self.last_written.lock().unwrap().remove(&k);
+        let mut update_attempts = self.update_attempts.lock().unwrap();
+        let attempts = update_attempts.entry(k).or_insert(0);
+        *attempts += 1;
+        *attempts
+    }
+
+    // Drops the values in `batch` that are equal to the last value written
+    // back for their key, and returns copies of the rest, to record with
+    // `record_written` once they've been written.
+    fn skip_unchanged(
+        &self,
+        batch: &mut Vec<(K, V)>,
+        (eq, clone): SkipUnchanged<V>,
+    ) -> HashMap<K, V> {
+        let last_written = self.last_written.lock().unwrap();
+        batch.retain(|(k, v)| !last_written.get(k).is_some_and(|written| eq(written, v)));
+        batch.iter().map(|(k, v)| (*k, clone(v))).collect()
+    }
+
+    fn record_written(&self, written: impl IntoIterator<Item = (K, V)>) {
+        self.last_written.lock().unwrap().extend(written);
+    }
+
+    fn is_shut_down(&self) -> bool {
+        self.shut_down.load(Ordering::Relaxed)
+    }
+
+    // Stops retrying `k`'s writeback, and reports the failure like a
+    // panicking writeback, so that `shutdown` fails with it.
+    fn give_up(&self, k: K, message: String, config: &Config<K, V>) {
+        self.update_attempts.lock().unwrap().remove(&k);
+        config.report_background_error(BackgroundPhase::Update, message.clone());
+        let err = anyhow::anyhow!(message);
+        self.update_failure
+            .lock()
+            .unwrap()
+            .get_or_insert(Arc::new(err));
+    }
+
+    // Puts `v` back in the cache with an access TTL of `delay`, so that the
+    // pruner evicts it again once the delay is up. This happens in a task of
+    // its own, since `evict_all_sync` holds the cache's lock while it waits
+    // for the evictor, and `shutdown` waits for the task. If the cache has
+    // been shut down by the time the task runs, the value is given up on.
+    fn put_back(self: &Arc<Self>, k: K, v: V, delay: Duration, config: &Arc<Config<K, V>>) {
+        let writeback = self.clone();
+        let config = config.clone();
+        self.put_backs.spawn(async move {
+            let mut data = writeback.data.lock().await;
+            if writeback.is_shut_down() {
+                drop(data);
+                let message = format!("Update for key {} was being retried at shutdown", k);
+                writeback.give_up(k, message, &config);
+                return;
+            }
+            match data.entry(k) {
+                hash_map::Entry::Vacant(e) => {
+                    let weight = NodeWeight::new(&config, &k, &v);
+                    let node = CacheNode::new(
+                        Arc::new(v),
+                        config.clock.now(),
+                        Some(delay),
+                        ValueSource::Inserted,
+                        weight,
+                    );
+                    e.insert(CacheEntry::Node(node));
+                    config.record_insert(&k);
+                    Cache::publish_emptiness(&writeback.emptiness, &data);
+                }
+                hash_map::Entry::Occupied(_) => {
+                    writeback.update_attempts.lock().unwrap().remove(&k);
+                }
+            }
+        });
+    }
+}
+
+// The most keys a sweep looks at, or evicts, with the cache locked.
+const SWEEP_CHUNK: usize = 1024;
+
+// A value that a sweep could evict.
+struct SweepCandidate<K> {
+    candidate: Candidate<K>,
+    inserted: bool,
+    // This is zero without `max_weight`.
+    weight: usize,
+}
+
+// What a pruner needs to sweep a cache.
+struct Sweeper<K, V> {
+    data: Data<K, V>,
+    // A shared pruner can still be sweeping while `evict_all_sync` waits for
+    // the old evictor, so it doesn't hold on to a sender.
+    evict_tx: EvictTx<K, V>,
+    config: Arc<Config<K, V>>,
+    frozen: Arc<AtomicBool>,
+    emptiness: Emptiness,
+}
+
+impl<K, V> Sweeper<K, V>
+where
+    K: std::hash::Hash + fmt::Display + Copy + Eq + Send + Sync + 'static,
+    V: Send + Sync + 'static,
+{
+    // Evicts the values that have expired, then the least recently used ones,
+    // or the ones the eviction policy picks, needed to get back under
+    // `max_capacity` and `max_weight`. The cache is only locked for
+    // `SWEEP_CHUNK` keys at a time, so that gets aren't held up by a sweep of
+    // a big cache. A value that's accessed after the sweep looked at it is
+    // left for the next sweep. Errors that were cached for a while are
+    // dropped once they expire, since only a get of the same key drops them
+    // otherwise.
+    async fn sweep_once(&self) {
+        if self.frozen.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let now = self.config.clock.now();
+        let keys: Vec<K> = {
+            let mut data = self.data.lock().await;
+            data.retain(|_, entry| {
+                !matches!(entry, CacheEntry::FetchFailed(_, Some(expires_at)) if now >= *expires_at)
+            });
+            data.iter()
+                .filter(|(_, entry)| matches!(entry, CacheEntry::Node(_)))
+                .map(|(k, _)| *k)
+                .collect()
+        };
+        let mut candidates = vec![];
+        let mut weight = 0;
+        for chunk in keys.chunks(SWEEP_CHUNK) {
+            tokio::task::yield_now().await;
+            let mut data = self.data.lock().await;
+            let looked = self.catch_panic(|| {
+                for k in chunk {
+                    let Some(CacheEntry::Node(CacheNode::Real(real_node))) = data.get_mut(k) else {
+                        continue;
+                    };
+                    let value_weight = match self.config.max_weight {
+                        Some(_) => real_node.weight.weight,
+                        None => 0,
+                    };
+                    weight += value_weight;
+                    if Arc::strong_count(&real_node.value) == 1 {
+                        candidates.push(SweepCandidate {
+                            candidate: Candidate {
+                                key: *k,
+                                first_access_ts: real_node.first_access_ts,
+                                last_access_ts: real_node.last_access_ts,
+                                ttl: real_node.ttl,
+                            },
+                            inserted: real_node.source == ValueSource::Inserted,
+                            weight: value_weight,
+                        });
+                    } else if self.config.renew_while_held {
+                        real_node.bump_access_time(k, &self.config, now);
+                    }
+                }
+            });
+            if looked.is_none() {
+                return;
+            }
+        }
+
+        // The eviction policy is a hook too.
+        let Some(victims) =
+            self.catch_panic(|| self.select_victims(candidates, keys.len(), weight))
+        else {
+            return;
+        };
+        let mut taken = vec![];
+        for chunk in victims.chunks(SWEEP_CHUNK) {
+            tokio::task::yield_now().await;
+            let mut data = self.data.lock().await;
+            for victim in chunk {
+                let k = victim.candidate.key;
+                let unchanged = matches!(
+                    data.get(&k),
+                    Some(CacheEntry::Node(CacheNode::Real(real_node)))
+                        if real_node.last_access_ts == victim.candidate.last_access_ts
+                );
+                if !unchanged {
+                    continue;
+                }
+                if let Some(v) = Cache::try_take(&mut data, &self.config, k) {
+                    taken.push((victim.inserted, (k, v)));
+                }
+            }
+            Cache::publish_emptiness(&self.emptiness, &data);
+        }
+
+        // Inserted values are written back before fetched ones, since the
+        // store may not have them yet, unless there's an eviction order.
+        taken.sort_by_key(|(inserted, _)| !inserted);
+        let mut victims: Vec<_> = taken.into_iter().map(|(_, victim)| victim).collect();
+        if let Some(order) = &self.config.eviction_order {
+            self.catch_panic(|| victims.sort_by(|(a, _), (b, _)| order(a, b)));
+        }
+
+        // Send the whole sweep as one batch so that it's written back with a
+        // single `update_many`.
+        if !victims.is_empty() {
+            Cache::evict_sender(&self.evict_tx)
+                .send_batch(victims)
+                .await;
+        }
+    }
+
+    // Picks the values to evict out of the ones that can be, given that the
+    // cache has `values` values weighing `weight` in total.
+    fn select_victims(
+        &self,
+        mut candidates: Vec<SweepCandidate<K>>,
+        values: usize,
+        weight: usize,
+    ) -> Vec<SweepCandidate<K>> {
+        let config = &self.config;
+        candidates.sort_by_key(|candidate| candidate.candidate.last_access_ts);
+        let now = config.clock.now();
+        let (mut victims, live): (Vec<_>, Vec<_>) = candidates
+            .into_iter()
+            .partition(|candidate| candidate.candidate.is_expired(config, now));
+        let over_capacity = config.max_capacity.map_or(0, |max_capacity| {
+            (values - victims.len()).saturating_sub(max_capacity)
+        });
+        // Without `max_weight`, the policy only needs to pick enough values
+        // to get back under `max_capacity`.
+        let count = match config.max_weight {
+            Some(_) => live.len(),
+            None => over_capacity,
+        };
+        let mut live =
+            Cache::by_policy(config, live, |candidate| candidate.candidate.key, count).into_iter();
+        victims.extend(live.by_ref().take(over_capacity));
+        if let Some(max_weight) = config.max_weight {
+            let mut weight = weight - victims.iter().map(|victim| victim.weight).sum::<usize>();
+            for candidate in live {
+                if weight <= max_weight {
+                    break;
+                }
+                weight -= candidate.weight;
+                victims.push(candidate);
+            }
+        }
+        victims
+    }
+
+    // Sweeps call hooks like the weigher, so a panic is caught to keep it
+    // from killing the pruner. Returns `None` if `f` panicked.
+    fn catch_panic<R>(&self, f: impl FnOnce() -> R) -> Option<R> {
+        std::panic::catch_unwind(std::panic::AssertUnwindSafe(f))
+            .map_err(|payload| {
+                let message = format!("Sweep panicked: {}", panic_payload_message(payload));
+                self.config
+                    .report_background_error(BackgroundPhase::Prune, message);
+            })
+            .ok()
+    }
+}
+
+impl<K, V> Sweep for Sweeper<K, V>
+where
+    K: std::hash::Hash + fmt::Display + Copy + Eq + Send + Sync + 'static,
+    V: Send + Sync + 'static,
+{
+    fn sweep(&self) -> BoxFuture<'_, ()> {
+        Box::pin(self.sweep_once())
+    }
+}
+
+impl<K, V> fmt::Debug for Cache<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Cache")
+            .field("name", &self.config.name)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<K, V> Clone for Cache<K, V> {
+    fn clone(&self) -> Self {
+        Self {
+            data: self.data.clone(),
+            background: self.background.clone(),
+            evict_tx: self.evict_tx.clone(),
+            store: self.store.clone(),
+            config: self.config.clone(),
+            stats: self.stats.clone(),
+            frozen: self.frozen.clone(),
+            emptiness: self.emptiness.clone(),
+            inserted: self.inserted.clone(),
+            shut_down: self.shut_down.clone(),
+            update_failure: self.update_failure.clone(),
+            update_attempts: self.update_attempts.clone(),
+            last_written: self.last_written.clone(),
+            writeback_enabled: self.writeback_enabled.clone(),
+            fetch_gate: self.fetch_gate.clone(),
+            breaker: self.breaker.clone(),
+            fetch_task: self.fetch_task.clone(),
+            fetching: self.fetching.clone(),
+            debounced: self.debounced.clone(),
+            writeback_backlog: self.writeback_backlog.clone(),
+            tasks: self.tasks.clone(),
+            put_backs: self.put_backs.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::task::JoinSet;
+    use tokio::time::{sleep, Duration};
+
+    use crate::evict::EvictOverflow;
+    use crate::executor::{BlockingStore, FetchExecutor};
+    use crate::policy::EvictionPolicy;
+    use crate::runtime::CacheRuntime;
+    use crate::testing::{HashMapStore, StoreOperation};
+
+    fn test_store() -> HashMapStore<i32, String> {
+        HashMapStore::new().with_default(String::from("Hello"))
+    }
+
+    fn store_with_latency() -> HashMapStore<i32, String> {
+        test_store()
+            .with_fetch_latency(Duration::from_secs(1))
+            .with_update_latency(Duration::from_secs(1))
+    }
+
+    #[tokio::test]
+    async fn it_works() {
+        let store = test_store();
+
+        let mut cache = Cache::new(store.clone()).await;
+
+        {
+            let v = cache.get(10).await.unwrap();
+            assert_eq!("Hello", *v);
+            let v = cache.get(10).await.unwrap();
+            assert_eq!("Hello", *v);
+        }
+
+        cache.evict_all_sync().await;
+
+        drop(cache);
+
+        assert_eq!(
+            vec![
+                StoreOperation::Fetch(10),
+                StoreOperation::Update(10, "Hello".to_string())
+            ],
+            store.operations()
+        );
+    }
+
+    #[tokio::test]
+    async fn set_store() {
+        let old_store = test_store();
+        let new_store = test_store();
+
+        let cache = Cache::new(old_store.clone()).await;
+
+        drop(cache.get(1).await.unwrap());
+        assert_eq!(vec![1], old_store.fetches());
+
+        cache.set_store(new_store.clone());
+
+        drop(cache.get(2).await.unwrap());
+        assert_eq!(vec![2], new_store.fetches());
+
+        // The value fetched from the old store is kept, but it's written back
+        // to the new one.
+        assert!(cache.try_evict(1).await);
+        assert_eq!(
+            vec![(1, "Hello".to_string())],
+            new_store.wait_for_updates(1).await
+        );
+        assert!(old_store.updates().is_empty());
+    }
+
+    #[tokio::test]
+    async fn evict_all_sync_force() {
+        let store = test_store();
+
+        let mut cache = Cache::new(store.clone()).await;
+
+        let v = cache.get(1).await.unwrap();
+        cache.evict_all_sync_force(2).await;
+
+        // The reference is still outstanding, but a copy was written back.
+        assert_eq!("Hello", *v);
+        assert_eq!(
+            vec![
+                StoreOperation::Fetch(1),
+                StoreOperation::Update(1, "Hello".to_string())
+            ],
+            store.operations()
+        );
+    }
+
+    #[tokio::test]
+    async fn get_lazy_key() {
+        let store = test_store();
+        let cache = Cache::new(store.clone()).await;
+
+        cache.insert(1, Arc::new(String::from("World"))).await;
+
+        let v = cache
+            .get_lazy_key(&1, || unreachable!("key constructed on a hit"))
+            .await
+            .unwrap();
+        assert_eq!("World", *v);
+
+        let mut constructed = false;
+        let v = cache
+            .get_lazy_key(&2, || {
+                constructed = true;
+                2
+            })
+            .await
+            .unwrap();
+        assert_eq!("Hello", *v);
+        assert!(constructed);
+        assert_eq!(vec![2], store.fetches());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn eviction_order() {
+        let store = test_store();
+        let cache = Cache::builder(store.clone())
+            .access_ttl(Duration::from_secs(1))
+            .prune_interval(Duration::from_secs(1))
+            .eviction_order(|a: &i32, b: &i32| b.cmp(a))
+            .build()
+            .await;
+
+        for k in [3, 1, 4, 5, 2] {
+            cache.insert(k, Arc::new(k.to_string())).await;
+        }
+
+        let keys: Vec<_> = store
+            .wait_for_updates(5)
+            .await
+            .into_iter()
+            .map(|(k, _)| k)
+            .collect();
+        assert_eq!(vec![5, 4, 3, 2, 1], keys);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn touch() {
+        let store = test_store();
+        let cache = Cache::builder(store.clone())
+            .access_ttl(Duration::from_secs(2))
+            .prune_interval(Duration::from_secs(1))
+            .build()
+            .await;
+
+        cache.insert(1, Arc::new(String::from("one"))).await;
+        cache.insert(2, Arc::new(String::from("two"))).await;
+
+        sleep(Duration::from_millis(1500)).await;
+        assert!(cache.touch(&1).await);
+        assert!(!cache.touch(&3).await);
+
+        // Only the untouched key expires.
+        assert_eq!(
+            vec![(2, String::from("two"))],
+            store.wait_for_updates(1).await
+        );
+        assert_eq!("one", *cache.get(1).await.unwrap());
+        assert!(store.fetches().is_empty());
+    }
+
+    struct PanicOnceStore {
+        panicked: std::sync::atomic::AtomicBool,
+    }
+
+    #[async_trait]
+    impl Store<i32, String> for PanicOnceStore {
+        async fn fetch(&self, _key: &i32) -> anyhow::Result<String> {
+            if !self
+                .panicked
+                .swap(true, std::sync::atomic::Ordering::SeqCst)
+            {
+                panic!("Store is broken");
+            }
+            Ok(String::from("Hello"))
+        }
+
+        async fn update(&self, _key: i32, _value: String) {}
+    }
+
+    #[tokio::test]
+    async fn panicking_fetch() {
+        let cache = Cache::new(PanicOnceStore {
+            panicked: Default::default(),
+        })
+        .await;
+
+        let err = cache.get(1).await.unwrap_err();
+        assert!(err
+            .store_error()
+            .unwrap()
+            .to_string()
+            .contains("Store is broken"));
+
+        assert_eq!("Hello", *cache.get(1).await.unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn try_evict_sends_after_unlocking() {
+        let store = test_store().with_update_latency(Duration::from_secs(10));
+        let cache = Cache::builder(store.clone())
+            .evict_channel_capacity(1)
+            .no_pruner()
+            .build()
+            .await;
+        for k in 1..=3 {
+            cache.insert(k, Arc::new(k.to_string())).await;
+        }
+
+        // The evictor is busy with the first value, and the second fills the
+        // channel, so evicting the third waits for room.
+        assert!(cache.try_evict(1).await);
+        sleep(Duration::from_millis(1)).await;
+        assert!(cache.try_evict(2).await);
+        let evict = tokio::spawn({
+            let cache = cache.clone();
+            async move { cache.try_evict(3).await }
+        });
+        sleep(Duration::from_millis(1)).await;
+        assert!(!evict.is_finished());
+
+        // Gets aren't held up in the meantime.
+        let start = Instant::now();
+        assert_eq!("Hello", *cache.get(4).await.unwrap());
+        assert_eq!(start, Instant::now());
+        assert!(evict.await.unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn evict_overflow_drop() {
+        let store = test_store().with_update_latency(Duration::from_secs(10));
+        let cache = Cache::builder(store.clone())
+            .access_ttl(Duration::from_secs(1))
+            .prune_interval(Duration::from_secs(1))
+            .evict_channel_capacity(1)
+            .evict_overflow(EvictOverflow::Drop)
+            .eviction_order_by_key()
+            .build()
+            .await;
+
+        // The first sweep's batch is taken by the evictor, which is then busy
+        // writing it back.
+        cache.insert(1, Arc::new(1.to_string())).await;
+        sleep(Duration::from_millis(1500)).await;
+
+        // The next sweep's batch fills the channel, and the one after that is
+        // dropped whole.
+        for k in 2..=3 {
+            cache.insert(k, Arc::new(k.to_string())).await;
+        }
+        sleep(Duration::from_secs(2)).await;
+        for k in 4..=6 {
+            cache.insert(k, Arc::new(k.to_string())).await;
+        }
+        sleep(Duration::from_secs(2)).await;
+        #[cfg(feature = "stats")]
+        assert_eq!(3, cache.stats().dropped_writebacks);
+
+        // Only what fit is written back.
+        let updates = store.wait_for_updates(3).await;
+        assert_eq!(
+            vec![(1, 1.to_string()), (2, 2.to_string()), (3, 3.to_string())],
+            updates
+        );
+    }
+
+    #[tokio::test]
+    async fn stats_consistent() {
+        let cache = Arc::new(Cache::new(test_store()).await);
+
+        let mut tasks = JoinSet::new();
+        for k in 0..20 {
+            let cache = cache.clone();
+            tasks.spawn(async move {
+                cache.get(k % 10).await.unwrap();
+            });
+        }
+        while let Some(res) = tasks.join_next().await {
+            res.unwrap();
+        }
+
+        let stats = cache.stats_consistent().await;
+        assert_eq!(Some(cache.len().await), stats.entries);
+        assert_eq!(Some(10), stats.entries);
+        #[cfg(feature = "stats")]
+        assert_eq!(20, stats.hits + stats.misses);
+        assert_eq!(None, cache.stats().entries);
+    }
+
+    // Only runs without the `stats` feature, e.g. with
+    // `cargo test --no-default-features --lib stats_disabled`.
+    #[cfg(not(feature = "stats"))]
+    #[tokio::test]
+    async fn stats_disabled() {
+        let cache = Cache::new(test_store()).await;
+        cache.get(1).await.unwrap();
+        cache.get(1).await.unwrap();
+
+        assert_eq!(CacheStats::default(), cache.stats());
+        assert_eq!(Some(1), cache.stats_consistent().await.entries);
+    }
+
+    struct MockGetter;
+
+    #[async_trait]
+    impl AsyncGet<i32, String> for MockGetter {
+        async fn get(&self, k: i32) -> Result<Arc<String>, CacheError> {
+            Ok(Arc::new(format!("Mock {k}")))
+        }
+    }
+
+    async fn greeting(getter: Arc<dyn AsyncGet<i32, String> + Send + Sync>) -> String {
+        format!("{}!", getter.get(1).await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn async_get() {
+        let cache = Cache::new(test_store()).await;
+        assert_eq!("Hello!", greeting(Arc::new(cache)).await);
+        assert_eq!("Mock 1!", greeting(Arc::new(MockGetter)).await);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn gets_interleaved_with_evictions() {
+        // Every pruner sweep tries to evict everything.
+        let cache = Arc::new(
+            Cache::builder(test_store())
+                .access_ttl(Duration::ZERO)
+                .prune_interval(Duration::from_millis(1))
+                .build()
+                .await,
+        );
+
+        let mut tasks = JoinSet::new();
+        for i in 0..8 {
+            let cache = cache.clone();
+            tasks.spawn(async move {
+                for j in 0..500 {
+                    let k = (i + j) % 4;
+                    match j % 3 {
+                        // Evicting a key that's being fetched abandons the
+                        // fetch, so a get is cancelled if that happens twice.
+                        0 => match cache.get(k).await {
+                            Ok(v) => assert_eq!("Hello", *v),
+                            Err(err) => assert!(matches!(err, CacheError::Cancelled), "{err}"),
+                        },
+                        1 => {
+                            cache.try_evict(k).await;
+                        }
+                        _ => {
+                            cache.touch(&k).await;
+                        }
+                    }
+                }
+            });
+        }
+
+        while let Some(res) = tasks.join_next().await {
+            res.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn get_many_results() {
+        let store = HashMapStore::new();
+        store.insert(1, String::from("one"));
+        store.insert(3, String::from("three"));
+        let cache = Cache::new(store.clone()).await;
+
+        let results = cache.get_many_results(&[1, 2, 3]).await;
+        assert_eq!("one", **results[0].as_ref().unwrap());
+        assert!(results[1].is_err());
+        assert_eq!("three", **results[2].as_ref().unwrap());
+
+        // The failure wasn't cached.
+        store.insert(2, String::from("two"));
+        assert_eq!("two", *cache.get(2).await.unwrap());
+        assert_eq!(vec![1, 2, 3, 2], store.fetches());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn freeze() {
+        let store = test_store();
+        let cache = Cache::builder(store.clone())
+            .access_ttl(Duration::from_secs(1))
+            .prune_interval(Duration::from_secs(1))
+            .build()
+            .await;
+
+        cache.insert(1, Arc::new(String::from("one"))).await;
+        cache.freeze();
+
+        assert!(matches!(cache.get(2).await, Err(CacheError::Frozen)));
+        assert!(matches!(
+            cache.get_many_results(&[2]).await[0],
+            Err(CacheError::Frozen)
+        ));
+        cache.insert(3, Arc::new(String::from("three"))).await;
+        assert!(!cache.try_evict(1).await);
+        cache.remove(1).await;
+        assert!(!cache.rename(1, 4).await);
+
+        // Existing values are still served, but aren't pruned.
+        sleep(Duration::from_secs(3)).await;
+        assert_eq!(1, cache.len().await);
+        assert!(store.operations().is_empty());
+
+        cache.unfreeze();
+        assert_eq!("Hello", *cache.get(2).await.unwrap());
+        assert_eq!(vec![2], store.fetches());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn before_fetch() {
+        let store = store_with_latency();
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let cache = Arc::new(
+            Cache::builder(store.clone())
+                .before_fetch({
+                    let calls = calls.clone();
+                    move |k| {
+                        assert_ne!(5, *k, "Hook failed");
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        Box::pin(async {})
+                    }
+                })
+                .build()
+                .await,
+        );
+
+        let mut tasks = JoinSet::new();
+        for k in 0..10 {
+            let cache = cache.clone();
+            tasks.spawn(async move { cache.get(k % 2).await.unwrap() });
+        }
+        while let Some(res) = tasks.join_next().await {
+            res.unwrap();
+        }
+
+        assert_eq!(2, store.fetches().len());
+        assert_eq!(2, calls.load(Ordering::SeqCst));
+
+        // A panicking hook fails the fetch instead of leaving it stuck.
+        let err = cache.get(5).await.unwrap_err();
+        assert!(err.to_string().contains("Hook failed"));
+        assert!(!cache.is_fetching(&5).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn dry_run_eviction() {
+        let store = test_store();
+        let cache = Cache::builder(store.clone())
+            .max_capacity(1)
+            .prune_interval(Duration::from_secs(3600))
+            .build()
+            .await;
+        // Let the pruner make its first sweep while the cache is empty.
+        sleep(Duration::from_secs(1)).await;
+
+        for k in 1..=3 {
+            cache.insert(k, Arc::new(k.to_string())).await;
+            sleep(Duration::from_secs(1)).await;
+        }
+        cache.touch(&1).await;
+
+        assert_eq!(vec![2, 3], cache.dry_run_eviction().await);
+        assert_eq!(3, cache.len().await);
+        assert!(store.updates().is_empty());
+    }
+
+    #[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+    struct TracedKey {
+        id: i32,
+        trace_id: u64,
+    }
+
+    impl fmt::Display for TracedKey {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{} ({})", self.id, self.trace_id)
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn canonicalize() {
+        let store = HashMapStore::new()
+            .with_default(String::from("Hello"))
+            .with_fetch_latency(Duration::from_secs(1));
+        let cache = Cache::builder(store.clone())
+            .canonicalize(|k: &TracedKey| TracedKey { trace_id: 0, ..*k })
+            .build()
+            .await;
+
+        let first = TracedKey { id: 1, trace_id: 7 };
+        let second = TracedKey { id: 1, trace_id: 8 };
+        let (a, b) = tokio::join!(cache.get(first), cache.get(second));
+        assert_eq!("Hello", *a.unwrap());
+        assert_eq!("Hello", *b.unwrap());
+
+        // The store sees the key that started the fetch.
+        assert_eq!(vec![first], store.fetches());
+        assert_eq!(1, cache.len().await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn tick_clock() {
+        let store = test_store();
+        let cache = Cache::builder(store.clone())
+            .clock(crate::clock::TickClock::new(Duration::from_secs(1)))
+            .access_ttl(Duration::from_secs(5))
+            .prune_interval(Duration::from_secs(1))
+            .build()
+            .await;
+
+        cache.insert(1, Arc::new(String::from("one"))).await;
+
+        // Real time passing doesn't expire anything.
+        sleep(Duration::from_secs(10)).await;
+        assert_eq!(1, cache.len().await);
+
+        cache.advance_ticks(5);
+        assert_eq!(
+            vec![(1, String::from("one"))],
+            store.wait_for_updates(1).await
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn no_pruner() {
+        let store = test_store();
+        let cache = Cache::builder(store.clone())
+            .access_ttl(Duration::ZERO)
+            .max_capacity(0)
+            .no_pruner()
+            .build()
+            .await;
+
+        cache.insert(1, Arc::new(String::from("one"))).await;
+        sleep(Duration::from_secs(3600)).await;
+
+        assert_eq!(1, cache.len().await);
+        assert!(store.updates().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn is_fetching() {
+        let cache = Arc::new(Cache::new(store_with_latency()).await);
+        assert!(!cache.is_fetching(&1).await);
+
+        let get = tokio::spawn({
+            let cache = cache.clone();
+            async move { cache.get(1).await.unwrap() }
+        });
+        sleep(Duration::from_millis(500)).await;
+        assert!(cache.is_fetching(&1).await);
+        assert!(!cache.is_fetching(&2).await);
+
+        get.await.unwrap();
+        assert!(!cache.is_fetching(&1).await);
+        assert!(!cache.is_fetching(&2).await);
+        assert_eq!(1, cache.len().await);
+    }
+
+    // The test runs on a single-threaded runtime, and the fetch blocks until
+    // the test has made progress, so this would deadlock if the fetch ran on
+    // the runtime's thread.
+    #[tokio::test]
+    async fn blocking_fetch_executor() {
+        let (started_tx, started_rx) = tokio::sync::oneshot::channel();
+        let (proceed_tx, proceed_rx) = std::sync::mpsc::channel::<()>();
+        let started_tx = std::sync::Mutex::new(Some(started_tx));
+        let proceed_rx = std::sync::Mutex::new(proceed_rx);
+        let store = BlockingStore::new(
+            move |k: &i32| {
+                started_tx.lock().unwrap().take().unwrap().send(()).unwrap();
+                proceed_rx.lock().unwrap().recv().unwrap();
+                Ok((std::thread::current().id(), *k))
+            },
+            |_, _| {},
+        );
+        let cache = Arc::new(
+            Cache::builder(store)
+                .fetch_executor(FetchExecutor::Blocking)
+                .build()
+                .await,
+        );
+
+        let get = tokio::spawn({
+            let cache = cache.clone();
+            async move { cache.get(1).await.unwrap() }
+        });
+        started_rx.await.unwrap();
+        assert!(cache.is_fetching(&1).await);
+        proceed_tx.send(()).unwrap();
+
+        let (fetch_thread, value) = *get.await.unwrap();
+        assert_eq!(1, value);
+        assert_ne!(std::thread::current().id(), fetch_thread);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn wait_for() {
+        let store = test_store();
+        let cache = Arc::new(Cache::new(store.clone()).await);
+
+        let waiter = tokio::spawn({
+            let cache = cache.clone();
+            async move { cache.wait_for(1, Duration::from_secs(10)).await }
+        });
+        sleep(Duration::from_secs(1)).await;
+        cache.insert(2, Arc::new(String::from("Other"))).await;
+        cache.insert(1, Arc::new(String::from("Inserted"))).await;
+        assert_eq!("Inserted", *waiter.await.unwrap().unwrap());
+
+        // Hits return immediately, and misses don't fetch.
+        assert_eq!("Other", *cache.wait_for(2, Duration::ZERO).await.unwrap());
+        assert!(cache.wait_for(3, Duration::from_secs(1)).await.is_none());
+        assert!(store.fetches().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pruner_batches_updates() {
+        let store = test_store();
+        let cache = Cache::builder(store.clone())
+            .access_ttl(Duration::from_secs(1))
+            .prune_interval(Duration::from_secs(1))
+            .eviction_order_by_key()
+            .build()
+            .await;
+
+        for k in 1..=10 {
+            cache.insert(k, Arc::new(k.to_string())).await;
+        }
+        store.wait_for_updates(10).await;
+
+        let expected: Vec<_> = (1..=10).map(|k| (k, k.to_string())).collect();
+        assert_eq!(
+            vec![StoreOperation::UpdateMany(expected)],
+            store.operations()
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn evict_fraction() {
+        let store = test_store();
+        let cache = Cache::builder(store.clone()).no_pruner().build().await;
+
+        for k in 1..=100 {
+            cache.insert(k, Arc::new(k.to_string())).await;
+            sleep(Duration::from_millis(1)).await;
+        }
+        let pinned = cache.get(1).await.unwrap();
+
+        assert_eq!(50, cache.evict_fraction(0.5).await);
+        assert_eq!(50, cache.len().await);
+        let evicted: Vec<_> = store
+            .wait_for_updates(50)
+            .await
+            .into_iter()
+            .map(|(k, _)| k)
+            .collect();
+        assert_eq!((2..=51).collect::<Vec<_>>(), evicted);
+        drop(pinned);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn evict_one() {
+        let store = test_store();
+        let cache = Cache::builder(store.clone()).no_pruner().build().await;
+        assert_eq!(None, cache.evict_one().await);
+
+        for k in 1..=3 {
+            cache.insert(k, Arc::new(k.to_string())).await;
+        }
+        for k in [3, 1, 2] {
+            sleep(Duration::from_secs(1)).await;
+            cache.touch(&k).await;
+        }
+
+        assert_eq!(Some(3), cache.evict_one().await);
+        assert_eq!(
+            vec![(3, String::from("3"))],
+            store.wait_for_updates(1).await
+        );
+        let pinned = cache.get(1).await.unwrap();
+        assert_eq!(Some(2), cache.evict_one().await);
+        assert_eq!(None, cache.evict_one().await);
+        assert_eq!(1, cache.len().await);
+        drop(pinned);
+    }
+
+    #[tokio::test]
+    async fn sweep_yields_to_gets() {
+        const VALUES: usize = 10 * SWEEP_CHUNK;
+        let cache = Cache::builder(test_store())
+            .access_ttl(Duration::ZERO)
+            .no_pruner()
+            .build()
+            .await;
+        for k in 0..VALUES as i32 {
+            cache.insert(k, Arc::new(k.to_string())).await;
+        }
+
+        // The test runs on a single thread, so reads can only see a partly
+        // evicted cache if the sweep lets go of the lock in between chunks.
+        let sweep = tokio::spawn({
+            let cache = cache.clone();
+            async move { cache.run_prune_once().await }
+        });
+        loop {
+            let len = cache.len().await;
+            if len < VALUES {
+                assert!(len > 0);
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+        assert!(!sweep.is_finished());
+        sweep.await.unwrap();
+        assert!(cache.is_empty().await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn remove_during_fetch() {
+        let store = store_with_latency();
+        let cache = Cache::builder(store.clone()).no_pruner().build().await;
+        let get = tokio::spawn({
+            let cache = cache.clone();
+            async move { cache.get(1).await }
+        });
+        sleep(Duration::from_millis(500)).await;
+        store.insert(1, String::from("Changed"));
+        cache.remove(1).await;
+
+        // The get fetches again after the removal, so it sees the change, and
+        // the fetch that was in progress doesn't cache the old value when it
+        // completes.
+        assert_eq!("Changed", *get.await.unwrap().unwrap());
+        assert_eq!(vec![1, 1], store.fetches());
+        assert_eq!("Changed", *cache.get(1).await.unwrap());
+
+        let many = tokio::spawn({
+            let cache = cache.clone();
+            async move { cache.get_many(&[2]).await }
+        });
+        sleep(Duration::from_millis(500)).await;
+        store.insert(2, String::from("Changed"));
+        cache.remove(2).await;
+        assert_eq!("Changed", *many.await.unwrap().unwrap()[0]);
+        assert_eq!("Changed", *cache.get(2).await.unwrap());
+    }
+
+    struct PanickingUpdateStore;
+
+    #[async_trait]
+    impl Store<i32, String> for PanickingUpdateStore {
+        async fn fetch(&self, _key: &i32) -> anyhow::Result<String> {
+            Ok(String::from("Hello"))
+        }
+
+        async fn update(&self, _key: i32, _value: String) {
+            panic!("Store is read-only");
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cache_errors() {
+        let cache = Arc::new(Cache::new(HashMapStore::<i32, String>::new()).await);
+        assert!(matches!(cache.get(1).await, Err(CacheError::Fetch(_))));
+
+        let cache = Arc::new(Cache::new(store_with_latency()).await);
+        assert!(matches!(
+            cache.get_with_timeout(1, Duration::from_millis(100)).await,
+            Err(CacheError::Timeout)
+        ));
+
+        // The get retries once after its fetch is abandoned, so it gives up
+        // when the retry's fetch is abandoned too.
+        let get = tokio::spawn({
+            let cache = cache.clone();
+            async move { cache.get(2).await }
+        });
+        for _ in 0..2 {
+            sleep(Duration::from_millis(100)).await;
+            assert!(cache.is_fetching(&2).await);
+            cache.remove(2).await;
+        }
+        assert!(matches!(get.await.unwrap(), Err(CacheError::Cancelled)));
+
+        let mut cache = Cache::new(PanickingUpdateStore).await;
+        cache.get(1).await.unwrap();
+        let err = cache.shutdown().await.unwrap_err();
+        assert!(matches!(err, CacheError::UpdateFailed(_)));
+        assert!(err.to_string().contains("Store is read-only"));
+
+        assert!(matches!(cache.get(1).await, Err(CacheError::ShuttingDown)));
+        assert!(matches!(
+            cache.get_many_results(&[1]).await[..],
+            [Err(CacheError::ShuttingDown)]
+        ));
+        assert!(cache.shutdown().await.is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shared_runtime() {
+        let runtime = CacheRuntime::new(Duration::from_secs(1));
+        let strings = test_store();
+        let numbers = HashMapStore::<u64, u64>::new().with_default(7);
+
+        // The caches' own prune interval is too long for them to be pruned
+        // by anything but the runtime.
+        let string_cache = Cache::builder(strings.clone())
+            .access_ttl(Duration::from_secs(1))
+            .prune_interval(Duration::from_secs(3600))
+            .runtime(&runtime)
+            .build()
+            .await;
+        let number_cache = Cache::builder(numbers.clone())
+            .access_ttl(Duration::from_secs(1))
+            .prune_interval(Duration::from_secs(3600))
+            .runtime(&runtime)
+            .build()
+            .await;
+        assert_eq!(2, runtime.cache_count());
+
+        string_cache.get(1).await.unwrap();
+        number_cache.get(2).await.unwrap();
+        sleep(Duration::from_secs(3)).await;
+
+        assert_eq!(vec![(1, String::from("Hello"))], strings.updates());
+        assert_eq!(vec![(2, 7)], numbers.updates());
+        assert!(string_cache.is_empty().await);
+        assert!(number_cache.is_empty().await);
+
+        drop(string_cache);
+        assert_eq!(1, runtime.cache_count());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn evict_all_sync_with_shared_runtime() {
+        let runtime = CacheRuntime::new(Duration::from_millis(100));
+        let store = test_store();
+        let cache = Cache::builder(store.clone())
+            .runtime(&runtime)
+            .build()
+            .await;
+        cache.insert(1, Arc::new(String::from("One"))).await;
+        let pinned = cache.get(1).await.unwrap();
+
+        // The drain holds the lock while the pinned value keeps it waiting,
+        // so the runtime's next sweep waits for the lock too.
+        let drain = tokio::spawn({
+            let mut cache = cache.clone();
+            async move { cache.evict_all_sync().await }
+        });
+        sleep(Duration::from_millis(500)).await;
+        drop(pinned);
+        tokio::time::timeout(Duration::from_secs(60), drain)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(vec![(1, String::from("One"))], store.updates());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fetch_deadline() {
+        let store = store_with_latency();
+        let cache = Arc::new(
+            Cache::builder(store.clone())
+                .fetch_deadline(Duration::from_millis(500))
+                .build()
+                .await,
+        );
+
+        let mut gets = JoinSet::new();
+        for delay in [0, 100, 200] {
+            let cache = cache.clone();
+            gets.spawn(async move {
+                sleep(Duration::from_millis(delay)).await;
+                let result = cache.get(1).await;
+                (Instant::now(), result)
+            });
+        }
+
+        let start = Instant::now();
+        while let Some(result) = gets.join_next().await {
+            let (finished, result) = result.unwrap();
+            assert!(matches!(result, Err(CacheError::Timeout)));
+            assert_eq!(Duration::from_millis(500), finished - start);
+        }
+        assert!(!cache.is_fetching(&1).await);
+        assert!(store.fetches().is_empty());
+        #[cfg(feature = "stats")]
+        assert_eq!(1, cache.stats().fetches);
+    }
+
+    // Records the parent and fields of every span. This only follows the
+    // current span on a single thread, which is all the tests need.
+    #[cfg(feature = "tracing")]
+    #[derive(Default)]
+    struct SpanRecorder {
+        // The name and parent of each span, indexed by ID - 1.
+        spans: std::sync::Mutex<Vec<(&'static str, Option<u64>)>>,
+        // The fields of each span, formatted as `name=value`.
+        fields: std::sync::Mutex<Vec<Vec<String>>>,
+        entered: std::sync::Mutex<Vec<u64>>,
+    }
+
+    #[cfg(feature = "tracing")]
+    #[derive(Default)]
+    struct FieldRecorder(Vec<String>);
+
+    #[cfg(feature = "tracing")]
+    impl tracing::field::Visit for FieldRecorder {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn fmt::Debug) {
+            self.0.push(format!("{}={:?}", field.name(), value));
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    impl tracing::Subscriber for SpanRecorder {
+        fn enabled(&self, _metadata: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            let parent = if span.is_contextual() {
+                self.entered.lock().unwrap().last().copied()
+            } else {
+                span.parent().map(tracing::span::Id::into_u64)
+            };
+            let mut fields = FieldRecorder::default();
+            span.record(&mut fields);
+            self.fields.lock().unwrap().push(fields.0);
+            let mut spans = self.spans.lock().unwrap();
+            spans.push((span.metadata().name(), parent));
+            tracing::span::Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, _span: &tracing::span::Id, _values: &tracing::span::Record<'_>) {}
+
+        fn record_follows_from(&self, _span: &tracing::span::Id, _follows: &tracing::span::Id) {}
+
+        fn event(&self, _event: &tracing::Event<'_>) {}
+
+        fn enter(&self, span: &tracing::span::Id) {
+            self.entered.lock().unwrap().push(span.into_u64());
+        }
+
+        fn exit(&self, _span: &tracing::span::Id) {
+            self.entered.lock().unwrap().pop();
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    #[tokio::test]
+    async fn fetch_span_is_child_of_caller() {
+        use tracing::Instrument;
+
+        let recorder = Arc::new(SpanRecorder::default());
+        let _guard = tracing::subscriber::set_default(recorder.clone());
+
+        let cache = Cache::new(test_store()).await;
+        cache
+            .get(1)
+            .instrument(tracing::info_span!("caller"))
+            .await
+            .unwrap();
+
+        let spans = recorder.spans.lock().unwrap();
+        let caller = spans
+            .iter()
+            .position(|(name, _)| *name == "caller")
+            .unwrap();
+        let fetch = spans.iter().find(|(name, _)| *name == "fetch").unwrap();
+        assert_eq!(Some(caller as u64 + 1), fetch.1);
+    }
+
+    #[cfg(feature = "tracing")]
+    #[tokio::test]
+    async fn name() {
+        let recorder = Arc::new(SpanRecorder::default());
+        let _guard = tracing::subscriber::set_default(recorder.clone());
+
+        let cache = Cache::builder(test_store()).name("users").build().await;
+        cache.get(1).await.unwrap();
+
+        assert_eq!("users", cache.name());
+        assert_eq!(r#"Cache { name: "users", .. }"#, format!("{:?}", cache));
+        let spans = recorder.spans.lock().unwrap();
+        let fetch = spans.iter().position(|(name, _)| *name == "fetch").unwrap();
+        assert!(recorder.fields.lock().unwrap()[fetch].contains(&String::from("cache=users")));
+    }
+
+    #[tokio::test]
+    async fn entry() {
+        let store = test_store();
+        let cache = Cache::new(store.clone()).await;
+
+        let v = cache
+            .entry(1)
+            .await
+            .or_insert_with(|| Arc::new(String::from("Inserted")));
+        assert_eq!("Inserted", *v);
+        let v = cache
+            .entry(1)
+            .await
+            .or_insert_with(|| unreachable!("The entry already has a value"));
+        assert_eq!("Inserted", *v);
+        drop(v);
+
+        let entry = cache
+            .entry(1)
+            .await
+            .and_modify(|v| Arc::make_mut(v).push('!'));
+        assert_eq!(1, entry.key());
+        assert_eq!("Inserted!", *entry.or_insert(Arc::new(String::new())));
+
+        assert_eq!("Inserted!", *cache.entry(1).await.remove().unwrap());
+        assert!(cache.entry(1).await.remove().is_none());
+        assert!(cache.is_empty().await);
+        assert!(store.operations().is_empty());
+    }
+
+    #[tokio::test]
+    async fn max_value_weight() {
+        let store = HashMapStore::new();
+        store.insert(1, String::from("Small"));
+        store.insert(2, "Large".repeat(100));
+        let cache = Cache::builder(store.clone())
+            .weigher(|_, v: &String| v.len())
+            .max_value_weight(100)
+            .build()
+            .await;
+
+        assert_eq!("Small", *cache.get(1).await.unwrap());
+        assert_eq!(500, cache.get(2).await.unwrap().len());
+        #[cfg(feature = "stats")]
+        assert_eq!(1, cache.stats().rejected_oversize);
+
+        // The oversize value isn't retained, so it's fetched again.
+        assert_eq!(1, cache.len().await);
+        cache.get(2).await.unwrap();
+        assert_eq!(vec![1, 2, 2], store.fetches());
+        #[cfg(feature = "stats")]
+        assert_eq!(2, cache.stats().rejected_oversize);
+    }
+
+    #[tokio::test]
+    async fn emptiness_watch() {
+        let mut cache = Cache::new(test_store()).await;
+        let mut emptiness = cache.emptiness_watch();
+        assert!(*emptiness.borrow_and_update());
+
+        cache.insert(1, Arc::new(String::from("One"))).await;
+        emptiness.changed().await.unwrap();
+        assert!(!*emptiness.borrow_and_update());
+
+        // Adding more values doesn't change anything.
+        cache.get(2).await.unwrap();
+        assert!(!emptiness.has_changed().unwrap());
+
+        cache.remove(1).await;
+        assert!(!emptiness.has_changed().unwrap());
+        cache.evict_all_sync().await;
+        emptiness.changed().await.unwrap();
+        assert!(*emptiness.borrow_and_update());
+    }
+
+    // Returns each key's value with an access TTL of that many seconds.
+    struct TtlStore(HashMapStore<i32, String>);
+
+    #[async_trait]
+    impl Store<i32, String> for TtlStore {
+        async fn fetch(&self, key: &i32) -> anyhow::Result<String> {
+            Ok(key.to_string())
+        }
+
+        async fn update(&self, key: i32, value: String) {
+            self.0.update(key, value).await
+        }
+
+        async fn fetch_with_ttl(&self, key: &i32) -> anyhow::Result<(String, Option<Duration>)> {
+            Ok((key.to_string(), Some(Duration::from_secs(*key as u64))))
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn store_ttl() {
+        let store = HashMapStore::new();
+        let cache = Cache::builder(TtlStore(store.clone()))
+            .prune_interval(Duration::from_secs(1))
+            .build()
+            .await;
+
+        for k in [2, 4] {
+            cache.get(k).await.unwrap();
+        }
+        cache.insert(1, Arc::new(String::from("Inserted"))).await;
+
+        sleep(Duration::from_millis(2500)).await;
+        assert_eq!(vec![(2, String::from("2"))], store.updates());
+        sleep(Duration::from_secs(2)).await;
+        assert_eq!(
+            vec![(2, String::from("2")), (4, String::from("4"))],
+            store.updates()
+        );
+
+        // Inserted values still use the cache's access TTL.
+        assert_eq!(1, cache.len().await);
+    }
+
+    #[tokio::test]
+    async fn into_store() {
+        let cache = Cache::new(test_store()).await;
+        cache.get(1).await.unwrap();
+        cache.insert(2, Arc::new(String::from("Inserted"))).await;
+
+        let store = cache.into_store().await.unwrap();
+        assert_eq!(1, Arc::strong_count(&store));
+        assert_eq!("Inserted", store.fetch(&2).await.unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn max_lifetime() {
+        let store = test_store();
+        let cache = Cache::builder(store.clone())
+            .access_ttl(Duration::from_secs(2))
+            .max_lifetime(Duration::from_secs(5))
+            .prune_interval(Duration::from_secs(1))
+            .build()
+            .await;
+
+        // Accessing the value keeps resetting its access TTL, but it's still
+        // evicted once it's been cached for the max lifetime.
+        for _ in 0..4 {
+            cache.get(1).await.unwrap();
+            sleep(Duration::from_millis(1000)).await;
+        }
+        cache.get(1).await.unwrap();
+        assert!(store.updates().is_empty());
+        sleep(Duration::from_millis(1500)).await;
+        assert_eq!(vec![(1, String::from("Hello"))], store.updates());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refresh_keeps_max_lifetime() {
+        let store = test_store();
+        store.insert(1, String::from("One"));
+        let cache = Cache::builder(store.clone())
+            .soft_ttl(Duration::from_secs(2))
+            .max_lifetime(Duration::from_secs(5))
+            .prune_interval(Duration::from_secs(1))
+            .build()
+            .await;
+        cache.get(1).await.unwrap();
+
+        // The refresh doesn't restart the value's lifetime.
+        store.insert(1, String::from("Uno"));
+        sleep(Duration::from_secs(3)).await;
+        cache.get(1).await.unwrap();
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!("Uno", *cache.get(1).await.unwrap());
+        sleep(Duration::from_secs(3)).await;
+        assert_eq!(vec![(1, String::from("Uno"))], store.updates());
+
+        // Nobody waited on the refresh, so it doesn't count as a fetch.
+        #[cfg(feature = "stats")]
+        assert_eq!(1, cache.stats().fetches);
+    }
+
+    #[tokio::test]
+    async fn get_and_remove() {
+        let store = test_store();
+        let mut cache = Cache::new(store.clone()).await;
+        cache.insert(1, Arc::new(String::from("Pending"))).await;
+
+        assert_eq!("Pending", *cache.get_and_remove(&1).await.unwrap());
+        assert!(cache.get_and_remove(&1).await.is_none());
+        assert!(cache.get_and_remove(&2).await.is_none());
+
+        cache.evict_all_sync().await;
+        assert!(store.operations().is_empty());
+    }
+
+    #[tokio::test]
+    async fn validate() {
+        let store = test_store();
+        store.insert(1, String::new());
+        let cache = Cache::builder(store.clone())
+            .validate(|_, v: &String| !v.is_empty())
+            .build()
+            .await;
+
+        assert_eq!("", *cache.get(1).await.unwrap());
+        assert_eq!("Hello", *cache.get(2).await.unwrap());
+        assert_eq!(1, cache.len().await);
+
+        // The invalid value wasn't cached, so it's fetched again.
+        store.insert(1, String::from("Fixed"));
+        assert_eq!("Fixed", *cache.get(1).await.unwrap());
+        assert_eq!(vec![1, 2, 1], store.fetches());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn touch_many() {
+        let store = test_store().with_fetch_latency(Duration::from_secs(1));
+        let cache = Arc::new(
+            Cache::builder(store.clone())
+                .access_ttl(Duration::from_secs(10))
+                .prune_interval(Duration::from_secs(1))
+                .eviction_order_by_key()
+                .build()
+                .await,
+        );
+        for k in 1..=4 {
+            cache.insert(k, Arc::new(k.to_string())).await;
+        }
+        tokio::spawn({
+            let cache = cache.clone();
+            async move { cache.get(5).await }
+        });
+
+        sleep(Duration::from_millis(500)).await;
+        assert!(cache.is_fetching(&5).await);
+        assert_eq!(2, cache.touch_many(&[1, 3, 5, 6]).await);
+
+        // Only the untouched values have expired.
+        sleep(Duration::from_millis(9700)).await;
+        let evicted: Vec<_> = store.updates().into_iter().map(|(k, _)| k).collect();
+        assert_eq!(vec![2, 4], evicted);
+    }
+
+    // An eviction that's started in the middle of a transaction has to wait
+    // for the transaction to finish. The transaction blocks on purpose to give
+    // the eviction a chance to run on another worker thread.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn read_transaction() {
+        let cache = Arc::new(Cache::new(test_store()).await);
+        cache.insert(1, Arc::new(String::from("One"))).await;
+        cache.insert(2, Arc::new(String::from("Two"))).await;
+
+        let (started_tx, started_rx) = tokio::sync::oneshot::channel();
+        let eviction = tokio::spawn({
+            let cache = cache.clone();
+            async move {
+                started_rx.await.unwrap();
+                cache.try_evict(2).await
+            }
+        });
+
+        // The values are copied out so that the eviction can go ahead as soon
+        // as the transaction is done.
+        let copy = |v: Option<Arc<String>>| v.map(|v| (*v).clone());
+        let values = cache
+            .read_transaction(|view| {
+                let one = copy(view.get_if_present(&1));
+                started_tx.send(()).unwrap();
+                std::thread::sleep(std::time::Duration::from_millis(100));
+                (one, copy(view.get_if_present(&2)), view.len())
+            })
+            .await;
+        assert_eq!(
+            (Some(String::from("One")), Some(String::from("Two")), 2),
+            values
+        );
+
+        assert!(eviction.await.unwrap());
+        assert_eq!(1, cache.len().await);
+    }
+
+    // Takes as many milliseconds to fetch a key as the key's value.
+    #[cfg(feature = "stats")]
+    struct SlowStore;
+
+    #[cfg(feature = "stats")]
+    #[async_trait]
+    impl Store<u64, u64> for SlowStore {
+        async fn fetch(&self, key: &u64) -> anyhow::Result<u64> {
+            sleep(Duration::from_millis(*key)).await;
+            Ok(*key)
+        }
+
+        async fn update(&self, _key: u64, _value: u64) {}
+    }
+
+    #[cfg(feature = "stats")]
+    #[tokio::test(start_paused = true)]
+    async fn fetch_latency_percentiles() {
+        let cache = Cache::new(SlowStore).await;
+        assert_eq!(Duration::ZERO, cache.stats().fetch_latency_p50);
+
+        for k in 1..=100 {
+            cache.get(k).await.unwrap();
+        }
+
+        let stats = cache.stats();
+        for (expected, actual) in [
+            (50, stats.fetch_latency_p50),
+            (95, stats.fetch_latency_p95),
+            (99, stats.fetch_latency_p99),
+        ] {
+            let expected = Duration::from_millis(expected);
+            assert!(
+                actual >= expected && actual <= expected * 9 / 8,
+                "{actual:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn scan() {
+        let cache = Cache::new(test_store()).await;
+        for k in 0..100 {
+            cache.insert(k, Arc::new(k.to_string())).await;
+        }
+
+        let mut batch_sizes = Vec::new();
+        let mut seen = Vec::new();
+        cache
+            .scan(10, |batch| {
+                // The cache isn't locked while a batch is processed.
+                assert!(cache.data.try_lock().is_ok());
+                batch_sizes.push(batch.len());
+                seen.extend(batch.iter().map(|(k, v)| {
+                    assert_eq!(k.to_string(), **v);
+                    *k
+                }));
+            })
+            .await;
+
+        assert_eq!(vec![10; 10], batch_sizes);
+        seen.sort();
+        assert_eq!((0..100).collect::<Vec<_>>(), seen);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn set_writeback_enabled() {
+        let store = test_store();
+        let mut cache = Cache::builder(store.clone())
+            .access_ttl(Duration::from_secs(5))
+            .prune_interval(Duration::from_secs(1))
+            .build()
+            .await;
+        cache.set_writeback_enabled(false);
+        assert!(!cache.is_writeback_enabled());
+
+        cache.get(1).await.unwrap();
+        cache.get(2).await.unwrap();
+        cache.get(3).await.unwrap();
+        assert!(cache.try_evict(1).await);
+        // The pruner evicts the rest.
+        sleep(Duration::from_secs(7)).await;
+        assert_eq!(0, cache.len().await);
+        // Wait for the evictor to finish with them.
+        cache.evict_all_sync().await;
+
+        assert!(store.updates().is_empty());
+
+        cache.set_writeback_enabled(true);
+        cache.insert(4, Arc::new(String::from("Four"))).await;
+        cache.evict_all_sync().await;
+        assert_eq!(vec![(4, String::from("Four"))], store.updates());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fetch_without_waiters() {
+        let store = store_with_latency();
+        let cache = Arc::new(Cache::new(store.clone()).await);
+
+        let waiters: Vec<_> = (0..2)
+            .map(|_| {
+                let cache = cache.clone();
+                tokio::spawn(async move { cache.get(1).await })
+            })
+            .collect();
+        sleep(Duration::from_millis(100)).await;
+        assert!(cache.is_fetching(&1).await);
+
+        for waiter in waiters {
+            waiter.abort();
+            assert!(waiter.await.unwrap_err().is_cancelled());
+        }
+        sleep(Duration::from_millis(100)).await;
+        assert!(!cache.is_fetching(&1).await);
+
+        // The fetch was aborted.
+        sleep(Duration::from_secs(2)).await;
+        assert!(store.fetches().is_empty());
+        assert!(cache.is_empty().await);
+
+        // A timed out get's fetch is abandoned at the same deadline.
+        assert!(matches!(
+            cache.get_with_timeout(2, Duration::from_millis(100)).await,
+            Err(CacheError::Timeout)
+        ));
+        sleep(Duration::from_secs(2)).await;
+        assert!(cache.is_empty().await);
+        assert!(!cache.is_fetching(&2).await);
+    }
+
+    #[tokio::test]
+    async fn max_weight() {
+        let store = test_store();
+        let cache = Cache::builder(store.clone())
+            .weigher(|_, v: &String| v.len())
+            .max_weight(100)
+            .build()
+            .await;
+
+        for k in 0..50 {
+            cache.insert(k, Arc::new(format!("{k:010}"))).await;
+            assert!(cache.weight().await <= 100);
+        }
+        // Fetched values count towards the weight too. Each one weighs 5, so
+        // every other fetch evicts an inserted value.
+        for k in 50..60 {
+            cache.get(k).await.unwrap();
+            assert!(cache.weight().await <= 100);
+        }
+
+        // The least recently used values were evicted and written back.
+        let updates = store.wait_for_updates(45).await;
+        let mut evicted: Vec<_> = updates.into_iter().map(|(k, _)| k).collect();
+        evicted.sort();
+        assert_eq!((0..45).collect::<Vec<_>>(), evicted);
+
+        // The total follows values that are modified in place.
+        drop(
+            cache
+                .entry(59)
+                .await
+                .and_modify(|v| Arc::make_mut(v).clear()),
+        );
+        assert_eq!(95, cache.weight().await);
+        cache.check_invariants().await;
+    }
+
+    #[tokio::test]
+    async fn max_weight_through_entry_api() {
+        let store = test_store();
+        let cache = Cache::builder(store.clone())
+            .weigher(|_, v: &String| v.len())
+            .max_weight(10)
+            .build()
+            .await;
+
+        cache.insert(1, Arc::new(String::from("aaaa"))).await;
+        cache
+            .entry(2)
+            .await
+            .or_insert(Arc::new(String::from("bbbbbbb")));
+        let updates = store.wait_for_updates(1).await;
+        assert_eq!((1, String::from("aaaa")), updates[0]);
+        assert_eq!(7, cache.weight().await);
+
+        cache.insert(3, Arc::new(String::from("c"))).await;
+        drop(
+            cache
+                .entry(3)
+                .await
+                .and_modify(|v| Arc::make_mut(v).push_str("cccc")),
+        );
+        let updates = store.wait_for_updates(2).await;
+        assert_eq!((2, String::from("bbbbbbb")), updates[1]);
+        assert_eq!(5, cache.weight().await);
+
+        cache.insert(4, Arc::new(String::from("d"))).await;
+        let swapped = cache
+            .compare_and_swap(4, &String::from("d"), Arc::new(String::from("dddddddd")))
+            .await;
+        assert!(swapped);
+        let updates = store.wait_for_updates(3).await;
+        assert_eq!((3, String::from("ccccc")), updates[2]);
+        assert_eq!(8, cache.weight().await);
+        cache.check_invariants().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn evict_all_sync_during_fetch_with_max_weight() {
+        let mut cache = Cache::builder(store_with_latency())
+            .max_weight(10)
+            .no_pruner()
+            .build()
+            .await;
+        let get = tokio::spawn({
+            let cache = cache.clone();
+            async move { cache.get(1).await }
+        });
+        tokio::task::yield_now().await;
+        assert!(cache.is_fetching(&1).await);
+
+        // The fetch doesn't hold on to the sender that the drain waits on.
+        tokio::time::timeout(Duration::from_secs(60), cache.evict_all_sync())
+            .await
+            .unwrap();
+        assert_eq!("Hello", *get.await.unwrap().unwrap());
+    }
+
+    #[tokio::test]
+    async fn on_replace() {
+        let replaced = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let store = test_store();
+        let cache = Cache::builder(store.clone())
+            .on_replace({
+                let replaced = replaced.clone();
+                move |k, v: Arc<String>| replaced.lock().unwrap().push((*k, (*v).clone()))
+            })
+            .build()
+            .await;
+
+        cache.insert(1, Arc::new(String::from("One"))).await;
+        cache.get(2).await.unwrap();
+        assert!(replaced.lock().unwrap().is_empty());
+
+        cache.insert(1, Arc::new(String::from("Uno"))).await;
+        cache.insert(2, Arc::new(String::from("Two"))).await;
+        assert_eq!(
+            vec![(1, String::from("One")), (2, String::from("Hello"))],
+            *replaced.lock().unwrap()
+        );
+        assert_eq!("Uno", *cache.get(1).await.unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn get_with_priority() {
+        let store = store_with_latency();
+        let cache = Arc::new(
+            Cache::builder(store.clone())
+                .max_concurrent_fetches(1)
+                .build()
+                .await,
+        );
+
+        let mut gets = JoinSet::new();
+        for (k, priority) in [
+            (1, Priority::Normal),
+            (2, Priority::Low),
+            (3, Priority::Low),
+            (4, Priority::Normal),
+            (5, Priority::High),
+        ] {
+            let cache = cache.clone();
+            gets.spawn(async move { cache.get_with_priority(k, priority).await });
+            // Let the get queue up before the next one.
+            sleep(Duration::from_millis(10)).await;
+        }
+        while let Some(get) = gets.join_next().await {
+            get.unwrap().unwrap();
+        }
+
+        // The first fetch took the only slot, and the rest waited for it.
+        assert_eq!(vec![1, 5, 4, 2, 3], store.fetches());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn evict_older_than() {
+        let store = test_store();
+        let cache = Cache::builder(store.clone()).no_pruner().build().await;
+        cache.insert(1, Arc::new(String::from("One"))).await;
+        cache.insert(2, Arc::new(String::from("Two"))).await;
+        sleep(Duration::from_secs(10)).await;
+        cache.insert(3, Arc::new(String::from("Three"))).await;
+        cache.touch(&2).await;
+        sleep(Duration::from_secs(5)).await;
+
+        assert_eq!(1, cache.evict_older_than(Duration::from_secs(7)).await);
+        assert_eq!(
+            vec![(1, String::from("One"))],
+            store.wait_for_updates(1).await
+        );
+        assert_eq!(2, cache.len().await);
+
+        assert_eq!(2, cache.evict_older_than(Duration::from_secs(1)).await);
+        assert!(cache.is_empty().await);
+    }
+
+    // Records the names of the threads that updates run on.
+    #[derive(Clone, Default)]
+    struct ThreadRecordingStore {
+        update_threads: Arc<std::sync::Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl Store<i32, String> for ThreadRecordingStore {
+        async fn fetch(&self, _key: &i32) -> anyhow::Result<String> {
+            Ok(String::from("Hello"))
+        }
+
+        async fn update(&self, _key: i32, _value: String) {
+            let thread = std::thread::current().name().unwrap_or_default().to_owned();
+            self.update_threads.lock().unwrap().push(thread);
+        }
+    }
+
+    #[tokio::test]
+    async fn background_handle() {
+        let background = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("background")
+            .enable_all()
+            .build()
+            .unwrap();
+        let store = ThreadRecordingStore::default();
+        let mut cache = Cache::builder(store.clone())
+            .background_handle(background.handle().clone())
+            .build()
+            .await;
+
+        cache.get(1).await.unwrap();
+        cache.evict_all_sync().await;
+        assert_eq!(vec!["background"], *store.update_threads.lock().unwrap());
+
+        drop(cache);
+        background.shutdown_background();
+    }
+
+    #[tokio::test]
+    async fn clone() {
+        let store = test_store();
+        let cache = Cache::new(store.clone()).await;
+        let mut clone = cache.clone();
+
+        cache.insert(1, Arc::new(String::from("One"))).await;
+        assert_eq!("One", *clone.get(1).await.unwrap());
+        clone.get(2).await.unwrap();
+        assert_eq!(2, cache.len().await);
+        #[cfg(feature = "stats")]
+        assert_eq!(1, cache.stats().hits);
+
+        // Evicting through one clone writes back the values for both, and
+        // dropping a clone leaves the other working.
+        clone.evict_all_sync().await;
+        assert!(cache.is_empty().await);
+        assert_eq!(2, store.updates().len());
+        drop(clone);
+        assert_eq!("One", *cache.get(1).await.unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn soft_and_hard_ttl() {
+        let store = test_store();
+        let cache = Cache::builder(store.clone())
+            .soft_ttl(Duration::from_secs(10))
+            .hard_ttl(Duration::from_secs(20))
+            .build()
+            .await;
+        store.insert(1, String::from("One"));
+        assert_eq!("One", *cache.get(1).await.unwrap());
+
+        // Fresh: the cached value is served.
+        store.insert(1, String::from("Uno"));
+        sleep(Duration::from_secs(5)).await;
+        assert_eq!("One", *cache.get(1).await.unwrap());
+        assert_eq!(1, store.fetches().len());
+
+        // Stale: the cached value is served, and refreshed in the background.
+        sleep(Duration::from_secs(10)).await;
+        assert_eq!("One", *cache.get(1).await.unwrap());
+        assert_eq!("One", *cache.get(1).await.unwrap());
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!("Uno", *cache.get(1).await.unwrap());
+        assert_eq!(2, store.fetches().len());
+
+        // Expired: the get waits for a fresh value.
+        store.insert(1, String::from("Eins"));
+        sleep(Duration::from_secs(21)).await;
+        assert_eq!("Eins", *cache.get(1).await.unwrap());
+        assert_eq!(3, store.fetches().len());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn hard_ttl_writes_back_inserted() {
+        let store = test_store();
+        let cache = Cache::builder(store.clone())
+            .hard_ttl(Duration::from_secs(20))
+            .no_pruner()
+            .build()
+            .await;
+        cache.insert(1, Arc::new(String::from("One"))).await;
+        let pinned = cache.get(1).await.unwrap();
+
+        // While it's referenced, the expired value is served rather than lost.
+        sleep(Duration::from_secs(21)).await;
+        assert_eq!("One", *cache.get(1).await.unwrap());
+        assert!(store.updates().is_empty());
+
+        drop(pinned);
+        cache.get(1).await.unwrap();
+        assert_eq!(
+            vec![(1, String::from("One"))],
+            store.wait_for_updates(1).await
+        );
+        assert_eq!(vec![1], store.fetches());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn get_many() {
+        let store = store_with_latency();
+        let cache = Arc::new(Cache::new(store.clone()).await);
+        cache.insert(1, Arc::new(String::from("One"))).await;
+        let fetching = tokio::spawn({
+            let cache = cache.clone();
+            async move { cache.get(2).await }
+        });
+        sleep(Duration::from_millis(100)).await;
+
+        let values = cache.get_many(&[1, 2, 3, 4, 3]).await.unwrap();
+        assert_eq!(
+            vec!["One", "Hello", "Hello", "Hello", "Hello"],
+            values.iter().map(|v| v.as_str()).collect::<Vec<_>>()
+        );
+        fetching.await.unwrap().unwrap();
+
+        // The cached key wasn't fetched, the fetching key was only fetched
+        // once, and the missing keys were fetched together.
+        assert_eq!(
+            vec![
+                StoreOperation::Fetch(2),
+                StoreOperation::FetchMany(vec![3, 4]),
+            ],
+            store.operations()
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn insert_during_fetch() {
+        // The fetch takes a second, so waiters that are done sooner didn't
+        // wait for it. Waiters that retry after being disconnected count hits.
+        for (policy, expected, waited_for_fetch, hits) in [
+            (InsertDuringFetch::FetchWins, "Hello", true, 0),
+            (InsertDuringFetch::InsertWins, "Inserted", false, 3),
+            (
+                InsertDuringFetch::InsertAndNotifyWaiters,
+                "Inserted",
+                false,
+                0,
+            ),
+        ] {
+            let cache = Arc::new(
+                Cache::builder(store_with_latency())
+                    .insert_during_fetch(policy)
+                    .build()
+                    .await,
+            );
+            let start = Instant::now();
+            let mut waiters = JoinSet::new();
+            for _ in 0..3 {
+                let cache = cache.clone();
+                waiters.spawn(async move { cache.get(1).await.unwrap() });
+            }
+            sleep(Duration::from_millis(100)).await;
+
+            cache.insert(1, Arc::new(String::from("Inserted"))).await;
+            while let Some(v) = waiters.join_next().await {
+                assert_eq!(expected, *v.unwrap(), "{policy:?}");
+            }
+            let waited = start.elapsed() >= Duration::from_secs(1);
+            assert_eq!(waited_for_fetch, waited, "{policy:?}");
+            if cfg!(feature = "stats") {
+                assert_eq!(hits, cache.stats().hits, "{policy:?}");
+            }
+
+            sleep(Duration::from_secs(1)).await;
+            assert_eq!(expected, *cache.get(1).await.unwrap(), "{policy:?}");
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn entry_insert_during_fetch() {
+        let cache = Arc::new(
+            Cache::builder(store_with_latency())
+                .insert_during_fetch(InsertDuringFetch::FetchWins)
+                .build()
+                .await,
+        );
+        let get = tokio::spawn({
+            let cache = cache.clone();
+            async move { cache.get(1).await.unwrap() }
+        });
+        sleep(Duration::from_millis(100)).await;
+
+        // The fetch wins, so the value is returned but not inserted.
+        let v = cache
+            .entry(1)
+            .await
+            .or_insert(Arc::new(String::from("Inserted")));
+        assert_eq!("Inserted", *v);
+        assert!(cache.is_fetching(&1).await);
+        assert_eq!("Hello", *get.await.unwrap());
+        assert_eq!("Hello", *cache.get(1).await.unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fetching_keys() {
+        let cache = Arc::new(Cache::new(store_with_latency()).await);
+        cache.insert(1, Arc::new(String::from("One"))).await;
+        let mut gets = JoinSet::new();
+        for k in [2, 3] {
+            let cache = cache.clone();
+            gets.spawn(async move { cache.get(k).await });
+        }
+        sleep(Duration::from_millis(100)).await;
+
+        let mut fetching = cache.fetching_keys().await;
+        fetching.sort();
+        assert_eq!(vec![2, 3], fetching);
+
+        while let Some(get) = gets.join_next().await {
+            get.unwrap().unwrap();
+        }
+        assert!(cache.fetching_keys().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn on_background_error() {
+        let errors = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut cache = Cache::builder(PanickingUpdateStore)
+            .on_background_error({
+                let errors = errors.clone();
+                move |err| errors.lock().unwrap().push(err)
+            })
+            .build()
+            .await;
+
+        cache.get(1).await.unwrap();
+        cache.evict_all_sync().await;
+
+        let errors = errors.lock().unwrap();
+        assert_eq!(1, errors.len());
+        assert_eq!(BackgroundPhase::Update, errors[0].phase);
+        assert!(errors[0].message.contains("Store is read-only"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn evict_all_sync_with_progress() {
+        let mut cache = Cache::new(test_store()).await;
+        let mut pinned = Vec::new();
+        for k in 0..4 {
+            let v = cache.get(k).await.unwrap();
+            if k > 0 {
+                pinned.push(v);
+            }
+        }
+
+        // Release one pinned value per pass.
+        let release = tokio::spawn(async move {
+            while !pinned.is_empty() {
+                sleep(Duration::from_millis(500)).await;
+                pinned.pop();
+                sleep(Duration::from_millis(500)).await;
+            }
+        });
+        let mut progress = Vec::new();
+        cache
+            .evict_all_sync_with_progress(|p| progress.push((p.remaining, p.evicted)))
+            .await;
+        release.await.unwrap();
+
+        assert_eq!(vec![(3, 1), (2, 1), (1, 1), (0, 1)], progress);
+    }
+
+    #[tokio::test]
+    async fn get_async_key() {
+        let store = test_store();
+        let cache = Cache::new(store.clone()).await;
+        let derivations = std::sync::atomic::AtomicUsize::new(0);
+        let make_key = || async {
+            derivations.fetch_add(1, Ordering::Relaxed);
+            tokio::task::yield_now().await;
+            1
+        };
+
+        // A miss and then a hit each derive the key once.
+        assert_eq!("Hello", *cache.get_async_key(make_key()).await.unwrap());
+        assert_eq!(1, derivations.load(Ordering::Relaxed));
+        assert_eq!("Hello", *cache.get_async_key(make_key()).await.unwrap());
+        assert_eq!(2, derivations.load(Ordering::Relaxed));
+        assert_eq!(vec![1], store.fetches());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ttl_fn() {
+        let store = test_store();
+        store.insert(1, String::from("x"));
+        store.insert(2, String::from("A much longer value"));
+        let cache = Cache::builder(store.clone())
+            .access_ttl(Duration::from_secs(100))
+            .prune_interval(Duration::from_secs(1))
+            .ttl_fn(|_, v: &String| {
+                if v.len() < 10 {
+                    Duration::from_secs(30)
+                } else {
+                    Duration::from_secs(5)
+                }
+            })
+            .build()
+            .await;
+        cache.get(1).await.unwrap();
+        cache.get(2).await.unwrap();
+        cache.insert(3, Arc::new(String::from("y"))).await;
+
+        // The long value expires first.
+        sleep(Duration::from_secs(10)).await;
+        assert_eq!(
+            vec![(2, String::from("A much longer value"))],
+            store.wait_for_updates(1).await
+        );
+        assert_eq!(2, cache.len().await);
+
+        sleep(Duration::from_secs(25)).await;
+        assert!(cache.is_empty().await);
+    }
+
+    #[tokio::test]
+    async fn rename() {
+        let store = HashMapStore::new();
+        let cache = Cache::new(store.clone()).await;
+        cache.insert(1, Arc::new(String::from("One"))).await;
+        cache.insert(3, Arc::new(String::from("Three"))).await;
+
+        assert!(cache.rename(1, 2).await);
+        assert_eq!("One", *cache.get(2).await.unwrap());
+        assert!(cache.get(1).await.is_err());
+        assert_eq!(vec![1], store.fetches());
+
+        // Neither a missing key nor an existing value can be renamed over.
+        assert!(!cache.rename(4, 5).await);
+        assert!(!cache.rename(2, 3).await);
+        assert_eq!("Three", *cache.get(3).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn compare_and_swap() {
+        let store = HashMapStore::new();
+        let mut cache = Cache::new(store.clone()).await;
+        cache.insert(1, Arc::new(String::from("One"))).await;
+
+        assert!(
+            !cache
+                .compare_and_swap(1, &String::from("Two"), Arc::new(String::from("Three")))
+                .await
+        );
+        assert_eq!("One", *cache.get(1).await.unwrap());
+
+        assert!(
+            cache
+                .compare_and_swap(1, &String::from("One"), Arc::new(String::from("Two")))
+                .await
+        );
+        assert_eq!("Two", *cache.get(1).await.unwrap());
+
+        // A key without a value is never swapped.
+        assert!(
+            !cache
+                .compare_and_swap(2, &String::from("Two"), Arc::new(String::from("Two")))
+                .await
+        );
+
+        cache.evict_all_sync().await;
+        assert_eq!(vec![(1, String::from("Two"))], store.updates());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn warm_up_with() {
+        let store = store_with_latency();
+        let cache = Cache::builder(store.clone()).no_pruner().build().await;
+
+        // Samples the number of fetches in progress while warming up.
+        let peak = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let sampler = tokio::spawn({
+            let cache = cache.clone();
+            let peak = peak.clone();
+            async move {
+                loop {
+                    let in_flight = cache.fetching_keys().await.len();
+                    peak.fetch_max(in_flight, Ordering::Relaxed);
+                    sleep(Duration::from_millis(100)).await;
+                }
+            }
+        });
+
+        let mut reported = Vec::new();
+        let progress = cache
+            .warm_up_with(
+                0..20,
+                4,
+                |progress| reported.push(progress.fetched),
+                std::future::pending(),
+            )
+            .await;
+        sampler.abort();
+        assert_eq!(
+            WarmUpProgress {
+                fetched: 20,
+                failed: 0,
+                total: 20,
+            },
+            progress
+        );
+        assert_eq!((1..=20).collect::<Vec<_>>(), reported);
+        assert_eq!(4, peak.load(Ordering::Relaxed));
+        assert_eq!(20, cache.len().await);
+
+        // Cancelling stops the warm-up partway through.
+        let progress = cache
+            .warm_up_with(20..40, 4, |_| {}, sleep(Duration::from_millis(1500)))
+            .await;
+        assert_eq!(4, progress.fetched);
+        // The aborted gets' fetches are abandoned by their own tasks.
+        sleep(Duration::from_millis(10)).await;
+        assert!(cache.fetching_keys().await.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn inserted_values_written_back_first() {
+        let store = test_store();
+        let cache = Cache::builder(store.clone())
+            .access_ttl(Duration::from_secs(1))
+            .prune_interval(Duration::from_secs(10))
+            .build()
+            .await;
+        cache.get(1).await.unwrap();
+        sleep(Duration::from_millis(1)).await;
+        cache.insert(2, Arc::new(String::from("Two"))).await;
+        sleep(Duration::from_millis(1)).await;
+        cache.get(3).await.unwrap();
+        assert_eq!(Some(ValueSource::Fetched), cache.value_source(&1).await);
+        assert_eq!(Some(ValueSource::Inserted), cache.value_source(&2).await);
+        assert_eq!(None, cache.value_source(&4).await);
+
+        // The values expire in the same sweep, least recently used first, but
+        // the inserted one is written back first.
+        assert_eq!(
+            vec![
+                (2, String::from("Two")),
+                (1, String::from("Hello")),
+                (3, String::from("Hello")),
+            ],
+            store.wait_for_updates(3).await
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn get_or_fetch_with() {
+        let store = test_store();
+        let cache = Cache::new(store.clone()).await;
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+
+        let mut gets = JoinSet::new();
+        for _ in 0..5 {
+            let cache = cache.clone();
+            let calls = calls.clone();
+            gets.spawn(async move {
+                cache
+                    .get_or_fetch_with(1, move || async move {
+                        calls.fetch_add(1, Ordering::Relaxed);
+                        sleep(Duration::from_secs(1)).await;
+                        Ok(String::from("Override"))
+                    })
+                    .await
+            });
+        }
+        while let Some(get) = gets.join_next().await {
+            assert_eq!("Override", *get.unwrap().unwrap());
+        }
+
+        assert_eq!(1, calls.load(Ordering::Relaxed));
+        assert!(store.fetches().is_empty());
+        assert_eq!("Override", *cache.get(1).await.unwrap());
+    }
+
+    // Fails the first `failures` calls to `try_update`.
+    struct FlakyStore {
+        store: HashMapStore<i32, String>,
+        failures: std::sync::atomic::AtomicUsize,
+    }
+
+    #[async_trait]
+    impl Store<i32, String> for FlakyStore {
+        async fn fetch(&self, key: &i32) -> anyhow::Result<String> {
+            self.store.fetch(key).await
+        }
+
+        async fn update(&self, key: i32, value: String) {
+            self.store.update(key, value).await
+        }
+
+        async fn try_update(&self, key: i32, value: String) -> Result<(), (String, anyhow::Error)> {
+            let failures = &self.failures;
+            if failures
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+                .is_ok()
+            {
+                return Err((value, anyhow::anyhow!("Store unavailable")));
+            }
+            self.update(key, value).await;
+            Ok(())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn update_retries_at_shutdown() {
+        let store = test_store();
+        let errors = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let flaky_store = FlakyStore {
+            store: store.clone(),
+            failures: std::sync::atomic::AtomicUsize::new(usize::MAX),
+        };
+        let mut cache = Cache::builder(flaky_store)
+            .no_pruner()
+            .update_retries(3, Duration::MAX)
+            .on_background_error({
+                let errors = errors.clone();
+                move |err| errors.lock().unwrap().push(err.message)
+            })
+            .build()
+            .await;
+        cache.insert(1, Arc::new(String::from("One"))).await;
+
+        // The backoff saturates instead of overflowing.
+        for _ in 0..2 {
+            cache.evict_all_sync().await;
+            sleep(Duration::from_millis(10)).await;
+            assert_eq!(1, cache.len().await);
+        }
+
+        // Shutdown can't put the value back again, so it reports the failure.
+        cache.insert(2, Arc::new(String::from("Two"))).await;
+        let err = cache.shutdown().await.unwrap_err();
+        assert!(matches!(err, CacheError::UpdateFailed(_)));
+        assert!(store.updates().is_empty());
+        let errors = errors.lock().unwrap();
+        assert_eq!(2, errors.len());
+        assert!(errors.iter().all(|message| message.contains("failed")));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn update_retries() {
+        let store = test_store();
+        let flaky_store = FlakyStore {
+            store: store.clone(),
+            failures: std::sync::atomic::AtomicUsize::new(2),
+        };
+        let cache = Cache::builder(flaky_store)
+            .access_ttl(Duration::from_secs(1))
+            .prune_interval(Duration::from_secs(1))
+            .update_retries(3, Duration::from_secs(1))
+            .build()
+            .await;
+        cache.insert(1, Arc::new(String::from("One"))).await;
+
+        // The first writeback fails at 1s and is retried at 2s, which fails
+        // too and is retried at 4s.
+        sleep(Duration::from_millis(1500)).await;
+        assert_eq!(1, cache.len().await);
+        sleep(Duration::from_secs(2)).await;
+        assert_eq!(1, cache.len().await);
+        assert!(store.updates().is_empty());
+
+        sleep(Duration::from_secs(1)).await;
+        assert!(cache.is_empty().await);
+        assert_eq!(vec![(1, String::from("One"))], store.updates());
+    }
+
+    // Records the keys of the values it finalizes, after a delay.
+    struct FinalizingStore {
+        store: HashMapStore<i32, String>,
+        finalized: Arc<std::sync::Mutex<Vec<i32>>>,
+    }
+
+    #[async_trait]
+    impl Store<i32, String> for FinalizingStore {
+        async fn fetch(&self, key: &i32) -> anyhow::Result<String> {
+            self.store.fetch(key).await
+        }
+
+        async fn update(&self, key: i32, value: String) {
+            // The value is finalized before it's written back.
+            assert!(self.finalized.lock().unwrap().contains(&key));
+            self.store.update(key, value).await
+        }
+
+        async fn finalize(&self, key: &i32, _value: &String) {
+            sleep(Duration::from_millis(100)).await;
+            self.finalized.lock().unwrap().push(*key);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn finalize() {
+        let store = test_store();
+        let finalized = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut cache = Cache::builder(FinalizingStore {
+            store: store.clone(),
+            finalized: finalized.clone(),
+        })
+        .no_pruner()
+        .build()
+        .await;
+        cache.insert(1, Arc::new(String::from("One"))).await;
+        cache.insert(2, Arc::new(String::from("Two"))).await;
+        cache.insert(3, Arc::new(String::from("Three"))).await;
+
+        cache.try_evict(1).await;
+        sleep(Duration::from_secs(1)).await;
+        assert_eq!(vec![1], *finalized.lock().unwrap());
+
+        cache.evict_all_sync().await;
+        let mut finalized = finalized.lock().unwrap().clone();
+        finalized.sort();
+        assert_eq!(vec![1, 2, 3], finalized);
+        assert_eq!(3, store.updates().len());
+    }
+
+    #[tokio::test]
+    async fn headroom() {
+        let unbounded = Cache::builder(test_store()).no_pruner().build().await;
+        unbounded.insert(1, Arc::new(String::from("One"))).await;
+        assert_eq!(None, unbounded.headroom().await);
+
+        let cache = Cache::builder(test_store())
+            .max_capacity(3)
+            .no_pruner()
+            .build()
+            .await;
+        assert_eq!(Some(3), cache.headroom().await);
+        cache.insert(1, Arc::new(String::from("One"))).await;
+        assert_eq!(Some(2), cache.headroom().await);
+        cache.insert(2, Arc::new(String::from("Two"))).await;
+        cache.insert(3, Arc::new(String::from("Three"))).await;
+        cache.insert(4, Arc::new(String::from("Four"))).await;
+        assert_eq!(Some(0), cache.headroom().await);
+
+        let weighted = Cache::builder(test_store())
+            .weigher(|_, v: &String| v.len())
+            .max_weight(10)
+            .no_pruner()
+            .build()
+            .await;
+        weighted.insert(1, Arc::new(String::from("One"))).await;
+        assert_eq!(Some(7), weighted.headroom().await);
+    }
+
+    #[tokio::test]
+    async fn take_all() {
+        let store = test_store();
+        let mut cache = Cache::new(store.clone()).await;
+        for k in 1..=3 {
+            cache.insert(k, Arc::new(k.to_string())).await;
+        }
+
+        let mut values = cache.take_all().await;
+        values.sort_by_key(|(k, _)| *k);
+        let expected: Vec<_> = (1..=3).map(|k| (k, Arc::new(k.to_string()))).collect();
+        assert_eq!(expected, values);
+        assert!(cache.is_empty().await);
+
+        cache.evict_all_sync().await;
+        assert!(store.updates().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn get_waiting_on_shutdown() {
+        let store = test_store();
+        let cache = Cache::builder(store.clone()).no_pruner().build().await;
+        let pinned = cache.get(1).await.unwrap();
+
+        // The pinned value keeps the drain going, with the cache locked, until
+        // it's dropped. The get and the shutdown both wait for the lock.
+        let drain = tokio::spawn({
+            let mut cache = cache.clone();
+            async move { cache.evict_all_sync().await }
+        });
+        sleep(Duration::from_millis(10)).await;
+        let get = tokio::spawn({
+            let cache = cache.clone();
+            async move { cache.get(2).await }
+        });
+        let shutdown = tokio::spawn({
+            let mut cache = cache.clone();
+            async move { cache.shutdown().await }
+        });
+        sleep(Duration::from_millis(10)).await;
+        drop(pinned);
+
+        drain.await.unwrap();
+        assert!(matches!(get.await.unwrap(), Err(CacheError::ShuttingDown)));
+        shutdown.await.unwrap().unwrap();
+        assert_eq!(vec![1], store.fetches());
+        assert!(!cache.is_fetching(&2).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn get_with_timeout() {
+        // Fetches take 1s, longer than the cache's own deadline.
+        let store = store_with_latency();
+        let cache = Cache::builder(store.clone())
+            .fetch_deadline(Duration::from_millis(500))
+            .build()
+            .await;
+        assert!(matches!(cache.get(1).await, Err(CacheError::Timeout)));
+
+        // A longer timeout lets the fetch it starts finish, while a get that
+        // joins that fetch with a shorter timeout gives up on its own.
+        let joined = tokio::spawn({
+            let cache = cache.clone();
+            async move {
+                sleep(Duration::from_millis(10)).await;
+                cache.get_with_timeout(1, Duration::from_millis(200)).await
+            }
+        });
+        assert_eq!(
+            "Hello",
+            *cache
+                .get_with_timeout(1, Duration::from_secs(2))
+                .await
+                .unwrap()
+        );
+        assert!(matches!(joined.await.unwrap(), Err(CacheError::Timeout)));
+        assert_eq!(vec![1], store.fetches());
+    }
+
+    #[tokio::test]
+    async fn skip_clean_writebacks() {
+        let store = test_store();
+        let finalized = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut cache = Cache::builder(FinalizingStore {
+            store: store.clone(),
+            finalized: finalized.clone(),
+        })
+        .skip_clean_writebacks()
+        .build()
+        .await;
+        cache.get(1).await.unwrap();
+        cache.insert(2, Arc::new(String::from("Two"))).await;
+        cache.get(3).await.unwrap();
+        cache
+            .entry(3)
+            .await
+            .and_modify(|v| Arc::make_mut(v).push('!'));
+
+        cache.evict_all_sync().await;
+        let mut updates = store.updates();
+        updates.sort();
+        assert_eq!(
+            vec![(2, String::from("Two")), (3, String::from("Hello!"))],
+            updates
+        );
+        // The clean value is still finalized.
+        let mut finalized = finalized.lock().unwrap().clone();
+        finalized.sort();
+        assert_eq!(vec![1, 2, 3], finalized);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fetch_debounce() {
+        let store = test_store();
+        let cache = Cache::builder(store.clone())
+            .fetch_debounce(Duration::from_millis(50))
+            .build()
+            .await;
+
+        let mut gets = JoinSet::new();
+        for k in (0..10).chain([3]) {
+            let cache = cache.clone();
+            gets.spawn(async move { cache.get(k).await });
+            sleep(Duration::from_millis(2)).await;
+        }
+        while let Some(get) = gets.join_next().await {
+            assert_eq!("Hello", *get.unwrap().unwrap());
+        }
+
+        let operations = store.operations();
+        let [StoreOperation::FetchMany(keys)] = &operations[..] else {
+            panic!("Expected a single fetch_many, got {:?}", operations);
+        };
+        assert_eq!((0..10).collect::<Vec<_>>(), *keys);
+        #[cfg(feature = "stats")]
+        assert_eq!(11, cache.stats().misses);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn writeback_backlog() {
+        let store = store_with_latency();
+        let cache = Cache::builder(store.clone()).no_pruner().build().await;
+        for k in 1..=3 {
+            cache.insert(k, Arc::new(k.to_string())).await;
+        }
+        assert_eq!(0, cache.writeback_backlog());
+
+        // Each update takes 1s, and they're written back one at a time.
+        for k in 1..=3 {
+            cache.try_evict(k).await;
+        }
+        assert_eq!(3, cache.writeback_backlog());
+        sleep(Duration::from_millis(1500)).await;
+        assert_eq!(2, cache.writeback_backlog());
+        store.wait_for_updates(3).await;
+        sleep(Duration::from_millis(10)).await;
+        assert_eq!(0, cache.writeback_backlog());
+    }
+
+    #[cfg(debug_assertions)]
+    #[tokio::test(start_paused = true)]
+    async fn invariants_under_random_operations() {
+        let store = store_with_latency();
+        let cache = Cache::builder(store.clone())
+            .access_ttl(Duration::from_secs(5))
+            .prune_interval(Duration::from_secs(1))
+            .max_capacity(8)
+            .build()
+            .await;
+
+        // A xorshift generator, so that the operations are the same every run.
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        let mut gets = JoinSet::new();
+        for _ in 0..500 {
+            let k = (next() % 16) as i32;
+            match next() % 8 {
+                0 | 1 => {
+                    let cache = cache.clone();
+                    gets.spawn(async move { cache.get(k).await });
+                }
+                2 => {
+                    let cache = cache.clone();
+                    gets.spawn(async move {
+                        cache.get_with_timeout(k, Duration::from_millis(500)).await
+                    });
+                }
+                3 => cache.insert(k, Arc::new(k.to_string())).await,
+                4 => {
+                    cache.try_evict(k).await;
+                }
+                5 => cache.remove(k).await,
+                6 => {
+                    cache.evict_fraction(0.5).await;
+                }
+                _ => gets.abort_all(),
+            }
+            sleep(Duration::from_millis(next() % 300)).await;
+            cache.check_invariants().await;
+        }
+        while gets.join_next().await.is_some() {}
+        cache.check_invariants().await;
+    }
+
+    #[tokio::test]
+    async fn skip_unchanged_writebacks() {
+        let store = test_store();
+        let mut cache = Cache::builder(store.clone())
+            .skip_unchanged_writebacks()
+            .no_pruner()
+            .build()
+            .await;
+        cache.get(1).await.unwrap();
+        cache.evict_all_sync().await;
+        assert_eq!(1, store.updates().len());
+
+        // The store returns the same value again, so writing it back would
+        // change nothing.
+        cache.get(1).await.unwrap();
+        cache.evict_all_sync().await;
+        assert_eq!(1, store.updates().len());
+
+        cache.insert(1, Arc::new(String::from("Changed"))).await;
+        cache.evict_all_sync().await;
+        assert_eq!(2, store.updates().len());
+
+        // A removed key is forgotten, since the store may have changed.
+        cache.remove(1).await;
+        cache.insert(1, Arc::new(String::from("Changed"))).await;
+        cache.evict_all_sync().await;
+        assert_eq!(3, store.updates().len());
+    }
 
-        let (new_evict_tx, new_evict_rx) = mpsc::unbounded_channel();
-        let new_pruner_join_handle =
-            Self::pruner_join_handle(data_clone, new_evict_tx.clone(), self.access_ttl);
+    // Panics on the first update.
+    struct PanicOnceUpdateStore {
+        store: HashMapStore<i32, String>,
+        panicked: std::sync::atomic::AtomicBool,
+    }
 
-        drop(std::mem::replace(&mut self.evict_tx, new_evict_tx));
+    #[async_trait]
+    impl Store<i32, String> for PanicOnceUpdateStore {
+        async fn fetch(&self, key: &i32) -> anyhow::Result<String> {
+            self.store.fetch(key).await
+        }
 
-        let new_evictor_join_handle = Self::evictor_join_handle(new_evict_rx, self.store.clone());
+        async fn update(&self, key: i32, value: String) {
+            if !self.panicked.swap(true, Ordering::SeqCst) {
+                panic!("Store is broken");
+            }
+            self.store.update(key, value).await
+        }
+    }
 
-        // Abort the old pruner so its evict_tx is dropped,
-        // allowing the old evictor to complete.
-        self.pruner_join_handle.abort();
-        self.pruner_join_handle = new_pruner_join_handle;
+    #[tokio::test]
+    async fn skip_unchanged_writebacks_after_panic() {
+        let store = test_store();
+        let mut cache = Cache::builder(PanicOnceUpdateStore {
+            store: store.clone(),
+            panicked: Default::default(),
+        })
+        .skip_unchanged_writebacks()
+        .no_pruner()
+        .build()
+        .await;
+        cache.insert(1, Arc::new(String::from("One"))).await;
+        cache.evict_all_sync().await;
+        assert!(store.updates().is_empty());
 
-        // Replace the evictor and wait for the old evictor to evict everything.
-        std::mem::replace(&mut self.evictor_join_handle, new_evictor_join_handle)
-            .await
-            .unwrap();
+        // The value was never written, so it isn't skipped.
+        cache.insert(1, Arc::new(String::from("One"))).await;
+        cache.evict_all_sync().await;
+        assert_eq!(vec![(1, String::from("One"))], store.updates());
     }
 
-    pub fn evictor_join_handle(
-        mut rx: mpsc::UnboundedReceiver<(K, V)>,
-        store: Arc<dyn Store<K, V> + Send + Sync>,
-    ) -> tokio::task::JoinHandle<()> {
-        tokio::spawn(async move {
-            while let Some((k, v)) = rx.recv().await {
-                store.update(k, v).await;
-            }
+    // A stream of the keys sent on a channel.
+    struct ChannelStream(tokio::sync::mpsc::UnboundedReceiver<i32>);
+
+    impl Stream for ChannelStream {
+        type Item = i32;
+
+        fn poll_next(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Option<i32>> {
+            self.0.poll_recv(cx)
+        }
+    }
+
+    #[tokio::test]
+    async fn attach_invalidation_stream() {
+        let cache = Cache::builder(test_store()).no_pruner().build().await;
+        cache.insert(1, Arc::new(String::from("One"))).await;
+        cache.insert(2, Arc::new(String::from("Two"))).await;
+        cache.insert(3, Arc::new(String::from("Three"))).await;
+
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        cache.attach_invalidation_stream(ChannelStream(rx));
+        tx.send(1).unwrap();
+        tx.send(3).unwrap();
+        sleep(Duration::from_millis(10)).await;
+
+        assert_eq!(None, cache.value_source(&1).await);
+        assert!(cache.value_source(&2).await.is_some());
+        assert_eq!(None, cache.value_source(&3).await);
+
+        // The stream is dropped with the cache.
+        drop(cache);
+        sleep(Duration::from_millis(10)).await;
+        assert!(tx.is_closed());
+    }
+
+    // Returns an error for odd keys, as a value, and records its fetches.
+    struct ResultStore {
+        fetches: Arc<std::sync::Mutex<Vec<i32>>>,
+    }
+
+    #[async_trait]
+    impl Store<i32, Result<String, String>> for ResultStore {
+        async fn fetch(&self, key: &i32) -> anyhow::Result<Result<String, String>> {
+            self.fetches.lock().unwrap().push(*key);
+            Ok(if key % 2 == 0 {
+                Ok(key.to_string())
+            } else {
+                Err(String::from("Not found"))
+            })
+        }
+
+        async fn update(&self, _key: i32, _value: Result<String, String>) {}
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn negative_ttl() {
+        let fetches = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let cache = Cache::builder(ResultStore {
+            fetches: fetches.clone(),
         })
+        .negative_ttl(Duration::from_secs(1))
+        .build()
+        .await;
+
+        assert!(cache.get(1).await.unwrap().is_err());
+        assert!(cache.get(2).await.unwrap().is_ok());
+        cache.get(1).await.unwrap();
+        assert_eq!(vec![1, 2], *fetches.lock().unwrap());
+
+        // The error is fetched again once the negative TTL has passed, but the
+        // value is still cached.
+        sleep(Duration::from_secs(1)).await;
+        assert!(cache.get(1).await.unwrap().is_err());
+        assert!(cache.get(2).await.unwrap().is_ok());
+        assert_eq!(vec![1, 2, 1], *fetches.lock().unwrap());
     }
 
-    fn pruner_join_handle(
-        data: Arc<Mutex<HashMap<K, CacheEntry<V>>>>,
-        tx: mpsc::UnboundedSender<(K, V)>,
-        access_ttl: Duration,
-    ) -> tokio::task::JoinHandle<()> {
-        tokio::spawn(async move {
-            loop {
-                // iterate over all entries. if CacheEntry::Value
-                // and arc count is 1 (and idle for long time) then
-                // evict
-                let mut data = data.lock().await;
-                let keys: Vec<_> = data.keys().copied().collect();
-                let now = Instant::now();
-                for key in keys {
-                    let entry = data.entry(key);
-                    if let hash_map::Entry::Occupied(mut e) = entry {
-                        if let CacheEntry::Node(ref mut node) = e.get_mut() {
-                            if now.duration_since(node.unwrap().last_access_ts) < access_ttl {
-                                continue;
-                            }
-                            match mem::replace(node, CacheNode::Dummy) {
-                                CacheNode::Real(real_node) => {
-                                    match RealCacheNode::try_unwrap(real_node) {
-                                        Ok(v) => {
-                                            e.remove();
-                                            tx.send((key, v)).unwrap()
-                                        }
-                                        Err(real_node) => {
-                                            *node = CacheNode::Real(real_node);
-                                        }
-                                    }
-                                }
-                                CacheNode::Dummy => (),
-                            }
-                        }
-                    }
-                }
-                drop(data);
-                sleep(Duration::from_secs(10)).await;
+    #[tokio::test]
+    async fn run_prune_once() {
+        let store = test_store();
+        let cache = Cache::builder(store.clone())
+            .clock(crate::clock::TickClock::new(Duration::from_secs(1)))
+            .access_ttl(Duration::from_secs(5))
+            .no_pruner()
+            .build()
+            .await;
+        cache.insert(1, Arc::new(String::from("One"))).await;
+        cache.insert(2, Arc::new(String::from("Two"))).await;
+
+        cache.advance_ticks(3);
+        cache.touch(&2).await;
+        cache.advance_ticks(2);
+        assert_eq!(2, cache.len().await);
+
+        cache.run_prune_once().await;
+        assert_eq!(1, cache.len().await);
+        assert_eq!(
+            vec![(1, String::from("One"))],
+            store.wait_for_updates(1).await
+        );
+
+        cache.advance_ticks(3);
+        cache.run_prune_once().await;
+        assert!(cache.is_empty().await);
+    }
+
+    // Evicts values in the order they were first inserted.
+    struct QueuePolicy(std::sync::Mutex<std::collections::VecDeque<i32>>);
+
+    impl EvictionPolicy<i32> for QueuePolicy {
+        fn on_insert(&self, k: &i32) {
+            let mut queue = self.0.lock().unwrap();
+            if !queue.contains(k) {
+                queue.push_back(*k);
             }
-        })
+        }
+
+        fn on_remove(&self, k: &i32) {
+            self.0.lock().unwrap().retain(|queued| queued != k);
+        }
+
+        fn select_victim(&self, candidates: &[i32]) -> Option<i32> {
+            let queue = self.0.lock().unwrap();
+            queue.iter().find(|k| candidates.contains(k)).copied()
+        }
     }
 
-    fn web_join_handle(
-        data: Arc<Mutex<HashMap<K, CacheEntry<V>>>>,
-        access_ttl: Duration,
-    ) -> tokio::task::JoinHandle<io::Result<()>> {
-        tokio::spawn(async move {
-            let mut app = tide::with_state(data);
-            app.at("/").get(
-                move |req: tide::Request<Arc<Mutex<HashMap<K, CacheEntry<V>>>>>| async move {
-                    let mut table = String::from("<table>");
-                    table.push_str(
-                        "
-                        <tr>
-                          <th>Key</th>
-                          <th>Time since last access</th>
-                          <th>Time in cache</th>
-                          <th>Strong count</th>
-                        </tr>",
-                    );
-                    let data = req.state().lock().await;
-                    let now = Instant::now();
-                    for (k, entry) in &*data {
-                        table.push_str("<tr>");
-                        table += &*format!("<td>{}</td>", k);
-                        table += "<td>";
-                        table += &match entry {
-                            CacheEntry::Fetching(_) => String::from("Fetching..."),
-                            CacheEntry::Node(node) => match node {
-                                CacheNode::Real(real_node) => {
-                                    now.duration_since(real_node.last_access_ts)
-                                        .as_secs()
-                                        .to_string()
-                                        + " secs."
-                                }
-                                CacheNode::Dummy => String::from("<Dummy>"),
-                            },
-                            CacheEntry::FetchFailed(_) => String::from("<Fetch error>"),
-                        };
-                        table += "</td>";
-                        if let CacheEntry::Node(CacheNode::Real(real_node)) = entry {
-                            let time_in_cache =
-                                now.duration_since(real_node.first_access_ts).as_secs();
-                            table += &format!("<td>{time_in_cache} secs.</td>");
-                        } else {
-                            table += "<td></td>";
-                        }
-                        if let CacheEntry::Node(CacheNode::Real(real_node)) = entry {
-                            let strong_count = Arc::strong_count(&real_node.value);
-                            table += &format!("<td>{strong_count}</td>");
-                        } else {
-                            table += "<td></td>";
-                        }
-                        table.push_str("</tr>");
-                    }
-                    table.push_str("</table>");
+    #[tokio::test(start_paused = true)]
+    async fn eviction_policy() {
+        let store = test_store();
+        let cache = Cache::builder(store.clone())
+            .max_capacity(2)
+            .eviction_policy(QueuePolicy(Default::default()))
+            .no_pruner()
+            .build()
+            .await;
+        for k in 1..=3 {
+            cache.insert(k, Arc::new(k.to_string())).await;
+        }
+        // Without the policy, these would make 2 and then 3 the least
+        // recently used.
+        sleep(Duration::from_secs(1)).await;
+        cache.touch(&1).await;
 
-                    let response = format!(
-                        "
-                        <html>
-                          <head>
-                            <style>
-                              table {{
-                                width: 100%;
-                                border-collapse: collapse;
-                              }}
-                              td, th {{
-                                border: 1px solid black;
-                                text-align: left;
-                                padding: 8px;
-                              }}
-                            </style>
-                          </head>
-                          <body>
-                            <p>Access TTL: {} secs.</p>
-                            {table}
-                          </body>
-                        </html>
-                    ",
-                        access_ttl.as_secs()
-                    );
+        cache.run_prune_once().await;
+        assert_eq!(
+            vec![(1, String::from("1"))],
+            store.wait_for_updates(1).await
+        );
+        sleep(Duration::from_secs(1)).await;
+        cache.touch(&2).await;
+        assert_eq!(Some(2), cache.evict_one().await);
 
-                    Ok(tide::Response::builder(200)
-                        .body(response)
-                        .content_type(tide::http::mime::HTML)
-                        .build())
-                },
-            );
-            app.listen("127.0.0.1:8030").await
-        })
+        // A removed value leaves the queue, and comes back at the end.
+        cache.insert(1, Arc::new(String::from("1"))).await;
+        cache.remove(3).await;
+        cache.insert(3, Arc::new(String::from("3"))).await;
+        assert_eq!(Some(1), cache.evict_one().await);
+        assert_eq!(Some(3), cache.evict_one().await);
     }
-}
 
-impl<K, V> Drop for Cache<K, V> {
-    fn drop(&mut self) {
-        self.evictor_join_handle.abort();
-        self.pruner_join_handle.abort();
-        // TODO: Use axum which supports graceful shutdown.
-        self.web_join_handle.abort();
+    #[tokio::test(start_paused = true)]
+    async fn quiesce() {
+        let store = test_store()
+            .with_fetch_latency(Duration::from_secs(2))
+            .with_update_latency(Duration::from_secs(3));
+        let cache = Cache::builder(store.clone()).no_pruner().build().await;
+        cache.quiesce().await;
+
+        let mut gets = JoinSet::new();
+        for k in 1..=3 {
+            let cache = cache.clone();
+            gets.spawn(async move { cache.get(k).await });
+        }
+        for k in 4..=5 {
+            cache.insert(k, Arc::new(k.to_string())).await;
+            assert!(cache.try_evict(k).await);
+        }
+        tokio::task::yield_now().await;
+
+        let start = tokio::time::Instant::now();
+        cache.quiesce().await;
+        assert!(start.elapsed() >= Duration::from_secs(3));
+        assert!(cache.fetching_keys().await.is_empty());
+        assert_eq!(0, cache.writeback_backlog());
+        let mut fetches = store.fetches();
+        fetches.sort();
+        assert_eq!(vec![1, 2, 3], fetches);
+        let mut updates = store.updates();
+        updates.sort();
+        assert_eq!(
+            vec![(4, String::from("4")), (5, String::from("5"))],
+            updates
+        );
+        while let Some(get) = gets.join_next().await {
+            assert_eq!("Hello", *get.unwrap().unwrap());
+        }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[tokio::test]
+    async fn should_admit() {
+        let store = test_store();
+        store.insert(1, String::from("aaaa"));
+        store.insert(2, String::from("bbbb"));
+        store.insert(3, String::from("cccccccc"));
+        store.insert(4, String::from("d"));
+        let consulted = Arc::new(std::sync::Mutex::new(vec![]));
+        let cache = Cache::builder(store)
+            .weigher(|_, v: &String| v.len())
+            .max_weight(10)
+            .should_admit({
+                let consulted = consulted.clone();
+                move |k, v: &String, context| {
+                    consulted.lock().unwrap().push((*k, context));
+                    v.len() <= 4
+                }
+            })
+            .no_pruner()
+            .build()
+            .await;
+        cache.get(1).await.unwrap();
+        cache.get(2).await.unwrap();
 
-    use tokio::sync::mpsc;
-    use tokio::task::JoinSet;
-    use tokio::time::{sleep, Duration};
+        // The big value is served, but the values it would push out stay.
+        assert_eq!("cccccccc", *cache.get(3).await.unwrap());
+        assert_eq!(None, cache.value_source(&3).await);
+        assert_eq!(2, cache.len().await);
+        let context = AdmissionContext {
+            len: 2,
+            weight: 8,
+            victim: Some(1),
+        };
+        assert_eq!(vec![(3, context)], *consulted.lock().unwrap());
+
+        // A value that fits isn't checked.
+        cache.get(4).await.unwrap();
+        assert_eq!(3, cache.len().await);
+        assert_eq!(1, consulted.lock().unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn shutdown_on() {
+        let store = test_store();
+        let cache = Cache::new(store.clone()).await;
+        cache.insert(1, Arc::new(String::from("One"))).await;
+        let (signal_tx, signal_rx) = tokio::sync::oneshot::channel::<()>();
+        let shutdown = cache.clone().shutdown_on(async move {
+            let _ = signal_rx.await;
+        });
+
+        sleep(Duration::from_millis(100)).await;
+        assert!(!cache.is_shut_down());
+        assert!(store.updates().is_empty());
+
+        signal_tx.send(()).unwrap();
+        shutdown.await.unwrap().unwrap();
+        assert!(cache.is_shut_down());
+        assert_eq!(vec![(1, String::from("One"))], store.updates());
+    }
+
+    #[cfg(all(feature = "signal", unix))]
+    #[tokio::test]
+    async fn on_shutdown_signal() {
+        let store = test_store();
+        let cache = Cache::new(store.clone()).await;
+        cache.insert(1, Arc::new(String::from("One"))).await;
+        let shutdown = cache.clone().on_shutdown_signal().unwrap();
+
+        // The handler is installed by now, so SIGTERM doesn't kill the test.
+        let status = std::process::Command::new("kill")
+            .args(["-TERM", &std::process::id().to_string()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+        shutdown.await.unwrap().unwrap();
+        assert!(cache.is_shut_down());
+        assert_eq!(vec![(1, String::from("One"))], store.updates());
+    }
 
-    #[derive(Debug, PartialEq, Eq)]
-    enum StoreOperation {
-        Fetch(i32),
-        Update((i32, String)),
+    #[tokio::test(start_paused = true)]
+    async fn get_fresh_only() {
+        let store = test_store();
+        let cache = Cache::builder(store.clone())
+            .access_ttl(Duration::from_secs(5))
+            .no_pruner()
+            .build()
+            .await;
+        assert_eq!(None, cache.get_fresh_only(1).await);
+        cache.insert(1, Arc::new(String::from("One"))).await;
+
+        sleep(Duration::from_secs(4)).await;
+        assert_eq!("One", *cache.get_fresh_only(1).await.unwrap());
+
+        // The value is still in the cache, since the pruner didn't run, but
+        // it's past its TTL.
+        sleep(Duration::from_secs(5)).await;
+        assert_eq!(None, cache.get_fresh_only(1).await);
+        assert_eq!(Some(ValueSource::Inserted), cache.value_source(&1).await);
+        assert!(store.fetches().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn on_fetch_complete() {
+        let store = HashMapStore::new().with_fetch_latency(Duration::from_secs(1));
+        store.insert(1, String::from("One"));
+        let latencies = Arc::new(std::sync::Mutex::new(HashMap::<_, Vec<_>>::new()));
+        let cache = Cache::builder(store)
+            .on_fetch_complete({
+                let latencies = latencies.clone();
+                move |k, latency, succeeded| {
+                    let mut latencies = latencies.lock().unwrap();
+                    latencies.entry(*k).or_default().push((latency, succeeded));
+                }
+            })
+            .build()
+            .await;
+        cache.get(1).await.unwrap();
+        cache.remove(1).await;
+        cache.get(1).await.unwrap();
+        cache.get(2).await.unwrap_err();
+        // A hit doesn't fetch.
+        cache.get(1).await.unwrap();
+
+        let latencies = latencies.lock().unwrap();
+        let second = Duration::from_secs(1);
+        assert_eq!(vec![(second, true), (second, true)], latencies[&1]);
+        assert_eq!(vec![(second, false)], latencies[&2]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_aborts_tasks() {
+        let store = test_store().with_fetch_latency(Duration::from_secs(10));
+        let mut cache = Cache::builder(store.clone())
+            .soft_ttl(Duration::from_secs(1))
+            .build()
+            .await;
+        cache.insert(1, Arc::new(String::from("One"))).await;
+        sleep(Duration::from_secs(2)).await;
+        // This starts a refresh.
+        assert_eq!("One", *cache.get(1).await.unwrap());
+        let mut gets = JoinSet::new();
+        for k in 2..=3 {
+            let cache = cache.clone();
+            gets.spawn(async move { cache.get(k).await });
+        }
+        tokio::task::yield_now().await;
+        assert_eq!(3, *cache.tasks.subscribe().borrow());
+
+        cache.shutdown().await.unwrap();
+        assert_eq!(0, *cache.tasks.subscribe().borrow());
+        while let Some(get) = gets.join_next().await {
+            assert!(matches!(get.unwrap(), Err(CacheError::ShuttingDown)));
+        }
+        assert!(store.fetches().is_empty());
     }
 
-    struct TestStore {
-        tx: mpsc::UnboundedSender<StoreOperation>,
+    // Fails every fetch while `down` is set, and counts the fetches.
+    struct DownStore {
+        store: HashMapStore<i32, String>,
+        down: Arc<AtomicBool>,
+        fetches: Arc<std::sync::atomic::AtomicUsize>,
     }
 
     #[async_trait]
-    impl Store<i32, String> for TestStore {
+    impl Store<i32, String> for DownStore {
         async fn fetch(&self, key: &i32) -> anyhow::Result<String> {
-            self.tx.send(StoreOperation::Fetch(*key)).unwrap();
-            Ok(String::from("Hello"))
+            self.fetches.fetch_add(1, Ordering::Relaxed);
+            if self.down.load(Ordering::Relaxed) {
+                anyhow::bail!("Store is down");
+            }
+            self.store.fetch(key).await
         }
 
         async fn update(&self, key: i32, value: String) {
-            self.tx.send(StoreOperation::Update((key, value))).unwrap();
+            self.store.update(key, value).await
         }
     }
 
-    #[tokio::test]
-    async fn it_works() {
-        let (tx, mut rx) = mpsc::unbounded_channel();
+    #[tokio::test(start_paused = true)]
+    async fn circuit_breaker() {
+        let store = test_store();
+        store.insert(1, String::from("One"));
+        let down = Arc::new(AtomicBool::new(false));
+        let fetches = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let down_store = DownStore {
+            store,
+            down: down.clone(),
+            fetches: fetches.clone(),
+        };
+        let cache = Cache::builder(down_store)
+            .hard_ttl(Duration::from_secs(10))
+            .circuit_breaker(2, Duration::from_secs(30))
+            .no_pruner()
+            .build()
+            .await;
+        assert_eq!("One", *cache.get(1).await.unwrap());
 
-        let mut cache = Cache::new(TestStore { tx }).await;
+        down.store(true, Ordering::Relaxed);
+        sleep(Duration::from_secs(11)).await;
+        cache.get(2).await.unwrap_err();
+        assert!(!cache.is_circuit_open());
+        cache.get(3).await.unwrap_err();
+        assert!(cache.is_circuit_open());
+        assert_eq!(3, fetches.load(Ordering::Relaxed));
 
-        {
-            let v = cache.get(10).await.unwrap();
-            assert_eq!("Hello", *v);
-            let v = cache.get(10).await.unwrap();
-            assert_eq!("Hello", *v);
+        // The value past its hard TTL is served, and misses don't fetch.
+        assert_eq!("One", *cache.get(1).await.unwrap());
+        assert!(matches!(cache.get(4).await, Err(CacheError::CircuitOpen)));
+        assert_eq!(3, fetches.load(Ordering::Relaxed));
+
+        // The probe closes the circuit once the store is back.
+        down.store(false, Ordering::Relaxed);
+        sleep(Duration::from_secs(30)).await;
+        assert_eq!("Hello", *cache.get(5).await.unwrap());
+        assert!(!cache.is_circuit_open());
+        assert_eq!(4, fetches.load(Ordering::Relaxed));
+        assert_eq!("One", *cache.get(1).await.unwrap());
+        assert_eq!(5, fetches.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn max_fetching_keys() {
+        let store = test_store().with_fetch_latency(Duration::from_secs(10));
+        let cache = Cache::builder(store.clone())
+            .max_fetching_keys(2)
+            .build()
+            .await;
+        let mut gets = JoinSet::new();
+        for k in [1, 2, 1] {
+            let cache = cache.clone();
+            gets.spawn(async move { cache.get(k).await });
         }
+        tokio::task::yield_now().await;
 
-        cache.evict_all_sync().await;
+        assert!(matches!(cache.get(3).await, Err(CacheError::TooBusy)));
+        let results = cache.get_many_results(&[2, 4]).await;
+        assert_eq!("Hello", **results[0].as_ref().unwrap());
+        assert!(matches!(results[1], Err(CacheError::TooBusy)));
+        while let Some(get) = gets.join_next().await {
+            assert_eq!("Hello", *get.unwrap().unwrap());
+        }
+        assert_eq!(2, store.fetches().len());
 
-        drop(cache);
+        // Once the fetches are done, other keys can be fetched.
+        assert_eq!("Hello", *cache.get(3).await.unwrap());
+    }
 
-        tokio::spawn(async move {
-            let mut operations = vec![];
-            while let Some(op) = rx.recv().await {
-                operations.push(op);
-            }
-            assert_eq!(
-                vec![
-                    StoreOperation::Fetch(10),
-                    StoreOperation::Update((10, "Hello".to_string()))
-                ],
-                operations
-            );
-        })
-        .await
-        .unwrap();
+    #[tokio::test(start_paused = true)]
+    async fn max_tasks() {
+        let store = test_store().with_fetch_latency(Duration::from_secs(10));
+        let cache = Cache::builder(store.clone())
+            .soft_ttl(Duration::from_secs(1))
+            .max_tasks(1)
+            .no_pruner()
+            .build()
+            .await;
+        cache.insert(1, Arc::new(String::from("One"))).await;
+        let get = tokio::spawn({
+            let cache = cache.clone();
+            async move { cache.get(2).await }
+        });
+        tokio::task::yield_now().await;
+
+        // The fetch of 2 takes the only task, so 3 isn't fetched and 1 isn't
+        // refreshed.
+        assert!(matches!(cache.get(3).await, Err(CacheError::TooBusy)));
+        sleep(Duration::from_secs(2)).await;
+        assert_eq!("One", *cache.get(1).await.unwrap());
+        assert_eq!("Hello", *get.await.unwrap().unwrap());
+        assert_eq!(vec![2], store.fetches());
+
+        // Once the fetch is done, there's room to refresh 1.
+        cache.get(1).await.unwrap();
+        cache.quiesce().await;
+        assert_eq!(vec![2, 1], store.fetches());
     }
 
-    struct StoreWithLatency;
+    #[tokio::test(start_paused = true)]
+    async fn renew_while_held() {
+        let cache = Cache::builder(test_store())
+            .access_ttl(Duration::from_secs(2))
+            .prune_interval(Duration::from_secs(1))
+            .renew_while_held()
+            .build()
+            .await;
+        let held = cache.get(1).await.unwrap();
+        sleep(Duration::from_millis(5500)).await;
+        assert_eq!(1, cache.len().await);
 
-    #[async_trait]
-    impl Store<i32, String> for StoreWithLatency {
-        async fn fetch(&self, _key: &i32) -> anyhow::Result<String> {
-            sleep(Duration::from_secs(1)).await;
-            Ok(String::from("Hello"))
-        }
+        // The last sweep while it was held, at 5 seconds, renewed it.
+        drop(held);
+        sleep(Duration::from_secs(1)).await;
+        assert_eq!(1, cache.len().await);
+        sleep(Duration::from_secs(1)).await;
+        assert!(cache.is_empty().await);
+    }
 
-        async fn update(&self, _key: i32, _value: String) {
-            sleep(Duration::from_secs(1)).await;
-        }
+    #[tokio::test(start_paused = true)]
+    async fn get_or_insert_many_with() {
+        let store = test_store().with_fetch_latency(Duration::from_secs(1));
+        let cache = Cache::new(store.clone()).await;
+        cache.insert(2, Arc::new(String::from("Two"))).await;
+        let fetching = tokio::spawn({
+            let cache = cache.clone();
+            async move { cache.get(3).await }
+        });
+        tokio::task::yield_now().await;
+
+        let computed = std::sync::Mutex::new(vec![]);
+        let values = cache
+            .get_or_insert_many_with(vec![1, 2, 3, 4, 1], |k| {
+                computed.lock().unwrap().push(*k);
+                format!("Computed {}", k)
+            })
+            .await;
+        let values: Vec<_> = values.iter().map(|v| v.as_str()).collect();
+        assert_eq!(
+            vec!["Computed 1", "Two", "Hello", "Computed 4", "Computed 1"],
+            values
+        );
+        assert_eq!(vec![1, 4], *computed.lock().unwrap());
+        assert_eq!(Some(ValueSource::Inserted), cache.value_source(&4).await);
+        assert_eq!("Hello", *fetching.await.unwrap().unwrap());
+        assert_eq!(vec![3], store.fetches());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn recent_events() {
+        let store = HashMapStore::new()
+            .with_default(String::from("Hello"))
+            .with_fetch_latency(Duration::from_secs(1));
+        let cache = Cache::builder(store).no_pruner().build().await;
+
+        let start = Instant::now();
+        cache.get(1).await.unwrap();
+        cache.get(1).await.unwrap();
+        assert_eq!(Some(1), cache.evict_one().await);
+        cache.get(1).await.unwrap();
+        cache.remove(1).await;
+        cache.get(2).await.unwrap();
+
+        let events = cache.recent_events(&1);
+        let kinds: Vec<_> = events.iter().map(|event| event.kind).collect();
+        let fetch_done = EventKind::FetchDone { succeeded: true };
+        assert_eq!(
+            vec![
+                EventKind::FetchStart,
+                fetch_done,
+                EventKind::Insert,
+                EventKind::Hit,
+                EventKind::Evict,
+                EventKind::FetchStart,
+                fetch_done,
+                EventKind::Insert,
+                EventKind::Invalidate,
+            ],
+            kinds
+        );
+        assert_eq!(start, events[0].at);
+        assert_eq!(start + Duration::from_secs(1), events[1].at);
+        assert_eq!(start + Duration::from_secs(2), events[8].at);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn on_fetch_timeout() {
+        let store = store_with_latency();
+        let cache = Cache::builder(store.clone())
+            .fetch_deadline(Duration::from_millis(500))
+            .on_fetch_timeout(OnFetchTimeout::NegativeCache(Duration::from_secs(5)))
+            .no_pruner()
+            .build()
+            .await;
+
+        assert!(matches!(cache.get(1).await, Err(CacheError::Timeout)));
+        #[cfg(feature = "stats")]
+        assert_eq!(1, cache.stats().fetches);
+
+        // The timeout is served without fetching again until the window is
+        // over.
+        sleep(Duration::from_secs(4)).await;
+        let start = Instant::now();
+        assert!(matches!(cache.get(1).await, Err(CacheError::Timeout)));
+        assert_eq!(start, Instant::now());
+        #[cfg(feature = "stats")]
+        assert_eq!(1, cache.stats().fetches);
+
+        sleep(Duration::from_secs(1)).await;
+        assert!(matches!(cache.get(1).await, Err(CacheError::Timeout)));
+        #[cfg(feature = "stats")]
+        assert_eq!(2, cache.stats().fetches);
+
+        // `get_many` serves it too, and the pruner drops it once the window is
+        // over.
+        let results = cache.get_many_results(&[1]).await;
+        assert!(matches!(results[0], Err(CacheError::Timeout)));
+        #[cfg(feature = "stats")]
+        assert_eq!(2, cache.stats().fetches);
+        sleep(Duration::from_secs(5)).await;
+        cache.run_prune_once().await;
+        assert!(cache.data.lock().await.is_empty());
     }
 
     #[tokio::test]
     async fn multiple_waiters() {
-        let cache = Arc::new(Cache::new(StoreWithLatency).await);
+        let cache = Arc::new(Cache::new(store_with_latency()).await);
 
         let mut tasks = JoinSet::new();
         for _ in 1..100 {
@@ -570,4 +6722,48 @@ mod tests {
             assert!(res.is_ok());
         }
     }
+
+    #[tokio::test]
+    async fn abandoned_fetch_is_retried() {
+        let store = store_with_latency();
+        let cache = Arc::new(Cache::new(store.clone()).await);
+
+        let mut tasks = JoinSet::new();
+        for _ in 0..10 {
+            let cache = cache.clone();
+            tasks.spawn(async move { cache.get(1).await.unwrap() });
+        }
+
+        // Removing the entry abandons the fetch and disconnects its waiters.
+        sleep(Duration::from_millis(500)).await;
+        cache.remove(1).await;
+
+        while let Some(res) = tasks.join_next().await {
+            assert_eq!("Hello", *res.unwrap());
+        }
+
+        sleep(Duration::from_secs(1)).await;
+        assert_eq!(vec![1, 1], store.fetches());
+    }
+
+    #[cfg(feature = "stats")]
+    #[tokio::test]
+    async fn fetch_fan_in() {
+        let cache = Arc::new(Cache::new(store_with_latency()).await);
+
+        let mut tasks = JoinSet::new();
+        for _ in 0..50 {
+            let cache = cache.clone();
+            tasks.spawn(async move { cache.get(1).await.unwrap() });
+        }
+
+        while let Some(res) = tasks.join_next().await {
+            assert_eq!("Hello", *res.unwrap());
+        }
+
+        let stats = cache.stats();
+        assert_eq!(1, stats.fetches);
+        assert_eq!(50, stats.max_fan_in);
+        assert_eq!(50.0, stats.average_fan_in);
+    }
 }
@@ -0,0 +1,126 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use tokio::time::Duration;
+
+use crate::Store;
+
+// A `Store` that falls back to other stores when a fetch fails, e.g. to serve
+// values from a replica or a snapshot while the primary store is down. Each
+// fetch tries the stores in order until one succeeds, and fails with the last
+// store's error if they all fail. Writes, including `finalize`, only go to
+// the primary store.
+pub struct ChainedStore<K, V> {
+    primary: Arc<dyn Store<K, V> + Send + Sync>,
+    fallbacks: Vec<Arc<dyn Store<K, V> + Send + Sync>>,
+}
+
+impl<K, V> ChainedStore<K, V> {
+    pub fn new(primary: impl Store<K, V> + Send + Sync + 'static) -> Self {
+        Self {
+            primary: Arc::new(primary),
+            fallbacks: vec![],
+        }
+    }
+
+    // Adds a store to fetch from if the ones before it fail.
+    pub fn with_fallback(mut self, store: impl Store<K, V> + Send + Sync + 'static) -> Self {
+        self.fallbacks.push(Arc::new(store));
+        self
+    }
+}
+
+impl<K, V> Clone for ChainedStore<K, V> {
+    fn clone(&self) -> Self {
+        Self {
+            primary: self.primary.clone(),
+            fallbacks: self.fallbacks.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl<K, V> Store<K, V> for ChainedStore<K, V>
+where
+    K: Send + Sync + 'static,
+    V: Send + Sync + 'static,
+{
+    async fn fetch(&self, key: &K) -> anyhow::Result<V> {
+        Ok(self.fetch_with_ttl(key).await?.0)
+    }
+
+    async fn update(&self, key: K, value: V) {
+        self.primary.update(key, value).await;
+    }
+
+    async fn fetch_with_ttl(&self, key: &K) -> anyhow::Result<(V, Option<Duration>)> {
+        let mut result = self.primary.fetch_with_ttl(key).await;
+        for store in &self.fallbacks {
+            if result.is_ok() {
+                break;
+            }
+            result = store.fetch_with_ttl(key).await;
+        }
+        result
+    }
+
+    // The primary store fetches all the keys at once, and the keys it fails
+    // to fetch are then fetched one at a time from the other stores.
+    async fn fetch_many(&self, keys: &[K]) -> Vec<anyhow::Result<V>> {
+        let mut results = self.primary.fetch_many(keys).await;
+        for (key, result) in keys.iter().zip(&mut results) {
+            for store in &self.fallbacks {
+                if result.is_ok() {
+                    break;
+                }
+                *result = store.fetch(key).await;
+            }
+        }
+        results
+    }
+
+    async fn update_many(&self, updates: Vec<(K, V)>) {
+        self.primary.update_many(updates).await;
+    }
+
+    async fn try_update(&self, key: K, value: V) -> Result<(), (V, anyhow::Error)> {
+        self.primary.try_update(key, value).await
+    }
+
+    async fn finalize(&self, key: &K, value: &V) {
+        self.primary.finalize(key, value).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{HashMapStore, StoreOperation};
+    use crate::Cache;
+
+    #[tokio::test]
+    async fn falls_back_when_the_primary_fails() {
+        let primary = HashMapStore::new();
+        let backup = HashMapStore::new();
+        backup.insert(1, String::from("One"));
+        let store = ChainedStore::new(primary.clone()).with_fallback(backup.clone());
+        let mut cache = Cache::builder(store).no_pruner().build().await;
+
+        assert_eq!("One", *cache.get(1).await.unwrap());
+        assert!(cache.get(2).await.is_err());
+        cache.evict_all_sync().await;
+
+        assert_eq!(
+            vec![
+                StoreOperation::Fetch(1),
+                StoreOperation::Fetch(2),
+                StoreOperation::Update(1, String::from("One")),
+            ],
+            primary.operations()
+        );
+        assert_eq!(
+            vec![StoreOperation::Fetch(1), StoreOperation::Fetch(2)],
+            backup.operations()
+        );
+    }
+}
@@ -0,0 +1,80 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tokio::time::{Duration, Instant};
+
+// The source of the timestamps that access TTLs are measured against.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    // Moves a logical clock forward. Clocks that follow real time ignore this.
+    fn advance_ticks(&self, _ticks: u64) {}
+}
+
+// Real time, as seen by tokio. This is the default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+// A logical clock that only moves when it's advanced. Each tick counts as
+// `tick` of elapsed time, so TTLs are expressed as a number of ticks times
+// the tick duration. The clock stops at `MAX_ELAPSED` past its start, or
+// earlier if the platform can't represent that instant.
+#[derive(Debug)]
+pub struct TickClock {
+    start: Instant,
+    tick: Duration,
+    ticks: AtomicU64,
+}
+
+// About 30 years, which is also what tokio treats as the far future.
+const MAX_ELAPSED: Duration = Duration::from_secs(86400 * 365 * 30);
+
+impl TickClock {
+    pub fn new(tick: Duration) -> Self {
+        Self {
+            start: Instant::now(),
+            tick,
+            ticks: AtomicU64::new(0),
+        }
+    }
+
+    pub fn ticks(&self) -> u64 {
+        self.ticks.load(Ordering::Relaxed)
+    }
+}
+
+impl Clock for TickClock {
+    fn now(&self) -> Instant {
+        let ticks = u32::try_from(self.ticks()).unwrap_or(u32::MAX);
+        let elapsed = self.tick.saturating_mul(ticks).min(MAX_ELAPSED);
+        let mut now = self.start.checked_add(elapsed);
+        // Back off until the instant is representable.
+        let mut step = elapsed;
+        while now.is_none() {
+            step /= 2;
+            now = self.start.checked_add(step);
+        }
+        now.unwrap()
+    }
+
+    fn advance_ticks(&self, ticks: u64) {
+        self.ticks.fetch_add(ticks, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tick_clock_saturates() {
+        let clock = TickClock::new(Duration::MAX);
+        clock.advance_ticks(u64::MAX);
+        assert_eq!(clock.start + MAX_ELAPSED, clock.now());
+    }
+}
@@ -0,0 +1,222 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex, Weak};
+
+use async_trait::async_trait;
+use tokio::time::Duration;
+
+use crate::{Cache, CacheError, Store};
+
+type Compress<V> = dyn Fn(&V) -> Vec<u8> + Send + Sync;
+
+type Decompress<V> = dyn Fn(&[u8]) -> V + Send + Sync;
+
+struct Codec<V> {
+    compress: Box<Compress<V>>,
+    decompress: Box<Decompress<V>>,
+}
+
+// Compresses values on their way from the store into the cache, and
+// decompresses them on their way back.
+struct CompressingStore<S, V> {
+    store: S,
+    codec: Arc<Codec<V>>,
+}
+
+#[async_trait]
+impl<K, V, S> Store<K, Vec<u8>> for CompressingStore<S, V>
+where
+    K: Send + Sync + 'static,
+    V: Send + Sync + 'static,
+    S: Store<K, V> + Send + Sync,
+{
+    async fn fetch(&self, key: &K) -> anyhow::Result<Vec<u8>> {
+        let value = self.store.fetch(key).await?;
+        Ok((self.codec.compress)(&value))
+    }
+
+    async fn fetch_with_ttl(&self, key: &K) -> anyhow::Result<(Vec<u8>, Option<Duration>)> {
+        let (value, ttl) = self.store.fetch_with_ttl(key).await?;
+        Ok(((self.codec.compress)(&value), ttl))
+    }
+
+    async fn update(&self, key: K, value: Vec<u8>) {
+        let value = (self.codec.decompress)(&value);
+        self.store.update(key, value).await
+    }
+
+    async fn update_many(&self, updates: Vec<(K, Vec<u8>)>) {
+        let updates = updates
+            .into_iter()
+            .map(|(key, value)| (key, (self.codec.decompress)(&value)))
+            .collect();
+        self.store.update_many(updates).await
+    }
+
+    async fn fetch_many(&self, keys: &[K]) -> Vec<anyhow::Result<Vec<u8>>> {
+        self.store
+            .fetch_many(keys)
+            .await
+            .into_iter()
+            .map(|result| result.map(|value| (self.codec.compress)(&value)))
+            .collect()
+    }
+}
+
+// A recently decompressed value. It's only used while the cache still holds
+// the compressed bytes it was decompressed from. The reference to them is weak
+// so that it doesn't keep them from being evicted.
+struct HotEntry<K, V> {
+    key: K,
+    compressed: Weak<Vec<u8>>,
+    value: Arc<V>,
+}
+
+// A cache that holds its values compressed, for values that are large but
+// compress well. Values are decompressed on `get`, and the most recently
+// decompressed ones are kept around so that hot keys aren't decompressed on
+// every access. Values are written back to the store decompressed.
+pub struct CompressedCache<K, V> {
+    cache: Cache<K, Vec<u8>>,
+    codec: Arc<Codec<V>>,
+    hot: Mutex<VecDeque<HotEntry<K, V>>>,
+    hot_capacity: usize,
+}
+
+impl<K, V> CompressedCache<K, V>
+where
+    K: Hash + fmt::Display + Copy + Eq + Send + Sync + 'static,
+    V: Send + Sync + 'static,
+{
+    // `hot_capacity` is the number of decompressed values that are kept.
+    pub async fn new(
+        store: impl Store<K, V> + Send + Sync + 'static,
+        compress: impl Fn(&V) -> Vec<u8> + Send + Sync + 'static,
+        decompress: impl Fn(&[u8]) -> V + Send + Sync + 'static,
+        hot_capacity: usize,
+    ) -> Self {
+        let codec = Arc::new(Codec {
+            compress: Box::new(compress),
+            decompress: Box::new(decompress),
+        });
+        let store = CompressingStore {
+            store,
+            codec: codec.clone(),
+        };
+        Self {
+            cache: Cache::new(store).await,
+            codec,
+            hot: Mutex::new(VecDeque::with_capacity(hot_capacity)),
+            hot_capacity,
+        }
+    }
+
+    // The underlying cache of compressed values.
+    pub fn inner(&self) -> &Cache<K, Vec<u8>> {
+        &self.cache
+    }
+
+    pub async fn get(&self, k: K) -> Result<Arc<V>, CacheError> {
+        let compressed = self.cache.get(k).await?;
+        Ok(self.decompress(k, &compressed))
+    }
+
+    pub async fn insert(&self, k: K, v: &V) {
+        self.cache
+            .insert(k, Arc::new((self.codec.compress)(v)))
+            .await
+    }
+
+    pub async fn remove(&self, k: K) {
+        self.cache.remove(k).await
+    }
+
+    pub async fn evict_all_sync(&mut self) {
+        self.hot.lock().unwrap().clear();
+        self.cache.evict_all_sync().await
+    }
+
+    fn decompress(&self, k: K, compressed: &Arc<Vec<u8>>) -> Arc<V> {
+        let mut hot = self.hot.lock().unwrap();
+        let is_current = |entry: &HotEntry<K, V>| {
+            entry.key == k && entry.compressed.as_ptr() == Arc::as_ptr(compressed)
+        };
+        if let Some(i) = hot.iter().position(is_current) {
+            let entry = hot.remove(i).unwrap();
+            let value = entry.value.clone();
+            hot.push_front(entry);
+            return value;
+        }
+        // Drop whatever was cached for the key before, since the compressed
+        // value it came from has been replaced.
+        hot.retain(|entry| entry.key != k);
+
+        let value = Arc::new((self.codec.decompress)(compressed));
+        if self.hot_capacity > 0 {
+            hot.truncate(self.hot_capacity - 1);
+            hot.push_front(HotEntry {
+                key: k,
+                compressed: Arc::downgrade(compressed),
+                value: value.clone(),
+            });
+        }
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::{HashMapStore, StoreOperation};
+
+    // Run-length encodes bytes as (count, byte) pairs.
+    fn rle_compress(s: &String) -> Vec<u8> {
+        let mut compressed: Vec<u8> = Vec::new();
+        for &b in s.as_bytes() {
+            match compressed.as_mut_slice() {
+                [.., count, last] if *last == b && *count < u8::MAX => *count += 1,
+                _ => compressed.extend([1, b]),
+            }
+        }
+        compressed
+    }
+
+    fn rle_decompress(compressed: &[u8]) -> String {
+        let bytes: Vec<u8> = compressed
+            .chunks(2)
+            .flat_map(|pair| std::iter::repeat_n(pair[1], pair[0] as usize))
+            .collect();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn compression() {
+        let value = "a".repeat(1000) + &"b".repeat(1000);
+        let store = HashMapStore::new();
+        store.insert(1, value.clone());
+        let mut cache = CompressedCache::new(store.clone(), rle_compress, rle_decompress, 1).await;
+
+        let v = cache.get(1).await.unwrap();
+        assert_eq!(value, *v);
+        assert!(cache.inner().get(1).await.unwrap().len() < value.len() / 10);
+
+        // Hot keys are only decompressed once.
+        assert!(Arc::ptr_eq(&v, &cache.get(1).await.unwrap()));
+
+        // Replacing the value invalidates its decompressed copy.
+        cache.insert(1, &String::from("cc")).await;
+        assert_eq!("cc", *cache.get(1).await.unwrap());
+
+        drop(v);
+        cache.evict_all_sync().await;
+        assert_eq!(
+            vec![
+                StoreOperation::Fetch(1),
+                StoreOperation::Update(1, String::from("cc")),
+            ],
+            store.operations()
+        );
+    }
+}
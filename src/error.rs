@@ -0,0 +1,84 @@
+use std::error;
+use std::fmt;
+use std::sync::Arc;
+
+// The errors returned by the cache's fallible operations. `E` is the error
+// from the store, which is shared between every caller that waited on the
+// same fetch.
+#[derive(Debug, Clone)]
+pub enum CacheError<E = Arc<anyhow::Error>> {
+    // Fetching the value from the store failed or panicked.
+    Fetch(E),
+    // The operation didn't complete within its timeout.
+    Timeout,
+    // The fetch the caller was waiting on was abandoned, e.g. because its
+    // entry was removed, even after retrying.
+    Cancelled,
+    // Writing a value back to the store failed.
+    UpdateFailed(E),
+    // The cache has been shut down.
+    ShuttingDown,
+    // The get would have fetched another key while `max_fetching_keys` keys
+    // were already being fetched.
+    TooBusy,
+    // The get missed while the cache was frozen.
+    Frozen,
+    // The get missed while the circuit breaker was stopping fetches.
+    CircuitOpen,
+}
+
+impl<E> CacheError<E> {
+    // The store's error, if the store failed.
+    pub fn store_error(&self) -> Option<&E> {
+        match self {
+            Self::Fetch(err) | Self::UpdateFailed(err) => Some(err),
+            Self::Timeout
+            | Self::Cancelled
+            | Self::ShuttingDown
+            | Self::TooBusy
+            | Self::Frozen
+            | Self::CircuitOpen => None,
+        }
+    }
+}
+
+impl<E: fmt::Display> fmt::Display for CacheError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Fetch(err) => write!(f, "Failed to fetch: {}", err),
+            Self::Timeout => write!(f, "Timed out"),
+            Self::Cancelled => write!(f, "Fetch was cancelled"),
+            Self::UpdateFailed(err) => write!(f, "Failed to update: {}", err),
+            Self::ShuttingDown => write!(f, "Cache is shutting down"),
+            Self::TooBusy => write!(f, "Too many keys are being fetched"),
+            Self::Frozen => write!(f, "Cache is frozen"),
+            Self::CircuitOpen => write!(f, "Circuit breaker is open"),
+        }
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> error::Error for CacheError<E> {}
+
+// The part of the cache's background work that failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BackgroundPhase {
+    Fetch,
+    Update,
+    Prune,
+}
+
+// A failure in the cache's background work, e.g. a store that panicked,
+// which is reported to the `on_background_error` hook.
+#[derive(Clone, Debug)]
+pub struct BackgroundError {
+    pub phase: BackgroundPhase,
+    pub message: String,
+}
+
+impl fmt::Display for BackgroundError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} failed: {}", self.phase, self.message)
+    }
+}
+
+impl error::Error for BackgroundError {}
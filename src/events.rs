@@ -0,0 +1,97 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use tokio::time::Instant;
+
+// Something that happened to a key's entry, for debugging.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventKind {
+    // A fetch or refresh of the key from the store started.
+    FetchStart,
+    // A fetch or refresh of the key finished, including by timing out or
+    // panicking. Abandoned fetches don't finish.
+    FetchDone { succeeded: bool },
+    // The key got a value, whether it was fetched or inserted.
+    Insert,
+    // A get was served from the key's cached value.
+    Hit,
+    // The key's value was evicted, or dropped for being past its hard TTL.
+    Evict,
+    // The key was removed, e.g. by `remove`.
+    Invalidate,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Event {
+    pub kind: EventKind,
+    // When it happened, by the cache's clock.
+    pub at: Instant,
+}
+
+// Whether events are recorded at all. Without the `events` feature, recording
+// does nothing, so the cache doesn't lock the log, and the log reads as
+// empty.
+const ENABLED: bool = cfg!(feature = "events");
+
+// The last `capacity` events for all keys, oldest first.
+pub(crate) struct EventLog<K> {
+    capacity: usize,
+    events: Mutex<VecDeque<(K, Event)>>,
+}
+
+impl<K> EventLog<K> {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            events: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        ENABLED && self.capacity > 0
+    }
+
+    pub(crate) fn record(&self, k: K, event: Event) {
+        let mut events = self.events.lock().unwrap();
+        if events.len() == self.capacity {
+            events.pop_front();
+        }
+        events.push_back((k, event));
+    }
+
+    pub(crate) fn recent(&self, k: &K) -> Vec<Event>
+    where
+        K: PartialEq,
+    {
+        let events = self.events.lock().unwrap();
+        events
+            .iter()
+            .filter(|(key, _)| key == k)
+            .map(|(_, event)| *event)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_last_events() {
+        let log = EventLog::new(3);
+        let at = Instant::now();
+        for (k, kind) in [
+            (1, EventKind::FetchStart),
+            (1, EventKind::Insert),
+            (2, EventKind::Insert),
+            (1, EventKind::Hit),
+        ] {
+            log.record(k, Event { kind, at });
+        }
+
+        // The oldest event fell out of the log.
+        let kinds: Vec<_> = log.recent(&1).iter().map(|event| event.kind).collect();
+        assert_eq!(vec![EventKind::Insert, EventKind::Hit], kinds);
+        assert_eq!(1, log.recent(&2).len());
+    }
+}
@@ -0,0 +1,199 @@
+use std::sync::Arc;
+
+use tokio::sync::{mpsc, watch};
+
+use crate::stats::Counters;
+
+// What to do with an evicted value when the bounded evict channel is full.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EvictOverflow {
+    // Wait for the evictor to make room.
+    #[default]
+    Block,
+    // Drop the value without writing it back to the store.
+    Drop,
+}
+
+// How far `Cache::evict_all_sync_with_progress` has got, after one pass
+// over the cache.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EvictProgress {
+    // The number of entries still in the cache, e.g. values that are still
+    // referenced.
+    pub remaining: usize,
+    // The number of entries evicted by this pass.
+    pub evicted: usize,
+}
+
+// A value on its way to the evictor. Every value is finalized, but `Clean`
+// ones, which `skip_clean_writebacks` skips, aren't written back.
+pub(crate) enum Evicted<V> {
+    Dirty(V),
+    Clean(V),
+}
+
+impl<V> Evicted<V> {
+    pub(crate) fn value(&self) -> &V {
+        match self {
+            Self::Dirty(v) | Self::Clean(v) => v,
+        }
+    }
+
+    // The value, if it's written back.
+    pub(crate) fn into_dirty(self) -> Option<V> {
+        match self {
+            Self::Dirty(v) => Some(v),
+            Self::Clean(_) => None,
+        }
+    }
+}
+
+// The channel between the code that evicts values (the pruner, `try_evict`,
+// and `evict_all_sync`) and the evictor that writes them back to the store.
+// Values are sent in batches, and each batch is written back together.
+// `backlog` counts the values that have been sent but not written back yet.
+pub(crate) struct EvictSender<K, V> {
+    tx: Tx<K, V>,
+    backlog: Backlog,
+}
+
+// A watch, so that `Cache::quiesce` can wait for it to drain.
+pub(crate) type Backlog = Arc<watch::Sender<usize>>;
+
+enum Tx<K, V> {
+    Unbounded(mpsc::UnboundedSender<Vec<(K, Evicted<V>)>>),
+    Bounded {
+        tx: mpsc::Sender<Vec<(K, Evicted<V>)>>,
+        overflow: EvictOverflow,
+        stats: Arc<Counters>,
+    },
+}
+
+pub(crate) struct EvictReceiver<K, V> {
+    rx: Rx<K, V>,
+    backlog: Backlog,
+}
+
+enum Rx<K, V> {
+    Unbounded(mpsc::UnboundedReceiver<Vec<(K, Evicted<V>)>>),
+    Bounded(mpsc::Receiver<Vec<(K, Evicted<V>)>>),
+}
+
+pub(crate) fn channel<K, V>(
+    capacity: Option<usize>,
+    overflow: EvictOverflow,
+    stats: Arc<Counters>,
+    backlog: Backlog,
+) -> (EvictSender<K, V>, EvictReceiver<K, V>) {
+    let (tx, rx) = match capacity {
+        None => {
+            let (tx, rx) = mpsc::unbounded_channel();
+            (Tx::Unbounded(tx), Rx::Unbounded(rx))
+        }
+        Some(capacity) => {
+            let (tx, rx) = mpsc::channel(capacity);
+            let tx = Tx::Bounded {
+                tx,
+                overflow,
+                stats,
+            };
+            (tx, Rx::Bounded(rx))
+        }
+    };
+    let rx = EvictReceiver {
+        rx,
+        backlog: backlog.clone(),
+    };
+    (EvictSender { tx, backlog }, rx)
+}
+
+impl<K, V> EvictSender<K, V> {
+    pub(crate) async fn send(&self, evicted: (K, Evicted<V>)) {
+        self.send_batch(vec![evicted]).await
+    }
+
+    pub(crate) async fn send_batch(&self, evicted: Vec<(K, Evicted<V>)>) {
+        if let Err(evicted) = self.try_send_batch(evicted) {
+            let Tx::Bounded { tx, .. } = &self.tx else {
+                unreachable!("Only a bounded channel can be full");
+            };
+            let count = evicted.len();
+            self.backlog.send_modify(|backlog| *backlog += count);
+            tx.send(evicted).await.unwrap();
+        }
+    }
+
+    // Like `send_batch`, but without waiting, for callers that have the cache
+    // locked. If the channel is full and blocks on overflow, the batch is
+    // handed back to be sent later.
+    pub(crate) fn try_send_batch(
+        &self,
+        evicted: Vec<(K, Evicted<V>)>,
+    ) -> Result<(), Vec<(K, Evicted<V>)>> {
+        // The backlog is counted before sending, so that the evictor can't
+        // finish the batch first.
+        let count = evicted.len();
+        self.backlog.send_modify(|backlog| *backlog += count);
+        let (tx, overflow, stats) = match &self.tx {
+            Tx::Unbounded(tx) => {
+                tx.send(evicted).unwrap();
+                return Ok(());
+            }
+            Tx::Bounded {
+                tx,
+                overflow,
+                stats,
+            } => (tx, overflow, stats),
+        };
+        match tx.try_send(evicted) {
+            Ok(()) => Ok(()),
+            Err(mpsc::error::TrySendError::Full(evicted)) => {
+                self.backlog.send_modify(|backlog| *backlog -= count);
+                match overflow {
+                    EvictOverflow::Block => Err(evicted),
+                    EvictOverflow::Drop => {
+                        stats.record_dropped_writebacks(evicted.len());
+                        Ok(())
+                    }
+                }
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => panic!("Evictor is gone"),
+        }
+    }
+}
+
+impl<K, V> Clone for EvictSender<K, V> {
+    fn clone(&self) -> Self {
+        let tx = match &self.tx {
+            Tx::Unbounded(tx) => Tx::Unbounded(tx.clone()),
+            Tx::Bounded {
+                tx,
+                overflow,
+                stats,
+            } => Tx::Bounded {
+                tx: tx.clone(),
+                overflow: *overflow,
+                stats: stats.clone(),
+            },
+        };
+        Self {
+            tx,
+            backlog: self.backlog.clone(),
+        }
+    }
+}
+
+impl<K, V> EvictReceiver<K, V> {
+    pub(crate) async fn recv(&mut self) -> Option<Vec<(K, Evicted<V>)>> {
+        match &mut self.rx {
+            Rx::Unbounded(rx) => rx.recv().await,
+            Rx::Bounded(rx) => rx.recv().await,
+        }
+    }
+
+    // Takes `count` values that were received off the backlog, once they've
+    // been written back or dropped.
+    pub(crate) fn finish(&self, count: usize) {
+        self.backlog.send_modify(|backlog| *backlog -= count);
+    }
+}
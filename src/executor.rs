@@ -0,0 +1,81 @@
+use std::future::Future;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use tokio::runtime::Handle;
+use tokio::task::JoinHandle;
+
+use crate::Store;
+
+// Where fetches from the store run.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FetchExecutor {
+    // On the async runtime, with `tokio::spawn`.
+    #[default]
+    Spawn,
+    // On tokio's blocking thread pool, with `tokio::task::spawn_blocking`.
+    // This is for stores that do CPU-heavy or blocking work in `fetch`, e.g. a
+    // `BlockingStore`, so that it doesn't tie up the runtime's worker threads.
+    // Aborting a fetch that's running here, e.g. at its `fetch_deadline`,
+    // doesn't stop it.
+    Blocking,
+}
+
+impl FetchExecutor {
+    pub(crate) fn spawn<T>(self, fetch: impl Future<Output = T> + Send + 'static) -> JoinHandle<T>
+    where
+        T: Send + 'static,
+    {
+        match self {
+            Self::Spawn => tokio::spawn(fetch),
+            Self::Blocking => {
+                let handle = Handle::current();
+                tokio::task::spawn_blocking(move || handle.block_on(fetch))
+            }
+        }
+    }
+}
+
+// Adapts a pair of synchronous closures into a `Store`. Fetches call `fetch`
+// directly, so build the cache with `FetchExecutor::Blocking` to keep them off
+// the runtime's worker threads. Updates always run on the blocking thread pool.
+pub struct BlockingStore<K, V, F, U> {
+    fetch: F,
+    update: Arc<U>,
+    _marker: PhantomData<fn(K) -> V>,
+}
+
+impl<K, V, F, U> BlockingStore<K, V, F, U>
+where
+    F: Fn(&K) -> anyhow::Result<V>,
+    U: Fn(K, V),
+{
+    pub fn new(fetch: F, update: U) -> Self {
+        Self {
+            fetch,
+            update: Arc::new(update),
+            _marker: PhantomData,
+        }
+    }
+}
+
+#[async_trait]
+impl<K, V, F, U> Store<K, V> for BlockingStore<K, V, F, U>
+where
+    K: Send + Sync + 'static,
+    V: Send + 'static,
+    F: Fn(&K) -> anyhow::Result<V> + Send + Sync,
+    U: Fn(K, V) + Send + Sync + 'static,
+{
+    async fn fetch(&self, key: &K) -> anyhow::Result<V> {
+        (self.fetch)(key)
+    }
+
+    async fn update(&self, key: K, value: V) {
+        let update = self.update.clone();
+        tokio::task::spawn_blocking(move || update(key, value))
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,126 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::oneshot;
+
+// How urgently a get needs its fetch, when the number of concurrent fetches
+// is limited. Fetches waiting for a slot start in priority order, and in the
+// order they started waiting within a priority.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    // E.g. prefetches that nobody is waiting on yet.
+    Low,
+    #[default]
+    Normal,
+    // E.g. user-facing gets.
+    High,
+}
+
+// Limits the number of fetches in progress, like a semaphore that hands out
+// its permits by priority instead of first come, first served.
+pub(crate) struct FetchGate {
+    state: Mutex<GateState>,
+}
+
+struct GateState {
+    available: usize,
+    waiting: BinaryHeap<Queued>,
+    // Breaks ties between waiters with the same priority.
+    next_seq: u64,
+}
+
+struct Queued {
+    priority: Priority,
+    seq: u64,
+    tx: oneshot::Sender<FetchPermit>,
+}
+
+impl PartialEq for Queued {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Queued {}
+
+impl PartialOrd for Queued {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Queued {
+    // The heap pops the greatest waiter first, so earlier waiters compare
+    // greater than later ones with the same priority.
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+// A slot for one fetch, which is given back when it's dropped.
+pub(crate) struct FetchPermit {
+    // This is only `None` for a permit that was never handed out.
+    gate: Option<Arc<FetchGate>>,
+}
+
+impl FetchPermit {
+    fn new(gate: &Arc<FetchGate>) -> Self {
+        Self {
+            gate: Some(gate.clone()),
+        }
+    }
+}
+
+impl FetchGate {
+    pub(crate) fn new(permits: usize) -> Arc<Self> {
+        Arc::new(Self {
+            state: Mutex::new(GateState {
+                available: permits,
+                waiting: BinaryHeap::new(),
+                next_seq: 0,
+            }),
+        })
+    }
+
+    pub(crate) async fn acquire(self: &Arc<Self>, priority: Priority) -> FetchPermit {
+        let rx = {
+            let mut state = self.state.lock().unwrap();
+            if state.available > 0 {
+                state.available -= 1;
+                return FetchPermit::new(self);
+            }
+            let (tx, rx) = oneshot::channel();
+            let seq = state.next_seq;
+            state.next_seq += 1;
+            state.waiting.push(Queued { priority, seq, tx });
+            rx
+        };
+        // The gate only drops a waiter's sender after sending it a permit.
+        rx.await.expect("Fetch gate dropped a waiter")
+    }
+
+    fn release(self: &Arc<Self>) {
+        let mut state = self.state.lock().unwrap();
+        while let Some(queued) = state.waiting.pop() {
+            match queued.tx.send(FetchPermit::new(self)) {
+                Ok(()) => return,
+                // The waiter stopped waiting. Taking the gate out of the
+                // permit keeps its drop from releasing it again while the
+                // gate is locked.
+                Err(mut permit) => permit.gate = None,
+            }
+        }
+        state.available += 1;
+    }
+}
+
+impl Drop for FetchPermit {
+    fn drop(&mut self) {
+        if let Some(gate) = self.gate.take() {
+            gate.release();
+        }
+    }
+}
@@ -1,3 +1,38 @@
+mod breaker;
+pub mod builder;
 pub mod cache;
+pub mod chained;
+pub mod clock;
+#[cfg(feature = "compression")]
+pub mod compression;
+pub mod error;
+pub mod events;
+pub mod evict;
+pub mod executor;
+pub mod gate;
+pub mod mem_store;
+pub mod policy;
+pub mod runtime;
+pub mod stats;
+mod tasks;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 
-pub use cache::{Cache, GetError, Store};
+pub use builder::{BoxFuture, CacheBuilder};
+pub use cache::{
+    AdmissionContext, AsyncGet, Cache, Entry, InsertDuringFetch, OnFetchTimeout, ReadView, Store,
+    ValueSource, WarmUpProgress,
+};
+pub use chained::ChainedStore;
+pub use clock::{Clock, SystemClock, TickClock};
+#[cfg(feature = "compression")]
+pub use compression::CompressedCache;
+pub use error::{BackgroundError, BackgroundPhase, CacheError};
+pub use events::{Event, EventKind};
+pub use evict::{EvictOverflow, EvictProgress};
+pub use executor::{BlockingStore, FetchExecutor};
+pub use gate::Priority;
+pub use mem_store::MemStore;
+pub use policy::{EvictionPolicy, Fifo, Lru};
+pub use runtime::CacheRuntime;
+pub use stats::CacheStats;
@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+
+use crate::Store;
+
+// A `Store` over an in-memory map that can be shared with other code, e.g. to
+// back one cache with the contents of another layer or to seed and inspect
+// the data in tests. Fetches clone the value out of the map, and updates
+// write it back. Fetching a missing key fails, unless the store has a
+// default value.
+pub struct MemStore<K, V> {
+    map: Arc<Mutex<HashMap<K, V>>>,
+    default: Option<V>,
+}
+
+impl<K, V> MemStore<K, V> {
+    pub fn new() -> Self {
+        Self::from_shared(Arc::default())
+    }
+
+    // A store over an existing map. Changes made through the map are seen by
+    // the store and the other way around.
+    pub fn from_shared(map: Arc<Mutex<HashMap<K, V>>>) -> Self {
+        Self { map, default: None }
+    }
+
+    // The value that fetching a missing key returns.
+    pub fn with_default(mut self, default: V) -> Self {
+        self.default = Some(default);
+        self
+    }
+
+    pub fn shared(&self) -> Arc<Mutex<HashMap<K, V>>> {
+        self.map.clone()
+    }
+}
+
+impl<K, V> Default for MemStore<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V: Clone> Clone for MemStore<K, V> {
+    fn clone(&self) -> Self {
+        Self {
+            map: self.map.clone(),
+            default: self.default.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl<K, V> Store<K, V> for MemStore<K, V>
+where
+    K: Hash + Eq + fmt::Display + Send + Sync,
+    V: Clone + Send + Sync,
+{
+    async fn fetch(&self, key: &K) -> anyhow::Result<V> {
+        self.map
+            .lock()
+            .unwrap()
+            .get(key)
+            .or(self.default.as_ref())
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("No value for key {}", key))
+    }
+
+    async fn update(&self, key: K, value: V) {
+        self.map.lock().unwrap().insert(key, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Cache;
+
+    #[tokio::test]
+    async fn backs_a_cache() {
+        let map = Arc::new(Mutex::new(HashMap::from([(1, String::from("One"))])));
+        let store = MemStore::from_shared(map.clone()).with_default(String::from("Default"));
+        let mut cache = Cache::builder(store).no_pruner().build().await;
+
+        assert_eq!("One", *cache.get(1).await.unwrap());
+        assert_eq!("Default", *cache.get(2).await.unwrap());
+
+        cache.insert(3, Arc::new(String::from("Three"))).await;
+        cache.evict_all_sync().await;
+
+        let map = map.lock().unwrap();
+        assert_eq!(3, map.len());
+        assert_eq!("Default", map[&2]);
+        assert_eq!("Three", map[&3]);
+    }
+
+    #[tokio::test]
+    async fn missing_key_without_default() {
+        let cache = Cache::new(MemStore::<i32, String>::new()).await;
+        assert!(cache.get(1).await.unwrap_err().store_error().is_some());
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Mutex;
+
+// Decides which values to evict when the cache is over `max_capacity` or
+// `max_weight`, and which ones `evict_one` and `evict_fraction` evict. The
+// cache tells the policy about every value that's added, accessed, or
+// removed, and asks it to pick victims out of the values that can be evicted
+// right now, i.e. that nothing else references. Values that have expired are
+// evicted regardless of the policy. Without a policy, the cache evicts the
+// least recently used values, as `Lru` does. The hooks are called with the
+// cache locked, so they must not use the cache.
+pub trait EvictionPolicy<K>: Send + Sync {
+    // Called when `k` gets a value, whether it's new or replaces another.
+    fn on_insert(&self, _k: &K) {}
+
+    // Called when `k`'s value is read or touched.
+    fn on_access(&self, _k: &K) {}
+
+    // Called when `k`'s value leaves the cache, however it leaves.
+    fn on_remove(&self, _k: &K) {}
+
+    // Picks the next value to evict out of `candidates`, or `None` to evict
+    // none of them.
+    fn select_victim(&self, candidates: &[K]) -> Option<K>;
+
+    // Picks up to `count` values to evict out of `candidates`, in the order
+    // they should be evicted. By default, this calls `select_victim` once
+    // for each one.
+    fn select_victims(&self, candidates: &[K], count: usize) -> Vec<K>
+    where
+        K: Clone + PartialEq,
+    {
+        let mut candidates = candidates.to_vec();
+        let mut victims = vec![];
+        while victims.len() < count {
+            let Some(victim) = self.select_victim(&candidates) else {
+                break;
+            };
+            candidates.retain(|k| *k != victim);
+            victims.push(victim);
+        }
+        victims
+    }
+}
+
+// The order in which keys were inserted or accessed, for the built-in
+// policies. Keys it has never seen come first.
+struct Ticks<K> {
+    ticks: Mutex<(HashMap<K, u64>, u64)>,
+}
+
+impl<K: Hash + Eq + Copy> Ticks<K> {
+    fn new() -> Self {
+        Self {
+            ticks: Mutex::new((HashMap::new(), 0)),
+        }
+    }
+
+    fn bump(&self, k: &K) {
+        let mut ticks = self.ticks.lock().unwrap();
+        let (ticks, next) = &mut *ticks;
+        *next += 1;
+        ticks.insert(*k, *next);
+    }
+
+    fn remove(&self, k: &K) {
+        self.ticks.lock().unwrap().0.remove(k);
+    }
+
+    fn oldest(&self, candidates: &[K], count: usize) -> Vec<K> {
+        let ticks = self.ticks.lock().unwrap();
+        let mut candidates = candidates.to_vec();
+        candidates.sort_by_key(|k| ticks.0.get(k).copied().unwrap_or(0));
+        candidates.truncate(count);
+        candidates
+    }
+}
+
+// Evicts the least recently inserted or accessed values first.
+pub struct Lru<K> {
+    ticks: Ticks<K>,
+}
+
+impl<K: Hash + Eq + Copy> Lru<K> {
+    pub fn new() -> Self {
+        Self {
+            ticks: Ticks::new(),
+        }
+    }
+}
+
+impl<K: Hash + Eq + Copy> Default for Lru<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Hash + Eq + Copy + Send + Sync> EvictionPolicy<K> for Lru<K> {
+    fn on_insert(&self, k: &K) {
+        self.ticks.bump(k);
+    }
+
+    fn on_access(&self, k: &K) {
+        self.ticks.bump(k);
+    }
+
+    fn on_remove(&self, k: &K) {
+        self.ticks.remove(k);
+    }
+
+    fn select_victim(&self, candidates: &[K]) -> Option<K> {
+        self.ticks.oldest(candidates, 1).pop()
+    }
+
+    fn select_victims(&self, candidates: &[K], count: usize) -> Vec<K> {
+        self.ticks.oldest(candidates, count)
+    }
+}
+
+// Evicts the least recently inserted values first, however recently they
+// were accessed.
+pub struct Fifo<K> {
+    ticks: Ticks<K>,
+}
+
+impl<K: Hash + Eq + Copy> Fifo<K> {
+    pub fn new() -> Self {
+        Self {
+            ticks: Ticks::new(),
+        }
+    }
+}
+
+impl<K: Hash + Eq + Copy> Default for Fifo<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Hash + Eq + Copy + Send + Sync> EvictionPolicy<K> for Fifo<K> {
+    fn on_insert(&self, k: &K) {
+        self.ticks.bump(k);
+    }
+
+    fn on_remove(&self, k: &K) {
+        self.ticks.remove(k);
+    }
+
+    fn select_victim(&self, candidates: &[K]) -> Option<K> {
+        self.ticks.oldest(candidates, 1).pop()
+    }
+
+    fn select_victims(&self, candidates: &[K], count: usize) -> Vec<K> {
+        self.ticks.oldest(candidates, count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lru_and_fifo() {
+        let lru = Lru::new();
+        let fifo = Fifo::new();
+        for k in 1..=3 {
+            lru.on_insert(&k);
+            fifo.on_insert(&k);
+        }
+        lru.on_access(&1);
+        fifo.on_access(&1);
+        lru.on_remove(&2);
+        fifo.on_remove(&2);
+
+        // A key the policy doesn't know about, like 2 now, goes first.
+        assert_eq!(vec![2, 3, 1], lru.select_victims(&[1, 2, 3], 3));
+        assert_eq!(vec![2, 1], fifo.select_victims(&[1, 2, 3], 2));
+        assert_eq!(Some(3), lru.select_victim(&[1, 3]));
+        assert_eq!(Some(1), fifo.select_victim(&[1, 3]));
+    }
+}
@@ -0,0 +1,98 @@
+use std::sync::{Arc, Mutex, Weak};
+
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Duration};
+
+use crate::builder::BoxFuture;
+
+// One pruner sweep of a cache, with the cache's key and value types erased so
+// that a single task can sweep caches of different types.
+pub(crate) trait Sweep: Send + Sync {
+    fn sweep(&self) -> BoxFuture<'_, ()>;
+}
+
+// How a cache's pruner runs.
+pub(crate) enum Pruner {
+    // In a task of its own.
+    Task(JoinHandle<()>),
+    // In a `CacheRuntime`'s task. The runtime only holds a weak reference, so
+    // this keeps the cache registered until the pruner is dropped.
+    Shared { _sweep: Arc<dyn Sweep> },
+}
+
+impl Drop for Pruner {
+    fn drop(&mut self) {
+        if let Self::Task(join_handle) = self {
+            join_handle.abort();
+        }
+    }
+}
+
+type Registered = Arc<Mutex<Vec<Weak<dyn Sweep>>>>;
+
+// Background tasks shared between caches, so that many small caches don't
+// each spawn their own pruner. Caches are registered with
+// `CacheBuilder::runtime`, and a single task sweeps all of them every
+// `prune_interval`. Clones share the same task.
+#[derive(Clone)]
+pub struct CacheRuntime {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    registered: Registered,
+    pruner_join_handle: JoinHandle<()>,
+}
+
+impl CacheRuntime {
+    pub fn new(prune_interval: Duration) -> Self {
+        let registered: Registered = Arc::default();
+        let pruner_join_handle = tokio::spawn({
+            let registered = registered.clone();
+            async move {
+                loop {
+                    // Caches that have been dropped are unregistered here.
+                    let caches: Vec<_> = {
+                        let mut registered = registered.lock().unwrap();
+                        registered.retain(|cache| cache.strong_count() > 0);
+                        registered.iter().filter_map(Weak::upgrade).collect()
+                    };
+                    for cache in caches {
+                        cache.sweep().await;
+                    }
+                    sleep(prune_interval).await;
+                }
+            }
+        });
+        Self {
+            inner: Arc::new(Inner {
+                registered,
+                pruner_join_handle,
+            }),
+        }
+    }
+
+    // Returns the number of caches that are registered and haven't been
+    // dropped.
+    pub fn cache_count(&self) -> usize {
+        let registered = self.inner.registered.lock().unwrap();
+        registered
+            .iter()
+            .filter(|cache| cache.strong_count() > 0)
+            .count()
+    }
+
+    pub(crate) fn register(&self, cache: &Arc<dyn Sweep>) {
+        self.inner
+            .registered
+            .lock()
+            .unwrap()
+            .push(Arc::downgrade(cache));
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        self.pruner_join_handle.abort();
+    }
+}
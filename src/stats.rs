@@ -0,0 +1,207 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tokio::time::Duration;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CacheStats {
+    // The number of gets served from a cached value.
+    pub hits: u64,
+    // The number of gets that had to fetch or wait on a fetch.
+    pub misses: u64,
+    // The number of values in the cache. This is only known when the stats
+    // are read with `Cache::stats_consistent`, which locks the cache.
+    pub entries: Option<usize>,
+    // The number of fetches that have completed, not counting soft TTL
+    // refreshes.
+    pub fetches: u64,
+    // The largest number of callers that waited on a single fetch.
+    pub max_fan_in: u64,
+    // The average number of callers that waited on a fetch.
+    pub average_fan_in: f64,
+    // The number of evicted values that were dropped instead of written back
+    // because the evict channel was full.
+    pub dropped_writebacks: u64,
+    // The number of fetched values that weren't cached because they weighed
+    // more than `max_value_weight`.
+    pub rejected_oversize: u64,
+    // Percentiles of how long fetches from the store took. These are the
+    // upper bounds of histogram buckets, so they can overestimate by up to an
+    // eighth. They're zero until a fetch completes.
+    pub fetch_latency_p50: Duration,
+    pub fetch_latency_p95: Duration,
+    pub fetch_latency_p99: Duration,
+}
+
+// Whether the counters are kept at all. Without the `stats` feature, recording
+// does nothing, so gets don't touch any atomics, and every counter reads as
+// zero.
+const ENABLED: bool = cfg!(feature = "stats");
+
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    fetches: AtomicU64,
+    fan_in_total: AtomicU64,
+    fan_in_max: AtomicU64,
+    dropped_writebacks: AtomicU64,
+    rejected_oversize: AtomicU64,
+    // The histogram is a few kilobytes, so it isn't even allocated without
+    // the feature.
+    #[cfg(feature = "stats")]
+    fetch_latency: LatencyHistogram,
+}
+
+impl Counters {
+    pub(crate) fn record_hit(&self) {
+        if !ENABLED {
+            return;
+        }
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_miss(&self) {
+        if !ENABLED {
+            return;
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Records a completed fetch along with the number of callers that were
+    // subscribed to it when it resolved.
+    pub(crate) fn record_fetch(&self, fan_in: usize) {
+        if !ENABLED {
+            return;
+        }
+        let fan_in = fan_in as u64;
+        self.fetches.fetch_add(1, Ordering::Relaxed);
+        self.fan_in_total.fetch_add(fan_in, Ordering::Relaxed);
+        self.fan_in_max.fetch_max(fan_in, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_dropped_writebacks(&self, count: usize) {
+        if !ENABLED {
+            return;
+        }
+        self.dropped_writebacks
+            .fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_rejected_oversize(&self) {
+        if !ENABLED {
+            return;
+        }
+        self.rejected_oversize.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Without the feature, this doesn't exist, so that fetches don't time
+    // themselves for it.
+    #[cfg(feature = "stats")]
+    pub(crate) fn record_fetch_latency(&self, latency: Duration) {
+        self.fetch_latency.record(latency);
+    }
+
+    pub(crate) fn snapshot(&self) -> CacheStats {
+        #[cfg(feature = "stats")]
+        let latency = |percentile| self.fetch_latency.percentile(percentile);
+        #[cfg(not(feature = "stats"))]
+        let latency = |_| Duration::ZERO;
+        let fetches = self.fetches.load(Ordering::Relaxed);
+        let fan_in_total = self.fan_in_total.load(Ordering::Relaxed);
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: None,
+            fetches,
+            max_fan_in: self.fan_in_max.load(Ordering::Relaxed),
+            average_fan_in: if fetches == 0 {
+                0.0
+            } else {
+                fan_in_total as f64 / fetches as f64
+            },
+            dropped_writebacks: self.dropped_writebacks.load(Ordering::Relaxed),
+            rejected_oversize: self.rejected_oversize.load(Ordering::Relaxed),
+            fetch_latency_p50: latency(0.50),
+            fetch_latency_p95: latency(0.95),
+            fetch_latency_p99: latency(0.99),
+        }
+    }
+}
+
+// The number of sub-buckets each power of two is split into, as a power of
+// two. This bounds the relative error of a percentile to 1 / 2^SUB_BITS.
+#[cfg(feature = "stats")]
+const SUB_BITS: u32 = 3;
+#[cfg(feature = "stats")]
+const SUB_BUCKETS: u64 = 1 << SUB_BITS;
+#[cfg(feature = "stats")]
+const BUCKETS: usize = (SUB_BUCKETS + (64 - SUB_BITS as u64) * SUB_BUCKETS) as usize;
+
+// A histogram of durations in microseconds, with log-linear buckets like an
+// HDR histogram. Durations below `SUB_BUCKETS` microseconds have a bucket
+// each, and every power of two above that is split into `SUB_BUCKETS`
+// buckets.
+#[cfg(feature = "stats")]
+#[derive(Debug)]
+struct LatencyHistogram {
+    buckets: Box<[AtomicU64]>,
+}
+
+#[cfg(feature = "stats")]
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self {
+            buckets: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+}
+
+#[cfg(feature = "stats")]
+impl LatencyHistogram {
+    fn record(&self, latency: Duration) {
+        let micros = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX);
+        self.buckets[Self::bucket(micros)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn bucket(micros: u64) -> usize {
+        if micros < SUB_BUCKETS {
+            return micros as usize;
+        }
+        let msb = 63 - micros.leading_zeros();
+        let sub = (micros >> (msb - SUB_BITS)) & (SUB_BUCKETS - 1);
+        (SUB_BUCKETS + u64::from(msb - SUB_BITS) * SUB_BUCKETS + sub) as usize
+    }
+
+    // The largest duration in microseconds that falls in `bucket`.
+    fn upper_bound(bucket: usize) -> u64 {
+        let bucket = bucket as u64;
+        if bucket < SUB_BUCKETS {
+            return bucket;
+        }
+        let shift = (bucket - SUB_BUCKETS) / SUB_BUCKETS;
+        let sub = (bucket - SUB_BUCKETS) % SUB_BUCKETS;
+        let lower = (SUB_BUCKETS | sub) << shift;
+        lower + ((1 << shift) - 1)
+    }
+
+    fn percentile(&self, percentile: f64) -> Duration {
+        let counts: Vec<_> = self
+            .buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .collect();
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return Duration::ZERO;
+        }
+        let rank = ((total as f64 * percentile).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, count) in counts.into_iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Duration::from_micros(Self::upper_bound(bucket));
+            }
+        }
+        unreachable!("The rank is at most the total count")
+    }
+}
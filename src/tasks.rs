@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::watch;
+use tokio::task::AbortHandle;
+
+// Tracks the tasks a cache spawns for its fetches and refreshes, so that they
+// can be counted, waited for, and aborted together, e.g. by `shutdown`.
+pub(crate) struct TaskTracker {
+    // The tasks that haven't finished yet, by ID, and the next ID.
+    tasks: Mutex<(HashMap<u64, AbortHandle>, u64)>,
+    // The number of tasks that haven't finished yet.
+    count: watch::Sender<usize>,
+}
+
+// Stops tracking a task when it's dropped, whether the task finished or was
+// aborted.
+struct Untrack {
+    tracker: Arc<TaskTracker>,
+    id: u64,
+}
+
+impl TaskTracker {
+    pub(crate) fn new() -> Arc<Self> {
+        Arc::new(Self {
+            tasks: Mutex::new((HashMap::new(), 0)),
+            count: watch::channel(0).0,
+        })
+    }
+
+    pub(crate) fn spawn(self: &Arc<Self>, task: impl Future<Output = ()> + Send + 'static) {
+        let mut tasks = self.tasks.lock().unwrap();
+        let (handles, next_id) = &mut *tasks;
+        let id = *next_id;
+        *next_id += 1;
+        let untrack = Untrack {
+            tracker: self.clone(),
+            id,
+        };
+        // The task can't untrack itself before it's tracked, since it has to
+        // lock the tasks first.
+        let join_handle = tokio::spawn(async move {
+            let _untrack = untrack;
+            task.await;
+        });
+        handles.insert(id, join_handle.abort_handle());
+        self.count.send_replace(handles.len());
+    }
+
+    // The number of tasks that haven't finished yet.
+    pub(crate) fn len(&self) -> usize {
+        *self.count.borrow()
+    }
+
+    // Returns a receiver of the number of tasks that haven't finished yet.
+    pub(crate) fn subscribe(&self) -> watch::Receiver<usize> {
+        self.count.subscribe()
+    }
+
+    // Returns once every task has finished, including tasks spawned while
+    // this waits.
+    pub(crate) async fn wait_all(&self) {
+        let mut count = self.count.subscribe();
+        // The sender lives as long as the tracker.
+        let _ = count.wait_for(|count| *count == 0).await;
+    }
+
+    // Aborts every task, and returns once they're all gone. Tasks spawned
+    // while this waits are aborted too.
+    pub(crate) async fn abort_all(&self) {
+        let mut count = self.count.subscribe();
+        loop {
+            // Aborting a task only schedules it to be dropped, so it doesn't
+            // untrack itself while the tasks are locked here.
+            for handle in self.tasks.lock().unwrap().0.values() {
+                handle.abort();
+            }
+            if *count.borrow_and_update() == 0 {
+                return;
+            }
+            // The sender lives as long as the tracker.
+            let _ = count.changed().await;
+        }
+    }
+}
+
+impl Drop for Untrack {
+    fn drop(&mut self) {
+        let mut tasks = self.tracker.tasks.lock().unwrap();
+        tasks.0.remove(&self.id);
+        self.tracker.count.send_replace(tasks.0.len());
+    }
+}
@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use tokio::sync::Notify;
+use tokio::time::{sleep, Duration};
+
+use crate::Store;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StoreOperation<K, V> {
+    Fetch(K),
+    FetchMany(Vec<K>),
+    Update(K, V),
+    UpdateMany(Vec<(K, V)>),
+}
+
+/// An in-memory [`Store`] that records every operation made against it.
+///
+/// Clones share the same contents and recording, so a clone can be kept
+/// around to inspect the store after handing it to a [`Cache`](crate::Cache).
+///
+/// ```
+/// use thru::testing::{HashMapStore, StoreOperation};
+/// use thru::Cache;
+///
+/// # #[tokio::main]
+/// # async fn main() {
+/// let store = HashMapStore::new();
+/// store.insert(1, String::from("Hello"));
+///
+/// let mut cache = Cache::new(store.clone()).await;
+/// assert_eq!("Hello", *cache.get(1).await.unwrap());
+/// cache.evict_all_sync().await;
+///
+/// assert_eq!(
+///     vec![
+///         StoreOperation::Fetch(1),
+///         StoreOperation::Update(1, String::from("Hello")),
+///     ],
+///     store.operations()
+/// );
+/// # }
+/// ```
+pub struct HashMapStore<K, V> {
+    inner: Arc<Inner<K, V>>,
+    default: Option<V>,
+    fetch_latency: Duration,
+    update_latency: Duration,
+}
+
+struct Inner<K, V> {
+    values: Mutex<HashMap<K, V>>,
+    operations: Mutex<Vec<StoreOperation<K, V>>>,
+    recorded: Notify,
+}
+
+impl<K, V> HashMapStore<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                values: Mutex::new(HashMap::new()),
+                operations: Mutex::new(Vec::new()),
+                recorded: Notify::new(),
+            }),
+            default: None,
+            fetch_latency: Duration::ZERO,
+            update_latency: Duration::ZERO,
+        }
+    }
+
+    // The value returned when fetching a key the store doesn't contain.
+    // Without a default, such fetches fail.
+    pub fn with_default(mut self, default: V) -> Self {
+        self.default = Some(default);
+        self
+    }
+
+    pub fn with_fetch_latency(mut self, latency: Duration) -> Self {
+        self.fetch_latency = latency;
+        self
+    }
+
+    pub fn with_update_latency(mut self, latency: Duration) -> Self {
+        self.update_latency = latency;
+        self
+    }
+
+    pub fn insert(&self, key: K, value: V) {
+        self.inner.values.lock().unwrap().insert(key, value);
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.inner.values.lock().unwrap().get(key).cloned()
+    }
+
+    pub fn operations(&self) -> Vec<StoreOperation<K, V>> {
+        self.inner.operations.lock().unwrap().clone()
+    }
+
+    // The keys fetched, in order. The keys of a `fetch_many` are flattened
+    // into the list.
+    pub fn fetches(&self) -> Vec<K> {
+        self.operations()
+            .into_iter()
+            .flat_map(|op| match op {
+                StoreOperation::Fetch(k) => vec![k],
+                StoreOperation::FetchMany(keys) => keys,
+                StoreOperation::Update(..) | StoreOperation::UpdateMany(_) => vec![],
+            })
+            .collect()
+    }
+
+    // The values written back, in order. The values of an `update_many` are
+    // flattened into the list.
+    pub fn updates(&self) -> Vec<(K, V)> {
+        self.operations()
+            .into_iter()
+            .flat_map(|op| match op {
+                StoreOperation::Fetch(_) | StoreOperation::FetchMany(_) => vec![],
+                StoreOperation::Update(k, v) => vec![(k, v)],
+                StoreOperation::UpdateMany(updates) => updates,
+            })
+            .collect()
+    }
+
+    // Waits until at least `count` updates have been recorded. Updates are
+    // made by a background task, so this is how tests observe writebacks.
+    pub async fn wait_for_updates(&self, count: usize) -> Vec<(K, V)> {
+        loop {
+            let recorded = self.inner.recorded.notified();
+            let updates = self.updates();
+            if updates.len() >= count {
+                return updates;
+            }
+            recorded.await;
+        }
+    }
+
+    fn record(&self, op: StoreOperation<K, V>) {
+        self.inner.operations.lock().unwrap().push(op);
+        self.inner.recorded.notify_waiters();
+    }
+}
+
+impl<K, V> Default for HashMapStore<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V: Clone> Clone for HashMapStore<K, V> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            default: self.default.clone(),
+            fetch_latency: self.fetch_latency,
+            update_latency: self.update_latency,
+        }
+    }
+}
+
+#[async_trait]
+impl<K, V> Store<K, V> for HashMapStore<K, V>
+where
+    K: Hash + Eq + Clone + fmt::Display + Send + Sync,
+    V: Clone + Send + Sync,
+{
+    async fn fetch(&self, key: &K) -> anyhow::Result<V> {
+        sleep(self.fetch_latency).await;
+        self.record(StoreOperation::Fetch(key.clone()));
+        self.get(key)
+            .or_else(|| self.default.clone())
+            .ok_or_else(|| anyhow::anyhow!("No value for key {}", key))
+    }
+
+    async fn fetch_many(&self, keys: &[K]) -> Vec<anyhow::Result<V>> {
+        sleep(self.fetch_latency).await;
+        self.record(StoreOperation::FetchMany(keys.to_vec()));
+        keys.iter()
+            .map(|key| {
+                self.get(key)
+                    .or_else(|| self.default.clone())
+                    .ok_or_else(|| anyhow::anyhow!("No value for key {}", key))
+            })
+            .collect()
+    }
+
+    async fn update(&self, key: K, value: V) {
+        sleep(self.update_latency).await;
+        self.record(StoreOperation::Update(key.clone(), value.clone()));
+        self.insert(key, value);
+    }
+
+    async fn update_many(&self, updates: Vec<(K, V)>) {
+        sleep(self.update_latency).await;
+        self.record(StoreOperation::UpdateMany(updates.clone()));
+        for (key, value) in updates {
+            self.insert(key, value);
+        }
+    }
+}